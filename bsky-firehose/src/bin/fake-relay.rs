@@ -0,0 +1,28 @@
+//! Serve a capture from `firehose-cli --record` as if it were a relay.
+//!
+//! ```text
+//! cargo run -p bsky-firehose --features testing --bin fake-relay -- <CAPTURE> [ADDR]
+//! ```
+//!
+//! Point a consumer at it with `--relay ws://127.0.0.1:3333` (firehose-cli) or
+//! `FIREHOSE_ENDPOINT=ws://127.0.0.1:3333` (webhooks).
+
+use bsky_firehose::testing::FakeRelay;
+use color_eyre::Result;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let mut args = std::env::args().skip(1);
+    let Some(capture) = args.next() else {
+        return Err(color_eyre::eyre::eyre!(
+            "usage: fake-relay <CAPTURE> [ADDR]"
+        ));
+    };
+    let addr = args.next().unwrap_or_else(|| "127.0.0.1:3333".to_string());
+
+    let relay = FakeRelay::from_capture(&capture).await?.bind(&addr).await?;
+    eprintln!("serving {capture} on {}", relay.endpoint());
+
+    tokio::signal::ctrl_c().await?;
+    Ok(())
+}
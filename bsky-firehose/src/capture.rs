@@ -0,0 +1,257 @@
+//! Recording raw firehose frames to disk and replaying them later.
+//!
+//! A capture is a flat sequence of records, each laid out as:
+//!
+//! ```text
+//! | received_at: u64 BE (unix millis) | len: u32 BE | frame: [u8; len] |
+//! ```
+//!
+//! where `frame` is the binary websocket message exactly as the relay sent it.
+
+use std::{
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use color_eyre::Result;
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _, BufReader},
+    time::Instant,
+};
+
+use crate::connection::{decode_event, FirehoseEvent};
+
+/// How often buffered frames are flushed to disk by default
+pub const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Writes frames to a capture. Frames are buffered and flushed every
+/// `flush_interval`, so a crash loses at most that much of the capture.
+/// Whatever's left is flushed when it's dropped.
+pub struct Recorder<W: AsyncWrite + Unpin = File> {
+    /// Only `None` once [`Recorder::into_inner`] has taken it
+    writer: Option<W>,
+    buffer: Vec<u8>,
+    flush_interval: Duration,
+    last_flush: Instant,
+}
+
+impl Recorder {
+    pub async fn create(path: impl AsRef<Path>) -> Result<Self> {
+        Ok(Self::new(File::create(path).await?))
+    }
+}
+
+impl<W: AsyncWrite + Unpin> Recorder<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer: Some(writer),
+            buffer: Vec::new(),
+            flush_interval: DEFAULT_FLUSH_INTERVAL,
+            last_flush: Instant::now(),
+        }
+    }
+
+    pub fn with_flush_interval(mut self, flush_interval: Duration) -> Self {
+        self.flush_interval = flush_interval;
+        self
+    }
+
+    pub async fn write_frame(&mut self, frame: &[u8]) -> Result<()> {
+        let received_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64;
+        self.write_frame_at(received_at, frame).await
+    }
+
+    pub async fn write_frame_at(&mut self, received_at: u64, frame: &[u8]) -> Result<()> {
+        let len = u32::try_from(frame.len())?;
+
+        // Buffered whole so a flush never leaves a header without its frame
+        self.buffer.reserve(12 + frame.len());
+        self.buffer.extend_from_slice(&received_at.to_be_bytes());
+        self.buffer.extend_from_slice(&len.to_be_bytes());
+        self.buffer.extend_from_slice(frame);
+
+        if self.last_flush.elapsed() >= self.flush_interval {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Write out everything buffered so far
+    pub async fn flush(&mut self) -> Result<()> {
+        self.last_flush = Instant::now();
+        let Some(writer) = &mut self.writer else {
+            return Ok(());
+        };
+        if !self.buffer.is_empty() {
+            writer.write_all(&self.buffer).await?;
+            self.buffer.clear();
+        }
+        writer.flush().await?;
+
+        Ok(())
+    }
+
+    /// Flush anything still buffered and hand back the writer
+    pub async fn into_inner(mut self) -> Result<W> {
+        self.flush().await?;
+        Ok(self.writer.take().expect("writer is only taken here"))
+    }
+}
+
+impl<W: AsyncWrite + Unpin> Drop for Recorder<W> {
+    /// Best effort, blocking, and only inside a runtime since writing a tokio
+    /// `File` needs one
+    fn drop(&mut self) {
+        if self.buffer.is_empty() || tokio::runtime::Handle::try_current().is_err() {
+            return;
+        }
+        if let Err(err) = futures::executor::block_on(self.flush()) {
+            tracing::warn!(?err, "failed to flush capture");
+        }
+    }
+}
+
+/// Reads a capture back, optionally sleeping between frames to reproduce the
+/// original timing.
+pub struct Replay<R = BufReader<File>> {
+    reader: R,
+    speed: Option<f64>,
+    started: Option<(Instant, u64)>,
+}
+
+impl Replay {
+    pub async fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Ok(Self::new(BufReader::new(File::open(path).await?)))
+    }
+}
+
+impl<R: AsyncRead + Unpin> Replay<R> {
+    /// A replay that runs as fast as frames can be read
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            speed: None,
+            started: None,
+        }
+    }
+
+    /// Pace frames at `speed` times their original rate, so `Some(1.0)` is
+    /// real time and `None` is as fast as possible
+    pub fn with_speed(mut self, speed: Option<f64>) -> Self {
+        self.speed = speed.filter(|speed| *speed > 0.0);
+        self
+    }
+
+    /// The next raw frame, or `None` at the end of the capture
+    pub async fn next_frame(&mut self) -> Result<Option<Vec<u8>>> {
+        let received_at = match self.reader.read_u64().await {
+            Ok(received_at) => received_at,
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let len = self.reader.read_u32().await?;
+        let mut frame = vec![0; len as usize];
+        self.reader.read_exact(&mut frame).await?;
+
+        if let Some(speed) = self.speed {
+            let (started_at, first_received_at) =
+                *self.started.get_or_insert((Instant::now(), received_at));
+            let offset = Duration::from_millis(received_at.saturating_sub(first_received_at));
+            tokio::time::sleep_until(started_at + offset.div_f64(speed)).await;
+        }
+
+        Ok(Some(frame))
+    }
+
+    /// The next decoded event, or `None` at the end of the capture
+    pub async fn next(&mut self) -> Result<Option<FirehoseEvent>> {
+        match self.next_frame().await? {
+            Some(frame) => Ok(Some(decode_event(&frame)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn round_trips_frames() {
+        let mut recorder = Recorder::new(Vec::new());
+        recorder
+            .write_frame_at(1_000, b"first")
+            .await
+            .expect("failed to record");
+        recorder
+            .write_frame_at(1_010, b"second frame")
+            .await
+            .expect("failed to record");
+        let capture = recorder.into_inner().await.unwrap();
+
+        let mut replay = Replay::new(capture.as_slice());
+        assert_eq!(
+            replay.next_frame().await.expect("failed to read"),
+            Some(b"first".to_vec())
+        );
+        assert_eq!(
+            replay.next_frame().await.expect("failed to read"),
+            Some(b"second frame".to_vec())
+        );
+        assert_eq!(replay.next_frame().await.expect("failed to read"), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn paces_frames_by_speed() {
+        let mut recorder = Recorder::new(Vec::new());
+        recorder.write_frame_at(0, b"a").await.unwrap();
+        recorder.write_frame_at(10_000, b"b").await.unwrap();
+        let capture = recorder.into_inner().await.unwrap();
+
+        let mut replay = Replay::new(capture.as_slice()).with_speed(Some(10.0));
+        let start = Instant::now();
+        replay.next_frame().await.unwrap();
+        replay.next_frame().await.unwrap();
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn flushes_on_an_interval() {
+        let mut recorder = Recorder::new(Vec::new()).with_flush_interval(Duration::from_secs(1));
+        recorder.write_frame_at(0, b"a").await.unwrap();
+        assert!(recorder.writer.as_ref().unwrap().is_empty());
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        recorder.write_frame_at(1_000, b"b").await.unwrap();
+        assert_eq!(recorder.writer.as_ref().unwrap().len(), 2 * 13);
+        assert!(recorder.buffer.is_empty());
+    }
+
+    #[tokio::test]
+    async fn truncated_capture_is_an_error() {
+        let mut recorder = Recorder::new(Vec::new());
+        recorder.write_frame_at(0, b"complete").await.unwrap();
+        let mut capture = recorder.into_inner().await.unwrap();
+        capture.truncate(capture.len() - 2);
+
+        let mut replay = Replay::new(capture.as_slice());
+        assert!(replay.next_frame().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn flushes_when_dropped() {
+        let path = std::env::temp_dir().join(format!("capture-{}.bin", std::process::id()));
+        let mut recorder = Recorder::create(&path)
+            .await
+            .unwrap()
+            .with_flush_interval(Duration::from_secs(60));
+        recorder.write_frame_at(0, b"buffered").await.unwrap();
+        drop(recorder);
+
+        let mut replay = Replay::open(&path).await.unwrap();
+        let frame = replay.next_frame().await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(frame, Some(b"buffered".to_vec()));
+    }
+}
@@ -0,0 +1,233 @@
+use atrium_api::{
+    app::bsky::feed::post::Record,
+    com::atproto::sync::subscribe_repos::{Commit, RepoOp},
+    types::{string::Did, CidLink, Collection as _},
+};
+use color_eyre::Result;
+use ipld_core::ipld::Ipld;
+use serde::de::DeserializeOwned;
+
+pub const CREATE_ACTION: &str = "create";
+pub const UPDATE_ACTION: &str = "update";
+pub const DELETE_ACTION: &str = "delete";
+pub const POST_PATH_TYPE: &str = atrium_api::app::bsky::feed::Post::NSID;
+
+/// The blocks carried in a commit's CAR slice, so ops can look up the records
+/// they wrote without re-reading the CAR for every op.
+pub struct CommitBlocks {
+    items: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl CommitBlocks {
+    pub async fn read(commit: &Commit) -> Result<Self> {
+        let (items, _) = rs_car::car_read_all(&mut commit.blocks.as_slice(), true).await?;
+        let items = items
+            .into_iter()
+            .map(|(cid, block)| (cid.to_bytes(), block))
+            .collect();
+
+        Ok(Self { items })
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn get(&self, cid: &CidLink) -> Option<&[u8]> {
+        let cid = cid.0.to_bytes();
+        self.items
+            .iter()
+            .find(|(item_cid, _)| *item_cid == cid)
+            .map(|(_, block)| block.as_slice())
+    }
+
+    /// Decode the record an op wrote. Errors if the op has no CID or its block
+    /// isn't in this commit.
+    pub fn record<T: DeserializeOwned>(&self, op: &RepoOp) -> Result<T> {
+        let Some(block) = op.cid.as_ref().and_then(|cid| self.get(cid)) else {
+            return Err(color_eyre::eyre::eyre!(
+                "FAILED: could not find item with operation cid {:?} out of {} items",
+                op.cid,
+                self.len()
+            ));
+        };
+
+        Ok(serde_ipld_dagcbor::from_slice::<T>(block)?)
+    }
+}
+
+/// Returns the collection NSID of a repo path like `app.bsky.feed.post/3kabc`
+pub fn path_collection(path: &str) -> &str {
+    path.split('/').next().unwrap_or(path)
+}
+
+/// Returns the record key of a repo path like `app.bsky.feed.post/3kabc`
+pub fn path_rkey(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+/// The CID of the record an update or delete replaced. Only newer relays send
+/// `prev`, and atrium doesn't model it yet so it ends up in the op's extra data.
+pub fn op_prev(op: &RepoOp) -> Option<CidLink> {
+    let Ipld::Map(extra) = &op.extra_data else {
+        return None;
+    };
+    match extra.get("prev") {
+        Some(Ipld::Link(cid)) => Some(CidLink(*cid)),
+        _ => None,
+    }
+}
+
+pub struct NewPost {
+    pub record: Record,
+    pub author: Did,
+    /// The repo path, `app.bsky.feed.post/<rkey>`
+    pub path: String,
+    pub cid: Option<CidLink>,
+    /// For updates, the CID of the version this one replaced, if the relay
+    /// sent it
+    pub prev: Option<CidLink>,
+}
+
+impl NewPost {
+    pub fn rkey(&self) -> &str {
+        path_rkey(&self.path)
+    }
+
+    pub fn uri(&self) -> String {
+        format!("at://{}/{}", self.author.as_str(), self.path)
+    }
+}
+
+/// A post that was removed from its author's repo. Deletes don't carry the
+/// record, so all we know is where it used to live.
+pub struct DeletedPost {
+    pub author: Did,
+    /// The repo path, `app.bsky.feed.post/<rkey>`
+    pub path: String,
+    /// The CID of the record that was deleted, if the relay sent it
+    pub prev: Option<CidLink>,
+}
+
+impl DeletedPost {
+    pub fn rkey(&self) -> &str {
+        path_rkey(&self.path)
+    }
+
+    pub fn uri(&self) -> String {
+        format!("at://{}/{}", self.author.as_str(), self.path)
+    }
+}
+
+pub enum PostEvent {
+    Created(NewPost),
+    /// The post was rewritten in place. `cid` on the [`NewPost`] is the new
+    /// version.
+    Updated(NewPost),
+    Deleted(DeletedPost),
+}
+
+/// Every `app.bsky.feed.post` created, updated or deleted in this commit, in op
+/// order. The CAR blocks are only read if there is a record to decode.
+pub async fn post_events(commit: &Commit) -> Result<Vec<PostEvent>> {
+    let post_ops: Vec<_> = commit
+        .ops
+        .iter()
+        .filter(|op| path_collection(&op.path) == POST_PATH_TYPE)
+        .collect();
+    if post_ops.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let needs_blocks = post_ops
+        .iter()
+        .any(|op| op.action == CREATE_ACTION || op.action == UPDATE_ACTION);
+    let blocks = if needs_blocks {
+        Some(CommitBlocks::read(commit).await?)
+    } else {
+        None
+    };
+
+    let mut events = Vec::with_capacity(post_ops.len());
+    for op in post_ops {
+        let post = |blocks: &CommitBlocks| -> Result<NewPost> {
+            Ok(NewPost {
+                record: blocks.record(op)?,
+                author: commit.repo.clone(),
+                path: op.path.clone(),
+                cid: op.cid.clone(),
+                prev: op_prev(op),
+            })
+        };
+        match (op.action.as_str(), &blocks) {
+            (CREATE_ACTION, Some(blocks)) => events.push(PostEvent::Created(post(blocks)?)),
+            (UPDATE_ACTION, Some(blocks)) => events.push(PostEvent::Updated(post(blocks)?)),
+            (DELETE_ACTION, _) => events.push(PostEvent::Deleted(DeletedPost {
+                author: commit.repo.clone(),
+                path: op.path.clone(),
+                prev: op_prev(op),
+            })),
+            _ => {}
+        }
+    }
+    Ok(events)
+}
+
+/// Every `app.bsky.feed.post` created in this commit
+pub async fn new_posts(commit: &Commit) -> Result<Vec<NewPost>> {
+    Ok(post_events(commit)
+        .await?
+        .into_iter()
+        .filter_map(|event| match event {
+            PostEvent::Created(post) => Some(post),
+            _ => None,
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn path_collection_splits_on_rkey() {
+        assert_eq!(
+            path_collection("app.bsky.feed.post/3kabcdefg"),
+            "app.bsky.feed.post"
+        );
+        assert_eq!(path_collection("app.bsky.feed.post"), "app.bsky.feed.post");
+        assert_eq!(path_rkey("app.bsky.feed.post/3kabcdefg"), "3kabcdefg");
+    }
+
+    #[test]
+    fn reads_prev_from_extra_data() {
+        let cid: ipld_core::cid::Cid =
+            "bafyreie5737gdxlw5i64vzichcalba3z2v5n6icifvx5xytvske7mr3hpm"
+                .parse()
+                .unwrap();
+        let op = |extra: Vec<(&str, Ipld)>| {
+            let mut map: std::collections::BTreeMap<_, _> = extra
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value))
+                .collect();
+            map.insert("action".to_string(), Ipld::String(UPDATE_ACTION.into()));
+            map.insert(
+                "path".to_string(),
+                Ipld::String("app.bsky.feed.post/3k".into()),
+            );
+            map.insert("cid".to_string(), Ipld::Link(cid));
+            let bytes = serde_ipld_dagcbor::to_vec(&Ipld::Map(map)).unwrap();
+            serde_ipld_dagcbor::from_slice::<RepoOp>(&bytes).unwrap()
+        };
+
+        assert_eq!(
+            op_prev(&op(vec![("prev", Ipld::Link(cid))])),
+            Some(CidLink(cid))
+        );
+        assert_eq!(op_prev(&op(vec![])), None);
+    }
+}
@@ -0,0 +1,289 @@
+use std::time::Duration;
+
+use atrium_api::com::atproto::sync::subscribe_repos::NSID;
+use color_eyre::Result;
+use futures::{SinkExt as _, StreamExt as _};
+use rand::Rng as _;
+use tokio::{net::TcpStream, sync::watch};
+use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
+
+use crate::{
+    capture::Recorder,
+    frames::{ErrorFrame, Frame, FrameError},
+    messages::RepoMessage,
+};
+
+pub const DEFAULT_ENDPOINT: &str = "wss://bsky.network";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionState {
+    Connecting,
+    Connected,
+    Reconnecting {
+        attempt: u32,
+        delay: Duration,
+        reason: String,
+    },
+}
+
+/// Jittered exponential backoff. Each delay is picked uniformly from the upper
+/// half of `initial * 2^(attempt - 1)`, capped at `max`.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(60),
+        }
+    }
+}
+
+impl Backoff {
+    fn ceiling(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(16);
+        self.initial.saturating_mul(1 << exponent).min(self.max)
+    }
+
+    pub fn delay(&self, attempt: u32) -> Duration {
+        self.ceiling(attempt)
+            .mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum FirehoseEvent {
+    Message(RepoMessage),
+    /// The relay sent an error frame. The connection has been dropped and
+    /// will be re-established on the next call to [`Firehose::next`].
+    Error(ErrorFrame),
+}
+
+/// Decode a raw websocket frame into the event it carries
+pub fn decode_event(data: &[u8]) -> Result<FirehoseEvent> {
+    match Frame::try_from(data)? {
+        Frame::Message(t, message) => Ok(FirehoseEvent::Message(RepoMessage::decode(
+            t.as_deref(),
+            &message,
+        )?)),
+        Frame::Error(err) => Ok(FirehoseEvent::Error(err)),
+    }
+}
+
+/// A supervised `subscribeRepos` connection.
+///
+/// Dropped connections, read timeouts and error frames are all handled by
+/// reconnecting with [`Backoff`], resuming from the last sequence number
+/// handed out, so callers just keep calling [`Firehose::next`].
+pub struct Firehose {
+    endpoint: String,
+    cursor: Option<i64>,
+    backoff: Backoff,
+    read_timeout: Duration,
+    attempt: u32,
+    reason: String,
+    stream: Option<WebSocketStream<MaybeTlsStream<TcpStream>>>,
+    state: watch::Sender<ConnectionState>,
+    recorder: Option<Recorder>,
+}
+
+impl Firehose {
+    pub fn new(endpoint: impl Into<String>) -> Self {
+        let (state, _) = watch::channel(ConnectionState::Connecting);
+        Self {
+            endpoint: endpoint.into().trim_end_matches('/').to_string(),
+            cursor: None,
+            backoff: Backoff::default(),
+            read_timeout: Duration::from_secs(60),
+            attempt: 0,
+            reason: String::new(),
+            stream: None,
+            state,
+            recorder: None,
+        }
+    }
+
+    pub fn with_cursor(mut self, cursor: Option<i64>) -> Self {
+        self.cursor = cursor;
+        self
+    }
+
+    pub fn with_backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    pub fn with_read_timeout(mut self, read_timeout: Duration) -> Self {
+        self.read_timeout = read_timeout;
+        self
+    }
+
+    /// Write every binary frame received to `recorder` before decoding it
+    pub fn with_recorder(mut self, recorder: Recorder) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    /// The sequence number of the last message returned, which is where a
+    /// reconnect will resume from
+    pub fn cursor(&self) -> Option<i64> {
+        self.cursor
+    }
+
+    pub fn state(&self) -> watch::Receiver<ConnectionState> {
+        self.state.subscribe()
+    }
+
+    fn url(&self) -> String {
+        match self.cursor {
+            Some(seq) => format!("{}/xrpc/{NSID}?cursor={seq}", self.endpoint),
+            None => format!("{}/xrpc/{NSID}", self.endpoint),
+        }
+    }
+
+    /// Wait for the next message. Connection problems are retried internally;
+    /// an `Err` here means a single frame couldn't be decoded and the caller
+    /// can carry on.
+    pub async fn next(&mut self) -> Result<FirehoseEvent> {
+        loop {
+            if self.stream.is_none() {
+                self.connect().await;
+            }
+            let stream = self
+                .stream
+                .as_mut()
+                .expect("connect always leaves us with a stream");
+
+            let received = tokio::time::timeout(self.read_timeout, stream.next()).await;
+            let data = match received {
+                Ok(Some(Ok(Message::Binary(data)))) => data,
+                Ok(Some(Ok(Message::Ping(data)))) => {
+                    if let Err(err) = stream.send(Message::Pong(data)).await {
+                        self.disconnected(format!("failed to answer ping: {err}"));
+                    }
+                    continue;
+                }
+                Ok(Some(Ok(Message::Close(frame)))) => {
+                    self.disconnected(format!("closed by relay: {frame:?}"));
+                    continue;
+                }
+                Ok(Some(Ok(_))) => continue,
+                Ok(Some(Err(err))) => {
+                    self.disconnected(err.to_string());
+                    continue;
+                }
+                Ok(None) => {
+                    self.disconnected("stream ended".to_string());
+                    continue;
+                }
+                Err(_) => {
+                    self.disconnected(format!("nothing received for {:?}", self.read_timeout));
+                    continue;
+                }
+            };
+
+            // A full disk shouldn't stop us consuming the firehose
+            if let Some(recorder) = &mut self.recorder {
+                if let Err(err) = recorder.write_frame(&data).await {
+                    tracing::error!(?err, "failed to record firehose frame");
+                }
+            }
+
+            let event = decode_event(&data)?;
+            match &event {
+                FirehoseEvent::Message(message) => {
+                    self.attempt = 0;
+                    if let Some(seq) = message.seq() {
+                        self.cursor = Some(seq);
+                    }
+                }
+                FirehoseEvent::Error(err) => {
+                    if err.error == FrameError::FutureCursor {
+                        // Our cursor is ahead of the relay, so start over from the live tip
+                        self.cursor = None;
+                    }
+                    // The relay closes the connection after an error frame
+                    self.disconnected(err.to_string());
+                }
+            }
+            return Ok(event);
+        }
+    }
+
+    fn disconnected(&mut self, reason: String) {
+        self.stream = None;
+        self.attempt += 1;
+        self.reason = reason;
+    }
+
+    async fn connect(&mut self) {
+        loop {
+            if self.attempt > 0 {
+                let delay = self.backoff.delay(self.attempt);
+                self.state.send_replace(ConnectionState::Reconnecting {
+                    attempt: self.attempt,
+                    delay,
+                    reason: self.reason.clone(),
+                });
+                tokio::time::sleep(delay).await;
+            }
+
+            self.state.send_replace(ConnectionState::Connecting);
+            match connect_async(self.url()).await {
+                Ok((stream, _)) => {
+                    self.stream = Some(stream);
+                    self.state.send_replace(ConnectionState::Connected);
+                    return;
+                }
+                Err(err) => self.disconnected(err.to_string()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_grows_and_caps() {
+        let backoff = Backoff {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(30),
+        };
+        assert_eq!(backoff.ceiling(1), Duration::from_secs(1));
+        assert_eq!(backoff.ceiling(2), Duration::from_secs(2));
+        assert_eq!(backoff.ceiling(5), Duration::from_secs(16));
+        assert_eq!(backoff.ceiling(6), Duration::from_secs(30));
+        assert_eq!(backoff.ceiling(1000), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn backoff_jitter_stays_in_range() {
+        let backoff = Backoff::default();
+        for attempt in 1..10 {
+            let delay = backoff.delay(attempt);
+            let ceiling = backoff.ceiling(attempt);
+            assert!(delay <= ceiling);
+            assert!(delay >= ceiling / 2);
+        }
+    }
+
+    #[test]
+    fn url_includes_cursor() {
+        let firehose = Firehose::new("wss://relay.example/");
+        assert_eq!(
+            firehose.url(),
+            "wss://relay.example/xrpc/com.atproto.sync.subscribeRepos"
+        );
+        let firehose = firehose.with_cursor(Some(42));
+        assert_eq!(
+            firehose.url(),
+            "wss://relay.example/xrpc/com.atproto.sync.subscribeRepos?cursor=42"
+        );
+    }
+}
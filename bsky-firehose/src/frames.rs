@@ -0,0 +1,322 @@
+use ipld_core::ipld::Ipld;
+use serde::Serialize;
+use std::{collections::BTreeMap, io::Cursor};
+
+// original definition:
+//```
+// export enum FrameType {
+//   Message = 1,
+//   Error = -1,
+// }
+// export const messageFrameHeader = z.object({
+//   op: z.literal(FrameType.Message), // Frame op
+//   t: z.string().optional(), // Message body type discriminator
+// })
+// export type MessageFrameHeader = z.infer<typeof messageFrameHeader>
+// export const errorFrameHeader = z.object({
+//   op: z.literal(FrameType.Error),
+// })
+// export type ErrorFrameHeader = z.infer<typeof errorFrameHeader>
+// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameHeader {
+    Message(Option<String>),
+    Error,
+}
+
+impl TryFrom<Ipld> for FrameHeader {
+    type Error = color_eyre::eyre::Error;
+
+    fn try_from(value: Ipld) -> Result<Self, <FrameHeader as TryFrom<Ipld>>::Error> {
+        if let Ipld::Map(map) = value {
+            if let Some(Ipld::Integer(i)) = map.get("op") {
+                match i {
+                    1 => {
+                        let t = if let Some(Ipld::String(s)) = map.get("t") {
+                            Some(s.clone())
+                        } else {
+                            None
+                        };
+                        return Ok(FrameHeader::Message(t));
+                    }
+                    -1 => return Ok(FrameHeader::Error),
+                    _ => {}
+                }
+            }
+        }
+        Err(color_eyre::eyre::eyre!("invalid frame type"))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame {
+    Message(Option<String>, MessageFrame),
+    Error(ErrorFrame),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MessageFrame {
+    pub body: Vec<u8>,
+}
+
+impl MessageFrame {
+    /// Encode `body` as dag-cbor
+    pub fn new<T: Serialize>(body: &T) -> color_eyre::Result<Self> {
+        Ok(Self {
+            body: serde_ipld_dagcbor::to_vec(body)?,
+        })
+    }
+}
+
+// original definition:
+//```
+// export const errorFrameBody = z.object({
+//   error: z.string(), // Error code
+//   message: z.string().optional(), // Error message
+// })
+// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorFrame {
+    pub error: FrameError,
+    pub message: Option<String>,
+}
+
+/// The `error` codes declared by `com.atproto.sync.subscribeRepos`, plus a
+/// catch-all for anything a relay sends that isn't in the lexicon.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameError {
+    FutureCursor,
+    ConsumerTooSlow,
+    Other(String),
+}
+
+impl From<&str> for FrameError {
+    fn from(value: &str) -> Self {
+        match value {
+            "FutureCursor" => FrameError::FutureCursor,
+            "ConsumerTooSlow" => FrameError::ConsumerTooSlow,
+            other => FrameError::Other(other.to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for FrameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FrameError::FutureCursor => f.write_str("FutureCursor"),
+            FrameError::ConsumerTooSlow => f.write_str("ConsumerTooSlow"),
+            FrameError::Other(s) => f.write_str(s),
+        }
+    }
+}
+
+impl std::fmt::Display for ErrorFrame {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.message {
+            Some(message) => write!(f, "{}: {}", self.error, message),
+            None => write!(f, "{}", self.error),
+        }
+    }
+}
+
+impl TryFrom<Ipld> for ErrorFrame {
+    type Error = color_eyre::eyre::Error;
+
+    fn try_from(value: Ipld) -> Result<Self, <ErrorFrame as TryFrom<Ipld>>::Error> {
+        if let Ipld::Map(map) = value {
+            if let Some(Ipld::String(error)) = map.get("error") {
+                let message = if let Some(Ipld::String(s)) = map.get("message") {
+                    Some(s.clone())
+                } else {
+                    None
+                };
+                return Ok(ErrorFrame {
+                    error: FrameError::from(error.as_str()),
+                    message,
+                });
+            }
+        }
+        Err(color_eyre::eyre::eyre!("invalid error frame body"))
+    }
+}
+
+impl TryFrom<&[u8]> for Frame {
+    type Error = color_eyre::eyre::Error;
+
+    fn try_from(value: &[u8]) -> Result<Self, <Frame as TryFrom<&[u8]>>::Error> {
+        let mut cursor = Cursor::new(value);
+        let (left, right) = match serde_ipld_dagcbor::from_reader::<Ipld, _>(&mut cursor) {
+            Err(serde_ipld_dagcbor::DecodeError::TrailingData) => {
+                value.split_at(cursor.position() as usize)
+            }
+            _ => {
+                // TODO
+                return Err(color_eyre::eyre::eyre!("invalid frame type"));
+            }
+        };
+        let header = FrameHeader::try_from(serde_ipld_dagcbor::from_slice::<Ipld>(left)?)?;
+        if let FrameHeader::Message(t) = &header {
+            Ok(Frame::Message(
+                t.clone(),
+                MessageFrame {
+                    body: right.to_vec(),
+                },
+            ))
+        } else {
+            let body = serde_ipld_dagcbor::from_slice::<Ipld>(right)?;
+            Ok(Frame::Error(ErrorFrame::try_from(body)?))
+        }
+    }
+}
+
+impl Frame {
+    /// Encode the frame the way a relay puts it on the wire: a dag-cbor header
+    /// immediately followed by the dag-cbor body
+    pub fn to_bytes(&self) -> color_eyre::Result<Vec<u8>> {
+        let (header, body) = match self {
+            Frame::Message(t, message) => {
+                let mut header = BTreeMap::from([("op".to_string(), Ipld::Integer(1))]);
+                if let Some(t) = t {
+                    header.insert("t".to_string(), Ipld::String(t.clone()));
+                }
+                (header, message.body.clone())
+            }
+            Frame::Error(err) => {
+                let header = BTreeMap::from([("op".to_string(), Ipld::Integer(-1))]);
+                let mut body =
+                    BTreeMap::from([("error".to_string(), Ipld::String(err.error.to_string()))]);
+                if let Some(message) = &err.message {
+                    body.insert("message".to_string(), Ipld::String(message.clone()));
+                }
+                (header, serde_ipld_dagcbor::to_vec(&Ipld::Map(body))?)
+            }
+        };
+
+        let mut bytes = serde_ipld_dagcbor::to_vec(&Ipld::Map(header))?;
+        bytes.extend(body);
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn serialized_data(s: &str) -> Vec<u8> {
+        assert!(s.len().is_multiple_of(2));
+        let b2u = |b: u8| match b {
+            b'0'..=b'9' => b - b'0',
+            b'a'..=b'f' => b - b'a' + 10,
+            _ => unreachable!(),
+        };
+        s.as_bytes()
+            .chunks(2)
+            .map(|b| (b2u(b[0]) << 4) + b2u(b[1]))
+            .collect()
+    }
+
+    #[test]
+    fn deserialize_message_frame_header() {
+        // {"op": 1, "t": "#commit"}
+        let data = serialized_data("a2626f700161746723636f6d6d6974");
+        let ipld = serde_ipld_dagcbor::from_slice::<Ipld>(&data).expect("failed to deserialize");
+        let result = FrameHeader::try_from(ipld);
+        assert_eq!(
+            result.expect("failed to deserialize"),
+            FrameHeader::Message(Some(String::from("#commit")))
+        );
+    }
+
+    #[test]
+    fn deserialize_error_frame_header() {
+        // {"op": -1}
+        let data = serialized_data("a1626f7020");
+        let ipld = serde_ipld_dagcbor::from_slice::<Ipld>(&data).expect("failed to deserialize");
+        let result = FrameHeader::try_from(ipld);
+        assert_eq!(result.expect("failed to deserialize"), FrameHeader::Error);
+    }
+
+    #[test]
+    fn deserialize_error_frame() {
+        // {"op": -1} {"error": "FutureCursor", "message": "Cursor in the future."}
+        let data = serialized_data(
+            "a1626f7020a2656572726f726c467574757265437572736f72676d6573736167657543757273\
+             6f7220696e20746865206675747572652e",
+        );
+        let frame = Frame::try_from(data.as_slice()).expect("failed to deserialize");
+        assert_eq!(
+            frame,
+            Frame::Error(ErrorFrame {
+                error: FrameError::FutureCursor,
+                message: Some(String::from("Cursor in the future.")),
+            })
+        );
+    }
+
+    #[test]
+    fn deserialize_unknown_error_frame() {
+        // {"op": -1} {"error": "Boom"}
+        let data = serialized_data("a1626f7020a1656572726f7264426f6f6d");
+        let frame = Frame::try_from(data.as_slice()).expect("failed to deserialize");
+        assert_eq!(
+            frame,
+            Frame::Error(ErrorFrame {
+                error: FrameError::Other(String::from("Boom")),
+                message: None,
+            })
+        );
+    }
+
+    #[test]
+    fn round_trip_frames() {
+        let message = Frame::Message(
+            Some(String::from("#info")),
+            MessageFrame::new(&Ipld::Map(BTreeMap::from([(
+                "name".to_string(),
+                Ipld::String("OutdatedCursor".into()),
+            )])))
+            .expect("failed to serialize"),
+        );
+        let bytes = message.to_bytes().expect("failed to serialize");
+        assert_eq!(
+            Frame::try_from(bytes.as_slice()).expect("failed to deserialize"),
+            message
+        );
+
+        let error = Frame::Error(ErrorFrame {
+            error: FrameError::ConsumerTooSlow,
+            message: Some(String::from("slow down")),
+        });
+        let bytes = error.to_bytes().expect("failed to serialize");
+        assert_eq!(
+            Frame::try_from(bytes.as_slice()).expect("failed to deserialize"),
+            error
+        );
+    }
+
+    #[test]
+    fn deserialize_invalid_frame_header() {
+        {
+            // {"op": 2, "t": "#commit"}
+            let data = serialized_data("a2626f700261746723636f6d6d6974");
+            let ipld =
+                serde_ipld_dagcbor::from_slice::<Ipld>(&data).expect("failed to deserialize");
+            let result = FrameHeader::try_from(ipld);
+            assert_eq!(
+                result.expect_err("must be failed").to_string(),
+                "invalid frame type"
+            );
+        }
+        {
+            // {"op": -2}
+            let data = serialized_data("a1626f7021");
+            let ipld =
+                serde_ipld_dagcbor::from_slice::<Ipld>(&data).expect("failed to deserialize");
+            let result = FrameHeader::try_from(ipld);
+            assert_eq!(
+                result.expect_err("must be failed").to_string(),
+                "invalid frame type"
+            );
+        }
+    }
+}
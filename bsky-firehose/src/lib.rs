@@ -0,0 +1,22 @@
+//! Shared connection handling and decoding for the
+//! `com.atproto.sync.subscribeRepos` firehose.
+//!
+//! Both `firehose-cli` and `webhooks` consume the same stream, so the frame
+//! parsing, reconnect logic and commit/record extraction live here instead of
+//! being copied into each binary.
+
+pub mod capture;
+pub mod commit;
+pub mod connection;
+pub mod frames;
+pub mod messages;
+pub mod source;
+#[cfg(feature = "testing")]
+pub mod testing;
+
+pub use capture::{Recorder, Replay};
+pub use commit::{new_posts, post_events, CommitBlocks, DeletedPost, NewPost, PostEvent};
+pub use connection::{Backoff, ConnectionState, Firehose, FirehoseEvent, DEFAULT_ENDPOINT};
+pub use frames::{ErrorFrame, Frame, FrameError, FrameHeader, MessageFrame};
+pub use messages::RepoMessage;
+pub use source::EventSource;
@@ -0,0 +1,209 @@
+use atrium_api::{
+    com::atproto::sync::subscribe_repos::{Account, Commit, Identity, Info},
+    types::string::{Datetime, Did, Handle},
+};
+use color_eyre::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::frames::MessageFrame;
+
+// atrium-api 0.24 doesn't generate the deprecated `#handle`, `#tombstone` and
+// `#migrate` bodies or the newer `#sync` one, so those are defined here.
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HandleMessage {
+    pub did: Did,
+    pub handle: Handle,
+    pub seq: i64,
+    pub time: Datetime,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TombstoneMessage {
+    pub did: Did,
+    pub seq: i64,
+    pub time: Datetime,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MigrateMessage {
+    pub did: Did,
+    pub migrate_to: Option<String>,
+    pub seq: i64,
+    pub time: Datetime,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncMessage {
+    pub did: Did,
+    #[serde(with = "serde_bytes")]
+    pub blocks: Vec<u8>,
+    pub rev: String,
+    pub seq: i64,
+    pub time: Datetime,
+}
+
+/// A decoded `com.atproto.sync.subscribeRepos` message, keyed off the `t`
+/// discriminator in the frame header.
+#[derive(Debug, Clone)]
+pub enum RepoMessage {
+    Commit(Box<Commit>),
+    Identity(Box<Identity>),
+    Account(Box<Account>),
+    Handle(Box<HandleMessage>),
+    Tombstone(Box<TombstoneMessage>),
+    Migrate(Box<MigrateMessage>),
+    Info(Box<Info>),
+    Sync(Box<SyncMessage>),
+    /// A `t` we don't know about (or no `t` at all), passed through undecoded
+    Unknown(Option<String>, MessageFrame),
+}
+
+impl RepoMessage {
+    pub fn decode(t: Option<&str>, message: &MessageFrame) -> Result<Self> {
+        let body = message.body.as_slice();
+        Ok(match t {
+            Some("#commit") => RepoMessage::Commit(Box::new(serde_ipld_dagcbor::from_slice(body)?)),
+            Some("#identity") => {
+                RepoMessage::Identity(Box::new(serde_ipld_dagcbor::from_slice(body)?))
+            }
+            Some("#account") => {
+                RepoMessage::Account(Box::new(serde_ipld_dagcbor::from_slice(body)?))
+            }
+            Some("#handle") => RepoMessage::Handle(Box::new(serde_ipld_dagcbor::from_slice(body)?)),
+            Some("#tombstone") => {
+                RepoMessage::Tombstone(Box::new(serde_ipld_dagcbor::from_slice(body)?))
+            }
+            Some("#migrate") => {
+                RepoMessage::Migrate(Box::new(serde_ipld_dagcbor::from_slice(body)?))
+            }
+            Some("#info") => RepoMessage::Info(Box::new(serde_ipld_dagcbor::from_slice(body)?)),
+            Some("#sync") => RepoMessage::Sync(Box::new(serde_ipld_dagcbor::from_slice(body)?)),
+            _ => RepoMessage::Unknown(t.map(ToString::to_string), message.clone()),
+        })
+    }
+
+    /// The firehose sequence number, for the message types that carry one
+    pub fn seq(&self) -> Option<i64> {
+        match self {
+            RepoMessage::Commit(m) => Some(m.seq),
+            RepoMessage::Identity(m) => Some(m.seq),
+            RepoMessage::Account(m) => Some(m.seq),
+            RepoMessage::Handle(m) => Some(m.seq),
+            RepoMessage::Tombstone(m) => Some(m.seq),
+            RepoMessage::Migrate(m) => Some(m.seq),
+            RepoMessage::Sync(m) => Some(m.seq),
+            RepoMessage::Info(_) | RepoMessage::Unknown(..) => None,
+        }
+    }
+
+    /// The repo this message is about, if it's about one
+    pub fn did(&self) -> Option<&Did> {
+        match self {
+            RepoMessage::Commit(m) => Some(&m.repo),
+            RepoMessage::Identity(m) => Some(&m.did),
+            RepoMessage::Account(m) => Some(&m.did),
+            RepoMessage::Handle(m) => Some(&m.did),
+            RepoMessage::Tombstone(m) => Some(&m.did),
+            RepoMessage::Migrate(m) => Some(&m.did),
+            RepoMessage::Sync(m) => Some(&m.did),
+            RepoMessage::Info(_) | RepoMessage::Unknown(..) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ipld_core::ipld::Ipld;
+    use std::collections::BTreeMap;
+
+    fn message(fields: Vec<(&str, Ipld)>) -> MessageFrame {
+        let map: BTreeMap<String, Ipld> = fields
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v))
+            .collect();
+        MessageFrame {
+            body: serde_ipld_dagcbor::to_vec(&Ipld::Map(map)).expect("failed to serialize"),
+        }
+    }
+
+    #[test]
+    fn decode_identity() {
+        let body = message(vec![
+            ("did", Ipld::String("did:plc:abc123".into())),
+            ("handle", Ipld::String("coreyja.com".into())),
+            ("seq", Ipld::Integer(42)),
+            ("time", Ipld::String("2024-11-10T18:00:00.000Z".into())),
+        ]);
+        let RepoMessage::Identity(identity) =
+            RepoMessage::decode(Some("#identity"), &body).expect("failed to decode")
+        else {
+            panic!("expected an identity message");
+        };
+        assert_eq!(identity.did.as_str(), "did:plc:abc123");
+        assert_eq!(
+            identity.handle.as_ref().map(|h| h.as_str()),
+            Some("coreyja.com")
+        );
+        assert_eq!(identity.seq, 42);
+    }
+
+    #[test]
+    fn decode_account() {
+        let body = message(vec![
+            ("did", Ipld::String("did:plc:abc123".into())),
+            ("active", Ipld::Bool(false)),
+            ("status", Ipld::String("takendown".into())),
+            ("seq", Ipld::Integer(43)),
+            ("time", Ipld::String("2024-11-10T18:00:00.000Z".into())),
+        ]);
+        let decoded = RepoMessage::decode(Some("#account"), &body).expect("failed to decode");
+        assert_eq!(decoded.seq(), Some(43));
+        let RepoMessage::Account(account) = decoded else {
+            panic!("expected an account message");
+        };
+        assert!(!account.active);
+        assert_eq!(account.status.as_deref(), Some("takendown"));
+    }
+
+    #[test]
+    fn decode_info() {
+        let body = message(vec![("name", Ipld::String("OutdatedCursor".into()))]);
+        let decoded = RepoMessage::decode(Some("#info"), &body).expect("failed to decode");
+        assert_eq!(decoded.seq(), None);
+        let RepoMessage::Info(info) = decoded else {
+            panic!("expected an info message");
+        };
+        assert_eq!(info.name, "OutdatedCursor");
+        assert_eq!(info.message, None);
+    }
+
+    #[test]
+    fn decode_sync() {
+        let body = message(vec![
+            ("did", Ipld::String("did:plc:abc123".into())),
+            ("blocks", Ipld::Bytes(vec![1, 2, 3])),
+            ("rev", Ipld::String("3kabcdefg".into())),
+            ("seq", Ipld::Integer(44)),
+            ("time", Ipld::String("2024-11-10T18:00:00.000Z".into())),
+        ]);
+        let RepoMessage::Sync(sync) =
+            RepoMessage::decode(Some("#sync"), &body).expect("failed to decode")
+        else {
+            panic!("expected a sync message");
+        };
+        assert_eq!(sync.blocks, vec![1, 2, 3]);
+        assert_eq!(sync.rev, "3kabcdefg");
+    }
+
+    #[test]
+    fn decode_unknown() {
+        let body = message(vec![("foo", Ipld::String("bar".into()))]);
+        let decoded = RepoMessage::decode(Some("#labels"), &body).expect("failed to decode");
+        assert!(
+            matches!(decoded, RepoMessage::Unknown(Some(ref t), ref frame) if t == "#labels" && *frame == body)
+        );
+    }
+}
@@ -0,0 +1,46 @@
+use color_eyre::Result;
+
+use crate::{
+    capture::Replay,
+    connection::{Firehose, FirehoseEvent},
+};
+
+/// Where events come from: a live relay, or a capture being replayed.
+///
+/// Consumers loop over [`EventSource::next`] and don't need to care which.
+pub enum EventSource {
+    Live(Box<Firehose>),
+    Replay(Replay),
+}
+
+impl EventSource {
+    /// The next event. `None` means the source is exhausted, which only
+    /// happens at the end of a replay. `Some(Err(_))` is a single frame that
+    /// failed to decode, and the caller can keep going.
+    pub async fn next(&mut self) -> Option<Result<FirehoseEvent>> {
+        match self {
+            EventSource::Live(firehose) => Some(firehose.next().await),
+            EventSource::Replay(replay) => replay.next().await.transpose(),
+        }
+    }
+
+    /// The last sequence number seen. Only tracked for live connections.
+    pub fn cursor(&self) -> Option<i64> {
+        match self {
+            EventSource::Live(firehose) => firehose.cursor(),
+            EventSource::Replay(_) => None,
+        }
+    }
+}
+
+impl From<Firehose> for EventSource {
+    fn from(firehose: Firehose) -> Self {
+        EventSource::Live(Box::new(firehose))
+    }
+}
+
+impl From<Replay> for EventSource {
+    fn from(replay: Replay) -> Self {
+        EventSource::Replay(replay)
+    }
+}
@@ -0,0 +1,209 @@
+//! A stand-in relay that serves scripted frames over a local websocket, so
+//! consumers can be tested end to end without touching the network.
+
+use std::{
+    collections::HashSet,
+    net::SocketAddr,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use color_eyre::Result;
+use futures::{SinkExt as _, StreamExt as _};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+};
+use tokio_tungstenite::tungstenite::{
+    handshake::server::{Request, Response},
+    Message,
+};
+
+use crate::{
+    capture::Replay,
+    connection::{decode_event, FirehoseEvent},
+    frames::{ErrorFrame, Frame, FrameError},
+};
+
+#[derive(Debug, Clone)]
+pub enum ScriptStep {
+    /// A raw frame, sent as-is
+    Frame(Vec<u8>),
+    /// Send an error frame and close the connection, like a real relay does
+    Error(ErrorFrame),
+    /// Drop the TCP connection without a close handshake
+    Disconnect,
+}
+
+impl ScriptStep {
+    pub fn frame(frame: &Frame) -> Result<Self> {
+        Ok(ScriptStep::Frame(frame.to_bytes()?))
+    }
+}
+
+#[derive(Debug)]
+struct Step {
+    step: ScriptStep,
+    seq: Option<i64>,
+}
+
+/// Serves a script of frames to every client that connects.
+///
+/// Clients connecting with `?cursor=N` skip any frame with a seq at or below
+/// `N`, and get a `FutureCursor` error if `N` is past the end of the script.
+/// Errors and disconnects are injected once across all connections, so a
+/// client that reconnects carries on past them.
+pub struct FakeRelay {
+    steps: Arc<Vec<Step>>,
+    fired: Arc<Mutex<HashSet<usize>>>,
+}
+
+pub struct RunningRelay {
+    pub local_addr: SocketAddr,
+    handle: JoinHandle<()>,
+}
+
+impl RunningRelay {
+    /// The endpoint to hand to [`crate::Firehose::new`]
+    pub fn endpoint(&self) -> String {
+        format!("ws://{}", self.local_addr)
+    }
+}
+
+impl Drop for RunningRelay {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+impl FakeRelay {
+    pub fn new(script: Vec<ScriptStep>) -> Self {
+        let steps = script
+            .into_iter()
+            .map(|step| {
+                let seq = match &step {
+                    ScriptStep::Frame(data) => match decode_event(data) {
+                        Ok(FirehoseEvent::Message(message)) => message.seq(),
+                        _ => None,
+                    },
+                    _ => None,
+                };
+                Step { step, seq }
+            })
+            .collect();
+
+        Self {
+            steps: Arc::new(steps),
+            fired: Arc::default(),
+        }
+    }
+
+    /// Serve every frame from a capture written by [`crate::Recorder`]
+    pub async fn from_capture(path: impl AsRef<Path>) -> Result<Self> {
+        let mut replay = Replay::open(path).await?;
+        let mut script = vec![];
+        while let Some(frame) = replay.next_frame().await? {
+            script.push(ScriptStep::Frame(frame));
+        }
+
+        Ok(Self::new(script))
+    }
+
+    pub async fn bind(self, addr: impl tokio::net::ToSocketAddrs) -> Result<RunningRelay> {
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+
+        let handle = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let steps = self.steps.clone();
+                let fired = self.fired.clone();
+                tokio::spawn(async move {
+                    // A client going away mid-script is expected, nothing to do
+                    let _ = serve(stream, steps, fired).await;
+                });
+            }
+        });
+
+        Ok(RunningRelay { local_addr, handle })
+    }
+}
+
+fn parse_cursor(query: Option<&str>) -> Option<i64> {
+    query?
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("cursor="))
+        .and_then(|cursor| cursor.parse().ok())
+}
+
+// The handshake callback's error type is tungstenite's, not ours
+#[allow(clippy::result_large_err)]
+async fn serve(
+    stream: TcpStream,
+    steps: Arc<Vec<Step>>,
+    fired: Arc<Mutex<HashSet<usize>>>,
+) -> Result<()> {
+    let mut query = None;
+    let mut ws = tokio_tungstenite::accept_hdr_async(stream, |req: &Request, resp: Response| {
+        query = req.uri().query().map(ToString::to_string);
+        Ok(resp)
+    })
+    .await?;
+    let cursor = parse_cursor(query.as_deref());
+
+    let last_seq = steps.iter().filter_map(|step| step.seq).max();
+    if let (Some(cursor), Some(last_seq)) = (cursor, last_seq) {
+        if cursor > last_seq {
+            let frame = Frame::Error(ErrorFrame {
+                error: FrameError::FutureCursor,
+                message: Some("Cursor in the future.".to_string()),
+            });
+            ws.send(Message::Binary(frame.to_bytes()?)).await?;
+            ws.close(None).await?;
+            return Ok(());
+        }
+    }
+
+    for (i, step) in steps.iter().enumerate() {
+        match &step.step {
+            ScriptStep::Frame(data) => {
+                if matches!((cursor, step.seq), (Some(cursor), Some(seq)) if seq <= cursor) {
+                    continue;
+                }
+                ws.send(Message::Binary(data.clone())).await?;
+            }
+            ScriptStep::Error(err) => {
+                if !fired.lock().unwrap().insert(i) {
+                    continue;
+                }
+                let frame = Frame::Error(err.clone());
+                ws.send(Message::Binary(frame.to_bytes()?)).await?;
+                ws.close(None).await?;
+                return Ok(());
+            }
+            ScriptStep::Disconnect => {
+                if !fired.lock().unwrap().insert(i) {
+                    continue;
+                }
+                return Ok(());
+            }
+        }
+    }
+
+    // Like a real relay, stay connected waiting for new events that will
+    // never come, until the client hangs up
+    while let Some(Ok(_)) = ws.next().await {}
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_cursor_from_query() {
+        assert_eq!(parse_cursor(None), None);
+        assert_eq!(parse_cursor(Some("cursor=42")), Some(42));
+        assert_eq!(parse_cursor(Some("foo=bar&cursor=7")), Some(7));
+        assert_eq!(parse_cursor(Some("cursor=nope")), None);
+    }
+}
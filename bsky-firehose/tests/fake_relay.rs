@@ -0,0 +1,94 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use bsky_firehose::{
+    testing::{FakeRelay, ScriptStep},
+    Backoff, ErrorFrame, Firehose, FirehoseEvent, Frame, FrameError, MessageFrame, RepoMessage,
+};
+use ipld_core::ipld::Ipld;
+
+fn identity(seq: i64) -> ScriptStep {
+    let body = Ipld::Map(BTreeMap::from([
+        ("did".to_string(), Ipld::String("did:plc:abc123".into())),
+        ("seq".to_string(), Ipld::Integer(seq.into())),
+        (
+            "time".to_string(),
+            Ipld::String("2024-11-10T18:00:00.000Z".into()),
+        ),
+    ]));
+    let frame = Frame::Message(
+        Some("#identity".to_string()),
+        MessageFrame::new(&body).expect("failed to serialize"),
+    );
+    ScriptStep::frame(&frame).expect("failed to serialize")
+}
+
+fn firehose(endpoint: String) -> Firehose {
+    Firehose::new(endpoint).with_backoff(Backoff {
+        initial: Duration::from_millis(1),
+        max: Duration::from_millis(10),
+    })
+}
+
+async fn next_seq(firehose: &mut Firehose) -> Option<i64> {
+    match firehose.next().await.expect("failed to decode") {
+        FirehoseEvent::Message(message @ RepoMessage::Identity(_)) => message.seq(),
+        other => panic!("expected an identity message, got {other:?}"),
+    }
+}
+
+#[tokio::test]
+async fn resumes_after_disconnects_and_errors() {
+    let relay = FakeRelay::new(vec![
+        identity(1),
+        identity(2),
+        ScriptStep::Disconnect,
+        identity(3),
+        ScriptStep::Error(ErrorFrame {
+            error: FrameError::ConsumerTooSlow,
+            message: None,
+        }),
+        identity(4),
+    ])
+    .bind("127.0.0.1:0")
+    .await
+    .expect("failed to bind");
+
+    let mut firehose = firehose(relay.endpoint());
+    assert_eq!(next_seq(&mut firehose).await, Some(1));
+    assert_eq!(next_seq(&mut firehose).await, Some(2));
+    // The disconnect is retried transparently, resuming after seq 2
+    assert_eq!(next_seq(&mut firehose).await, Some(3));
+    match firehose.next().await.expect("failed to decode") {
+        FirehoseEvent::Error(err) => assert_eq!(err.error, FrameError::ConsumerTooSlow),
+        other => panic!("expected an error frame, got {other:?}"),
+    }
+    assert_eq!(next_seq(&mut firehose).await, Some(4));
+    assert_eq!(firehose.cursor(), Some(4));
+}
+
+#[tokio::test]
+async fn honors_cursor() {
+    let relay = FakeRelay::new(vec![identity(1), identity(2), identity(3)])
+        .bind("127.0.0.1:0")
+        .await
+        .expect("failed to bind");
+
+    let mut firehose = firehose(relay.endpoint()).with_cursor(Some(2));
+    assert_eq!(next_seq(&mut firehose).await, Some(3));
+}
+
+#[tokio::test]
+async fn future_cursor_resets_to_live() {
+    let relay = FakeRelay::new(vec![identity(1), identity(2)])
+        .bind("127.0.0.1:0")
+        .await
+        .expect("failed to bind");
+
+    let mut firehose = firehose(relay.endpoint()).with_cursor(Some(100));
+    match firehose.next().await.expect("failed to decode") {
+        FirehoseEvent::Error(err) => assert_eq!(err.error, FrameError::FutureCursor),
+        other => panic!("expected an error frame, got {other:?}"),
+    }
+    assert_eq!(firehose.cursor(), None);
+    assert_eq!(next_seq(&mut firehose).await, Some(1));
+}
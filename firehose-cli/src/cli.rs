@@ -0,0 +1,140 @@
+use std::path::PathBuf;
+
+use bsky_firehose::{commit::POST_PATH_TYPE, DEFAULT_ENDPOINT};
+use clap::{ArgGroup, Parser, ValueEnum};
+
+/// Watch the Bluesky firehose for activity from specific accounts, or from
+/// everyone
+#[derive(Debug, Parser)]
+#[command(version, group(
+    ArgGroup::new("repos")
+        .required(true)
+        .multiple(true)
+        .args(["handles", "dids", "all"]),
+))]
+pub struct Cli {
+    /// Handle to watch, can be repeated
+    #[arg(long = "handle", value_name = "HANDLE")]
+    pub handles: Vec<String>,
+
+    /// DID to watch, can be repeated
+    #[arg(long = "did", value_name = "DID")]
+    pub dids: Vec<String>,
+
+    /// Watch every repo on the network instead of specific accounts
+    #[arg(long, conflicts_with_all = ["handles", "dids"])]
+    pub all: bool,
+
+    /// Relay to subscribe to, either a bare host or a ws(s):// URL
+    #[arg(long, default_value = DEFAULT_ENDPOINT)]
+    pub relay: String,
+
+    /// Sequence number to start from instead of the live tip
+    #[arg(long)]
+    pub cursor: Option<i64>,
+
+    /// Also write every raw frame received to this file, for replaying later
+    #[arg(long, value_name = "FILE")]
+    pub record: Option<PathBuf>,
+
+    /// Read frames from a file written by --record instead of connecting to a
+    /// relay
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["relay", "cursor", "record"])]
+    pub replay: Option<PathBuf>,
+
+    /// Replay speed as a multiple of the original pace, 0 for as fast as
+    /// possible
+    #[arg(long, default_value_t = 1.0, requires = "replay")]
+    pub speed: f64,
+
+    /// Collection to show records from, can be repeated
+    #[arg(long = "collection", value_name = "NSID", default_value = POST_PATH_TYPE)]
+    pub collections: Vec<String>,
+
+    /// How to print events
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    pub format: OutputFormat,
+
+    /// Print timestamps in UTC instead of local time
+    #[arg(long)]
+    pub utc: bool,
+
+    /// Only print records, skipping identity and account events
+    #[arg(long)]
+    pub records_only: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Human readable, one indented block per event
+    Text,
+    /// Newline delimited JSON, one object per event
+    Json,
+}
+
+impl Cli {
+    pub fn relay_url(&self) -> String {
+        if self.relay.starts_with("ws://") || self.relay.starts_with("wss://") {
+            self.relay.clone()
+        } else {
+            format!("wss://{}", self.relay)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_repeated_filters() {
+        let cli = Cli::try_parse_from([
+            "firehose-cli",
+            "--handle",
+            "coreyja.com",
+            "--handle",
+            "bsky.app",
+            "--did",
+            "did:plc:abc123",
+            "--relay",
+            "relay.example",
+        ])
+        .expect("failed to parse");
+        assert_eq!(cli.handles, vec!["coreyja.com", "bsky.app"]);
+        assert_eq!(cli.dids, vec!["did:plc:abc123"]);
+        assert_eq!(cli.collections, vec![POST_PATH_TYPE]);
+        assert_eq!(cli.relay_url(), "wss://relay.example");
+        assert_eq!(cli.format, OutputFormat::Text);
+    }
+
+    #[test]
+    fn replay_conflicts_with_live_options() {
+        let cli = Cli::try_parse_from([
+            "firehose-cli",
+            "--all",
+            "--replay",
+            "capture.bin",
+            "--speed",
+            "0",
+        ])
+        .expect("failed to parse");
+        assert_eq!(cli.replay, Some(PathBuf::from("capture.bin")));
+        assert_eq!(cli.speed, 0.0);
+        assert!(Cli::try_parse_from([
+            "firehose-cli",
+            "--all",
+            "--replay",
+            "capture.bin",
+            "--record",
+            "other.bin",
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn requires_repos_or_all() {
+        assert!(Cli::try_parse_from(["firehose-cli"]).is_err());
+        assert!(Cli::try_parse_from(["firehose-cli", "--all"]).is_ok());
+        assert!(Cli::try_parse_from(["firehose-cli", "--all", "--handle", "coreyja.com"]).is_err());
+    }
+}
@@ -1,134 +1,390 @@
-use std::collections::HashMap;
+use std::{
+    collections::{hash_map::Entry, HashMap, HashSet},
+    sync::Mutex,
+};
 
 use atrium_api::{
     agent::{store::MemorySessionStore, AtpAgent},
-    app::bsky::feed::post::Record,
-    com::atproto::sync::subscribe_repos::{Commit, NSID},
-    types::{string::Did, Collection as _},
+    com::atproto::sync::subscribe_repos::Commit,
+    types::{
+        string::{AtIdentifier, Datetime, Did},
+        Unknown,
+    },
 };
 use atrium_xrpc_client::reqwest::ReqwestClient;
-use chrono::Local;
+use bsky_firehose::{
+    commit::{op_prev, path_collection, path_rkey, POST_PATH_TYPE},
+    post_events, CommitBlocks, EventSource, Firehose, FirehoseEvent, NewPost, PostEvent, Recorder,
+    Replay, RepoMessage,
+};
+use chrono::{Local, Utc};
+use clap::Parser as _;
+use cli::{Cli, OutputFormat};
 use color_eyre::Result;
-use frames::Frame;
-use futures::StreamExt as _;
-use tokio_tungstenite::{connect_async, tungstenite::Message};
-
-const FIREHOSE_DOMAIN: &str = "bsky.network";
+use serde_json::json;
 
-mod frames;
+mod cli;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let handler = Handler::resolve_handles(HANDLES).await?;
-    println!("listening for posts from {:?}", handler.dids_to_handles);
+    let cli = Cli::parse();
+    // Only the firehose library logs, and only for things like failed
+    // recordings. Kept off stdout for the same reason as everything else.
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .init();
 
-    let (mut stream, _) = connect_async(format!("wss://{FIREHOSE_DOMAIN}/xrpc/{NSID}")).await?;
-    while let Some(result) = {
-        if let Some(Ok(Message::Binary(data))) = stream.next().await {
-            Some(Frame::try_from(data.as_slice()))
-        } else {
-            None
+    let handler = Handler::from_cli(&cli).await?;
+    // stdout is reserved for events so `--format json` can be piped into jq
+    if handler.all_repos {
+        eprintln!("listening for {:?} from every repo", handler.collections);
+    } else {
+        eprintln!(
+            "listening for {:?} from {:?}",
+            handler.collections,
+            handler.handles.lock().expect("handle cache poisoned")
+        );
+    }
+
+    let mut source: EventSource = match &cli.replay {
+        Some(path) => Replay::open(path).await?.with_speed(Some(cli.speed)).into(),
+        None => {
+            let mut firehose = Firehose::new(cli.relay_url()).with_cursor(cli.cursor);
+            if let Some(path) = &cli.record {
+                firehose = firehose.with_recorder(Recorder::create(path).await?);
+            }
+
+            let mut state = firehose.state();
+            tokio::spawn(async move {
+                while state.changed().await.is_ok() {
+                    eprintln!("firehose: {:?}", *state.borrow_and_update());
+                }
+            });
+            firehose.into()
         }
-    } {
-        if let Ok(Frame::Message(Some(t), message)) = result {
-            if t.as_str() == "#commit" {
-                let commit = serde_ipld_dagcbor::from_reader(message.body.as_slice())?;
-                if let Err(err) = handler.handle_commit(&commit).await {
+    };
+
+    while let Some(event) = source.next().await {
+        match event {
+            Ok(FirehoseEvent::Message(message)) => {
+                if let Err(err) = handler.handle_message(&message).await {
                     eprintln!("FAILED: {err:?}");
                 }
             }
+            Ok(FirehoseEvent::Error(err)) => eprintln!("firehose error: {err}"),
+            Err(err) => eprintln!("FAILED to decode frame: {err:?}"),
         }
     }
     Ok(())
 }
 
-const CREATE_ACTION: &str = "create";
-const POST_PATH_TYPE: &str = atrium_api::app::bsky::feed::Post::NSID;
+type Agent = AtpAgent<MemorySessionStore, ReqwestClient>;
 
-struct NewPost {
-    record: Record,
-    author: Did,
+struct Handler {
+    watched: HashSet<Did>,
+    all_repos: bool,
+    /// Handles we've resolved so far, `None` for repos that don't have one.
+    /// Only filled in for `--format json`, where every event carries one.
+    handles: Mutex<HashMap<Did, Option<String>>>,
+    appview: Agent,
+    collections: Vec<String>,
+    format: OutputFormat,
+    utc: bool,
+    records_only: bool,
 }
 
-impl NewPost {
-    fn print(&self) {
+impl Handler {
+    async fn from_cli(cli: &Cli) -> Result<Self> {
+        let appview = AtpAgent::new(
+            ReqwestClient::new("https://public.api.bsky.app"),
+            MemorySessionStore::default(),
+        );
+        let mut handles = resolve_handles(&cli.handles).await?;
+        for did in &cli.dids {
+            let did: Did = did
+                .parse()
+                .map_err(|_| color_eyre::eyre::eyre!("invalid did: {did}"))?;
+            if let Entry::Vacant(entry) = handles.entry(did) {
+                let handle = lookup_handle(&appview, entry.key()).await;
+                entry.insert(handle);
+            }
+        }
+
+        Ok(Self {
+            watched: handles.keys().cloned().collect(),
+            all_repos: cli.all,
+            handles: Mutex::new(handles),
+            appview,
+            collections: cli.collections.clone(),
+            format: cli.format,
+            utc: cli.utc,
+            records_only: cli.records_only,
+        })
+    }
+
+    fn is_watched(&self, did: &Did) -> bool {
+        self.all_repos || self.watched.contains(did)
+    }
+
+    fn format_time(&self, time: &Datetime) -> String {
+        if self.utc {
+            time.as_ref().with_timezone(&Utc).to_string()
+        } else {
+            time.as_ref().with_timezone(&Local).to_string()
+        }
+    }
+
+    fn print_post(&self, post: &NewPost) {
         println!(
             "{} - {}",
-            self.record.created_at.as_ref().with_timezone(&Local),
-            self.author.as_str()
+            self.format_time(&post.record.created_at),
+            post.author.as_str()
         );
-        for line in self.record.text.split('\n') {
+        for line in post.record.text.split('\n') {
             println!("  {line}");
         }
     }
-}
 
-struct Handler {
-    dids_to_handles: HashMap<Did, String>,
-}
+    fn print_notice(&self, time: &Datetime, did: &Did, notice: &str) {
+        println!("{} - {}", self.format_time(time), did.as_str());
+        println!("  [{notice}]");
+    }
 
-const HANDLES: &[&str] = &["coreyja.com"];
+    /// The repo's handle, looked up from the AppView the first time we see
+    /// it. With `--all` that's one request per new repo.
+    async fn handle_for(&self, did: &Did) -> Option<String> {
+        let cached = self
+            .handles
+            .lock()
+            .expect("handle cache poisoned")
+            .get(did)
+            .cloned();
+        if let Some(handle) = cached {
+            return handle;
+        }
 
-impl Handler {
-    async fn resolve_handles(handles: &[&str]) -> Result<Self> {
-        let client = ReqwestClient::new("https://bsky.social");
-        let agent = AtpAgent::new(client, MemorySessionStore::default());
-
-        let mut dids_to_handles = HashMap::new();
-        for handle in handles {
-            let resp = agent
-                .api
-                .com
-                .atproto
-                .identity
-                .resolve_handle(
-                    atrium_api::com::atproto::identity::resolve_handle::ParametersData {
-                        handle: handle
-                            .parse()
-                            .map_err(|_| color_eyre::eyre::eyre!("invalid handle"))?,
-                    }
-                    .into(),
-                )
-                .await?;
-            let did = resp.data.did;
-            dids_to_handles.insert(did, handle.to_string());
+        let handle = lookup_handle(&self.appview, did).await;
+        self.remember_handle(did, handle.clone());
+        handle
+    }
+
+    fn remember_handle(&self, did: &Did, handle: Option<String>) {
+        self.handles
+            .lock()
+            .expect("handle cache poisoned")
+            .insert(did.clone(), handle);
+    }
+
+    fn print_json(&self, event: serde_json::Value) {
+        println!("{event}");
+    }
+
+    async fn handle_message(&self, message: &RepoMessage) -> Result<()> {
+        if let RepoMessage::Info(info) = message {
+            eprintln!("firehose info: {} {:?}", info.name, info.message);
+            return Ok(());
+        }
+        let Some(did) = message.did().filter(|did| self.is_watched(did)) else {
+            return Ok(());
+        };
+        if self.records_only && !matches!(message, RepoMessage::Commit(_)) {
+            return Ok(());
+        }
+        // Keep the cache in step with handle changes as they come through
+        match message {
+            RepoMessage::Identity(identity) => self.remember_handle(
+                &identity.did,
+                identity.handle.as_ref().map(|h| h.as_str().to_string()),
+            ),
+            RepoMessage::Handle(handle) => {
+                self.remember_handle(&handle.did, Some(handle.handle.as_str().to_string()))
+            }
+            _ => {}
+        }
+
+        if let RepoMessage::Commit(commit) = message {
+            return self.handle_commit(commit).await;
+        }
+
+        if self.format == OutputFormat::Json {
+            let (t, body) = match message {
+                RepoMessage::Identity(m) => ("#identity", serde_json::to_value(m)?),
+                RepoMessage::Handle(m) => ("#handle", serde_json::to_value(m)?),
+                RepoMessage::Account(m) => ("#account", serde_json::to_value(m)?),
+                RepoMessage::Tombstone(m) => ("#tombstone", serde_json::to_value(m)?),
+                RepoMessage::Migrate(m) => ("#migrate", serde_json::to_value(m)?),
+                RepoMessage::Sync(m) => ("#sync", serde_json::to_value(m)?),
+                _ => return Ok(()),
+            };
+            self.print_json(json!({
+                "type": t,
+                "seq": message.seq(),
+                "repo": did.as_str(),
+                "handle": self.handle_for(did).await,
+                "body": body,
+            }));
+            return Ok(());
+        }
+
+        match message {
+            RepoMessage::Identity(identity) => {
+                let notice = match &identity.handle {
+                    Some(handle) => format!("identity updated, handle is @{}", handle.as_str()),
+                    None => "identity updated".to_string(),
+                };
+                self.print_notice(&identity.time, &identity.did, &notice);
+            }
+            RepoMessage::Handle(handle) => {
+                let notice = format!("handle changed to @{}", handle.handle.as_str());
+                self.print_notice(&handle.time, &handle.did, &notice);
+            }
+            RepoMessage::Account(account) => {
+                let notice = match (&account.status, account.active) {
+                    (Some(status), _) => format!("account is {status}"),
+                    (None, true) => "account is active".to_string(),
+                    (None, false) => "account is inactive".to_string(),
+                };
+                self.print_notice(&account.time, &account.did, &notice);
+            }
+            RepoMessage::Tombstone(tombstone) => {
+                self.print_notice(&tombstone.time, &tombstone.did, "account deleted");
+            }
+            RepoMessage::Migrate(migrate) => {
+                let notice = format!("migrating to {:?}", migrate.migrate_to);
+                self.print_notice(&migrate.time, &migrate.did, &notice);
+            }
+            _ => {}
         }
-        Ok(Self { dids_to_handles })
+        Ok(())
     }
 
     async fn handle_commit(&self, commit: &Commit) -> Result<()> {
-        if !self.dids_to_handles.contains_key(&commit.repo) {
-            return Ok(());
+        let wants_posts = self.collections.iter().any(|c| c == POST_PATH_TYPE);
+        if self.format == OutputFormat::Text && wants_posts {
+            for event in &post_events(commit).await? {
+                match event {
+                    PostEvent::Created(post) => self.print_post(post),
+                    PostEvent::Updated(post) => {
+                        let notice = format!("edited {}", post.uri());
+                        self.print_notice(&commit.time, &post.author, &notice);
+                        self.print_post(post);
+                    }
+                    PostEvent::Deleted(post) => {
+                        let notice = format!("deleted {}", post.uri());
+                        self.print_notice(&commit.time, &post.author, &notice);
+                    }
+                }
+            }
         }
 
-        for op in &commit.ops {
-            if op.action == CREATE_ACTION {
-                let path_type = op.path.split('/').next().unwrap();
-
-                if path_type == POST_PATH_TYPE {
-                    let (items, _) =
-                        rs_car::car_read_all(&mut commit.blocks.as_slice(), true).await?;
-                    if let Some((_, item)) = items.iter().find(|(cid, _)| {
-                        Some(cid.to_bytes()) == op.cid.as_ref().map(|cid| cid.0.to_bytes())
-                    }) {
-                        let record =
-                            serde_ipld_dagcbor::from_reader::<Record, _>(&mut item.as_slice())?;
-
-                        let new_post = NewPost {
-                            record,
-                            author: commit.repo.clone(),
-                        };
-                        new_post.print();
-                    } else {
-                        return Err(color_eyre::eyre::eyre!(
-                            "FAILED: could not find item with operation cid {:?} out of {} items",
-                            op.cid,
-                            items.len()
-                        ));
+        let ops: Vec<_> = commit
+            .ops
+            .iter()
+            .filter(|op| {
+                self.collections
+                    .iter()
+                    .any(|c| c == path_collection(&op.path))
+            })
+            .collect();
+        // Any collection's record can be decoded generically, so JSON output
+        // carries it whatever the record type
+        let blocks = if self.format == OutputFormat::Json && ops.iter().any(|op| op.cid.is_some()) {
+            Some(CommitBlocks::read(commit).await?)
+        } else {
+            None
+        };
+        let handle = match self.format {
+            OutputFormat::Json if !ops.is_empty() => self.handle_for(&commit.repo).await,
+            _ => None,
+        };
+
+        for op in ops {
+            let collection = path_collection(&op.path);
+
+            match self.format {
+                OutputFormat::Json => {
+                    let record = match (&blocks, &op.cid) {
+                        (Some(blocks), Some(_)) => match blocks.record::<Unknown>(op) {
+                            Ok(record) => Some(serde_json::to_value(record)?),
+                            Err(err) => {
+                                eprintln!("FAILED to decode {}: {err:?}", op.path);
+                                None
+                            }
+                        },
+                        _ => None,
+                    };
+                    self.print_json(json!({
+                        "type": "#commit",
+                        "seq": commit.seq,
+                        "repo": commit.repo.as_str(),
+                        "handle": handle,
+                        "time": commit.time.as_str(),
+                        "action": op.action,
+                        "collection": collection,
+                        "rkey": path_rkey(&op.path),
+                        "cid": op.cid.as_ref().map(|cid| cid.0.to_string()),
+                        "prev": op_prev(op).map(|cid| cid.0.to_string()),
+                        "record": record,
+                    }));
+                }
+                OutputFormat::Text => {
+                    // Posts are printed in full above, everything else just gets a notice
+                    if collection == POST_PATH_TYPE {
+                        continue;
                     }
+                    self.print_notice(
+                        &commit.time,
+                        &commit.repo,
+                        &format!("{} {}", op.action, op.path),
+                    );
                 }
             }
         }
         Ok(())
     }
 }
+
+/// Best effort, a repo we can't find a handle for is shown without one
+async fn lookup_handle(appview: &Agent, did: &Did) -> Option<String> {
+    let profile = appview
+        .api
+        .app
+        .bsky
+        .actor
+        .get_profile(
+            atrium_api::app::bsky::actor::get_profile::ParametersData {
+                actor: AtIdentifier::Did(did.clone()),
+            }
+            .into(),
+        )
+        .await
+        .inspect_err(|err| eprintln!("FAILED to look up handle for {}: {err:?}", did.as_str()))
+        .ok()?;
+    Some(profile.data.handle.as_str().to_string())
+}
+
+async fn resolve_handles(handles: &[String]) -> Result<HashMap<Did, Option<String>>> {
+    let client = ReqwestClient::new("https://bsky.social");
+    let agent = AtpAgent::new(client, MemorySessionStore::default());
+
+    let mut dids_to_handles = HashMap::new();
+    for handle in handles {
+        let resp = agent
+            .api
+            .com
+            .atproto
+            .identity
+            .resolve_handle(
+                atrium_api::com::atproto::identity::resolve_handle::ParametersData {
+                    handle: handle
+                        .parse()
+                        .map_err(|_| color_eyre::eyre::eyre!("invalid handle"))?,
+                }
+                .into(),
+            )
+            .await?;
+        let did = resp.data.did;
+        dids_to_handles.insert(did, Some(handle.to_string()));
+    }
+    Ok(dids_to_handles)
+}
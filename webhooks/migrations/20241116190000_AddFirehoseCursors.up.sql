@@ -0,0 +1,12 @@
+-- Add up migration script here
+CREATE TABLE
+  FirehoseCursors (
+    relay TEXT PRIMARY KEY NOT NULL,
+    seq BIGINT NOT NULL,
+    created_at TIMESTAMP
+    WITH
+      TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+      updated_at TIMESTAMP
+    WITH
+      TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+  );
@@ -0,0 +1,3 @@
+-- Add up migration script here
+ALTER TABLE SmsHandleSubscriptions
+ADD COLUMN notify_deletes BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,15 @@
+-- Add up migration script here
+CREATE TABLE
+  WebhookSubscriptions (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid (),
+    url TEXT NOT NULL,
+    secret TEXT NOT NULL,
+    handle TEXT NOT NULL,
+    did TEXT NOT NULL,
+    created_at TIMESTAMP
+    WITH
+      TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+      updated_at TIMESTAMP
+    WITH
+      TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+  );
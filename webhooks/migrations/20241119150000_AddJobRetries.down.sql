@@ -0,0 +1,7 @@
+-- Add down migration script here
+DROP INDEX idx_jobs_runnable;
+
+ALTER TABLE Jobs
+DROP COLUMN attempts,
+DROP COLUMN last_error,
+DROP COLUMN dead_at;
@@ -0,0 +1,9 @@
+-- Add up migration script here
+ALTER TABLE Jobs
+ADD COLUMN attempts INT NOT NULL DEFAULT 0,
+ADD COLUMN last_error TEXT,
+ADD COLUMN dead_at TIMESTAMPTZ;
+
+CREATE INDEX idx_jobs_runnable ON Jobs (priority DESC, run_at ASC)
+WHERE
+  dead_at IS NULL;
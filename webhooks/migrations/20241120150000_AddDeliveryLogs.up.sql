@@ -0,0 +1,16 @@
+-- Add up migration script here
+CREATE TABLE
+  DeliveryLogs (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid (),
+    job_id UUID NOT NULL,
+    name TEXT NOT NULL,
+    context TEXT NOT NULL,
+    attempt INT NOT NULL,
+    status TEXT NOT NULL,
+    error TEXT,
+    created_at TIMESTAMP
+    WITH
+      TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+  );
+
+CREATE INDEX idx_delivery_logs_created_at ON DeliveryLogs (created_at);
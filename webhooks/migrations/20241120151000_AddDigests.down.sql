@@ -0,0 +1,5 @@
+-- Add down migration script here
+DROP TABLE DigestEntries;
+
+ALTER TABLE SmsHandleSubscriptions
+DROP COLUMN digest;
@@ -0,0 +1,13 @@
+-- Add up migration script here
+ALTER TABLE SmsHandleSubscriptions
+ADD COLUMN digest BOOLEAN NOT NULL DEFAULT FALSE;
+
+CREATE TABLE
+  DigestEntries (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid (),
+    subscription_id UUID NOT NULL REFERENCES SmsHandleSubscriptions (id) ON DELETE CASCADE,
+    body TEXT NOT NULL,
+    created_at TIMESTAMP
+    WITH
+      TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+  );
@@ -0,0 +1,6 @@
+-- Add down migration script here
+ALTER TABLE SmsHandleSubscriptions
+DROP COLUMN confirmed_at,
+DROP COLUMN verification_code,
+DROP COLUMN verification_expires_at,
+DROP COLUMN verification_attempts;
@@ -0,0 +1,11 @@
+-- Add up migration script here
+ALTER TABLE SmsHandleSubscriptions
+ADD COLUMN confirmed_at TIMESTAMPTZ,
+ADD COLUMN verification_code TEXT,
+ADD COLUMN verification_expires_at TIMESTAMPTZ,
+ADD COLUMN verification_attempts INT NOT NULL DEFAULT 0;
+
+-- Everyone who subscribed before verification existed keeps their subscription
+UPDATE SmsHandleSubscriptions
+SET
+  confirmed_at = created_at;
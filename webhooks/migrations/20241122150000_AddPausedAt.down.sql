@@ -0,0 +1,3 @@
+-- Add down migration script here
+ALTER TABLE SmsHandleSubscriptions
+DROP COLUMN paused_at;
@@ -0,0 +1,3 @@
+-- Add up migration script here
+ALTER TABLE SmsHandleSubscriptions
+ADD COLUMN paused_at TIMESTAMPTZ;
@@ -0,0 +1,6 @@
+-- Add down migration script here
+DROP TABLE Sessions;
+
+ALTER TABLE SmsHandleSubscriptions
+DROP CONSTRAINT sms_handle_subscriptions_phone_number_did_key,
+DROP COLUMN last_fired_at;
@@ -0,0 +1,47 @@
+-- Add up migration script here
+-- Keep one row for any phone number that subscribed to the same DID twice,
+-- preferring a confirmed one, then the oldest
+DELETE FROM SmsHandleSubscriptions
+WHERE
+  id IN (
+    SELECT
+      id
+    FROM
+      (
+        SELECT
+          id,
+          ROW_NUMBER() OVER (
+            PARTITION BY
+              phone_number,
+              did
+            ORDER BY
+              confirmed_at IS NOT NULL DESC,
+              created_at ASC,
+              id ASC
+          ) AS rank
+        FROM
+          SmsHandleSubscriptions
+      ) ranked
+    WHERE
+      rank > 1
+  );
+
+ALTER TABLE SmsHandleSubscriptions
+ADD CONSTRAINT sms_handle_subscriptions_phone_number_did_key UNIQUE (phone_number, did),
+ADD COLUMN last_fired_at TIMESTAMPTZ;
+
+CREATE TABLE
+  Sessions (
+    session_id UUID PRIMARY KEY DEFAULT gen_random_uuid (),
+    phone_number TEXT,
+    verification_code TEXT,
+    verification_attempts INT NOT NULL DEFAULT 0,
+    verified_at TIMESTAMPTZ,
+    expires_at TIMESTAMPTZ NOT NULL,
+    created_at TIMESTAMP
+    WITH
+      TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+      updated_at TIMESTAMP
+    WITH
+      TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+  );
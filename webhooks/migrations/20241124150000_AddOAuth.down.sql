@@ -0,0 +1,11 @@
+-- Add down migration script here
+ALTER TABLE WebhookSubscriptions
+DROP COLUMN owner_did;
+
+ALTER TABLE SmsHandleSubscriptions
+DROP COLUMN owner_did;
+
+ALTER TABLE Sessions
+DROP COLUMN did;
+
+DROP TABLE OAuthRequests;
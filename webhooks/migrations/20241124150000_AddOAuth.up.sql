@@ -0,0 +1,23 @@
+-- Add up migration script here
+CREATE TABLE
+  OAuthRequests (
+    state TEXT PRIMARY KEY,
+    did TEXT NOT NULL,
+    issuer TEXT NOT NULL,
+    token_endpoint TEXT NOT NULL,
+    pkce_verifier TEXT NOT NULL,
+    dpop_key TEXT NOT NULL,
+    dpop_nonce TEXT,
+    created_at TIMESTAMP
+    WITH
+      TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+  );
+
+ALTER TABLE Sessions
+ADD COLUMN did TEXT;
+
+ALTER TABLE SmsHandleSubscriptions
+ADD COLUMN owner_did TEXT;
+
+ALTER TABLE WebhookSubscriptions
+ADD COLUMN owner_did TEXT;
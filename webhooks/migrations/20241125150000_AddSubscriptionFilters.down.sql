@@ -0,0 +1,6 @@
+-- Add down migration script here
+ALTER TABLE WebhookSubscriptions
+DROP COLUMN filters;
+
+ALTER TABLE SmsHandleSubscriptions
+DROP COLUMN filters;
@@ -0,0 +1,6 @@
+-- Add up migration script here
+ALTER TABLE SmsHandleSubscriptions
+ADD COLUMN filters JSONB NOT NULL DEFAULT '{}';
+
+ALTER TABLE WebhookSubscriptions
+ADD COLUMN filters JSONB NOT NULL DEFAULT '{}';
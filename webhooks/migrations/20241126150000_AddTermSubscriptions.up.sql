@@ -0,0 +1,18 @@
+-- Add up migration script here
+CREATE TABLE
+  TermSubscriptions (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid (),
+    phone_number TEXT NOT NULL,
+    kind TEXT NOT NULL CHECK (kind IN ('keyword', 'hashtag', 'domain')),
+    term TEXT NOT NULL,
+    paused_at TIMESTAMP
+    WITH
+      TIME ZONE,
+      created_at TIMESTAMP
+    WITH
+      TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+      updated_at TIMESTAMP
+    WITH
+      TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+      UNIQUE (phone_number, kind, term)
+  );
@@ -0,0 +1,4 @@
+-- Add down migration script here
+ALTER TABLE SmsHandleSubscriptions
+DROP COLUMN notify_mentions,
+DROP COLUMN notify_replies;
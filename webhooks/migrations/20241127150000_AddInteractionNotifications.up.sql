@@ -0,0 +1,4 @@
+-- Add up migration script here
+ALTER TABLE SmsHandleSubscriptions
+ADD COLUMN notify_mentions BOOLEAN NOT NULL DEFAULT FALSE,
+ADD COLUMN notify_replies BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,12 @@
+-- Add down migration script here
+DELETE FROM SmsHandleSubscriptions
+WHERE
+  channel = 'email';
+
+ALTER TABLE SmsHandleSubscriptions
+DROP CONSTRAINT sms_handle_subscriptions_email_did_key,
+DROP CONSTRAINT sms_handle_subscriptions_destination,
+ALTER COLUMN phone_number
+SET NOT NULL,
+DROP COLUMN email,
+DROP COLUMN channel;
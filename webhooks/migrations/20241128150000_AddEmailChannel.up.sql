@@ -0,0 +1,17 @@
+-- Add up migration script here
+ALTER TABLE SmsHandleSubscriptions
+ADD COLUMN channel TEXT NOT NULL DEFAULT 'sms' CHECK (channel IN ('sms', 'email')),
+ADD COLUMN email TEXT,
+ALTER COLUMN phone_number
+DROP NOT NULL,
+ADD CONSTRAINT sms_handle_subscriptions_destination CHECK (
+  (
+    channel = 'sms'
+    AND phone_number IS NOT NULL
+  )
+  OR (
+    channel = 'email'
+    AND email IS NOT NULL
+  )
+),
+ADD CONSTRAINT sms_handle_subscriptions_email_did_key UNIQUE (email, did);
@@ -0,0 +1,21 @@
+-- Add down migration script here
+DELETE FROM SmsHandleSubscriptions
+WHERE
+  channel IN ('discord', 'slack');
+
+ALTER TABLE SmsHandleSubscriptions
+DROP CONSTRAINT sms_handle_subscriptions_chat_webhook_url_did_key,
+DROP CONSTRAINT sms_handle_subscriptions_destination,
+ADD CONSTRAINT sms_handle_subscriptions_destination CHECK (
+  (
+    channel = 'sms'
+    AND phone_number IS NOT NULL
+  )
+  OR (
+    channel = 'email'
+    AND email IS NOT NULL
+  )
+),
+DROP CONSTRAINT smshandlesubscriptions_channel_check,
+ADD CONSTRAINT smshandlesubscriptions_channel_check CHECK (channel IN ('sms', 'email')),
+DROP COLUMN chat_webhook_url;
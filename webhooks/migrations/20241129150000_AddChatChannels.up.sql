@@ -0,0 +1,21 @@
+-- Add up migration script here
+ALTER TABLE SmsHandleSubscriptions
+ADD COLUMN chat_webhook_url TEXT,
+DROP CONSTRAINT smshandlesubscriptions_channel_check,
+ADD CONSTRAINT smshandlesubscriptions_channel_check CHECK (channel IN ('sms', 'email', 'discord', 'slack')),
+DROP CONSTRAINT sms_handle_subscriptions_destination,
+ADD CONSTRAINT sms_handle_subscriptions_destination CHECK (
+  (
+    channel = 'sms'
+    AND phone_number IS NOT NULL
+  )
+  OR (
+    channel = 'email'
+    AND email IS NOT NULL
+  )
+  OR (
+    channel IN ('discord', 'slack')
+    AND chat_webhook_url IS NOT NULL
+  )
+),
+ADD CONSTRAINT sms_handle_subscriptions_chat_webhook_url_did_key UNIQUE (chat_webhook_url, did);
@@ -0,0 +1,6 @@
+-- Add down migration script here
+UPDATE FirehoseCursors
+SET
+  relay = 'wss://' || relay
+WHERE
+  relay !~ '^wss?://';
@@ -0,0 +1,21 @@
+-- Add up migration script here
+-- Cursors used to be keyed by the full endpoint URL, they're now keyed by the
+-- relay's host. Where both forms exist keep whichever was saved most recently.
+DELETE FROM FirehoseCursors
+WHERE
+  relay NOT IN (
+    SELECT DISTINCT
+      ON (regexp_replace(relay, '^wss?://([^/]+).*$', '\1')) relay
+    FROM
+      FirehoseCursors
+    ORDER BY
+      regexp_replace(relay, '^wss?://([^/]+).*$', '\1'),
+      updated_at DESC
+  );
+
+UPDATE FirehoseCursors
+SET
+  relay = regexp_replace(relay, '^wss?://([^/]+).*$', '\1'),
+  updated_at = NOW()
+WHERE
+  relay ~ '^wss?://';
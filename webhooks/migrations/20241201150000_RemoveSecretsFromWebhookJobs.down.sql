@@ -0,0 +1,14 @@
+-- Add down migration script here
+UPDATE Jobs
+SET
+  payload = (Jobs.payload - 'subscription_id') || jsonb_build_object(
+    'url',
+    WebhookSubscriptions.url,
+    'secret',
+    WebhookSubscriptions.secret
+  )
+FROM
+  WebhookSubscriptions
+WHERE
+  Jobs.payload ->> 'kind' = 'webhook'
+  AND WebhookSubscriptions.id = (Jobs.payload ->> 'subscription_id')::UUID;
@@ -0,0 +1,18 @@
+-- Add up migration script here
+-- Webhook jobs used to carry the subscription's URL and secret, they now carry
+-- its id and look the rest up when they run. Jobs whose subscription is gone
+-- would be dropped at send time anyway.
+UPDATE Jobs
+SET
+  payload = (Jobs.payload - 'url' - 'secret') || jsonb_build_object('subscription_id', WebhookSubscriptions.id)
+FROM
+  WebhookSubscriptions
+WHERE
+  Jobs.payload ->> 'kind' = 'webhook'
+  AND WebhookSubscriptions.url = Jobs.payload ->> 'url'
+  AND WebhookSubscriptions.secret = Jobs.payload ->> 'secret';
+
+DELETE FROM Jobs
+WHERE
+  payload ->> 'kind' = 'webhook'
+  AND payload ? 'secret';
@@ -0,0 +1,3 @@
+-- Add up migration script here
+ALTER TABLE SmsHandleSubscriptions
+ADD COLUMN verification_sent_at TIMESTAMPTZ;
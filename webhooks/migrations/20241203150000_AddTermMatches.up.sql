@@ -0,0 +1,15 @@
+-- Add up migration script here
+-- Term matches wait here until the next batch, so a busy term costs each
+-- number at most one text per run
+CREATE TABLE
+  TermMatches (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid (),
+    subscription_id UUID NOT NULL REFERENCES TermSubscriptions (id) ON DELETE CASCADE,
+    phone_number TEXT NOT NULL,
+    post_uri TEXT NOT NULL,
+    message JSONB NOT NULL,
+    summary TEXT NOT NULL,
+    created_at TIMESTAMP
+    WITH
+      TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+  );
@@ -0,0 +1,3 @@
+-- Add up migration script here
+ALTER TABLE Jobs
+ADD COLUMN rate_limits INT NOT NULL DEFAULT 0;
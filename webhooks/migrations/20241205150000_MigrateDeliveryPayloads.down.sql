@@ -0,0 +1,64 @@
+-- Add down migration script here
+-- Only notices can go back to SMS and email, posts were formatted before they
+-- were queued back then
+DELETE FROM Jobs
+WHERE
+  payload ->> 'kind' = 'notify'
+  AND payload ->> 'channel' IN ('sms', 'email')
+  AND payload -> 'message' ->> 'type' = 'post';
+
+UPDATE Jobs
+SET
+  payload = jsonb_build_object(
+    'kind',
+    'sms',
+    'phone_number',
+    payload -> 'to',
+    'body',
+    payload -> 'message' -> 'body',
+    'subscription_id',
+    payload -> 'subscription_id'
+  )
+WHERE
+  payload ->> 'kind' = 'notify'
+  AND payload ->> 'channel' = 'sms';
+
+UPDATE Jobs
+SET
+  payload = jsonb_build_object(
+    'kind',
+    'email',
+    'to',
+    payload -> 'to',
+    'subject',
+    payload -> 'message' -> 'subject',
+    'text',
+    payload -> 'message' -> 'body',
+    'subscription_id',
+    payload -> 'subscription_id'
+  )
+WHERE
+  payload ->> 'kind' = 'notify'
+  AND payload ->> 'channel' = 'email';
+
+UPDATE Jobs
+SET
+  name = 'DeliverChat',
+  payload = jsonb_build_object(
+    'kind',
+    'chat',
+    'provider',
+    payload -> 'channel',
+    'url',
+    payload -> 'to',
+    'message',
+    CASE payload -> 'message' ->> 'type'
+      WHEN 'notice' THEN jsonb_build_object('type', 'notice', 'text', payload -> 'message' -> 'body')
+      ELSE payload -> 'message'
+    END,
+    'subscription_id',
+    payload -> 'subscription_id'
+  )
+WHERE
+  payload ->> 'kind' = 'notify'
+  AND payload ->> 'channel' IN ('discord', 'slack');
@@ -0,0 +1,76 @@
+-- Add up migration script here
+-- SMS, email and chat jobs used to each have their own payload shape, they're
+-- now all `notify` jobs carrying a message. Emails lose their pre-rendered
+-- HTML and go out as plain notices.
+UPDATE Jobs
+SET
+  payload = jsonb_build_object(
+    'kind',
+    'notify',
+    'channel',
+    'sms',
+    'to',
+    payload -> 'phone_number',
+    'message',
+    jsonb_build_object('type', 'notice', 'subject', '', 'body', payload -> 'body'),
+    'subscription_id',
+    payload -> 'subscription_id'
+  )
+WHERE
+  payload ->> 'kind' = 'sms';
+
+UPDATE Jobs
+SET
+  payload = jsonb_build_object(
+    'kind',
+    'notify',
+    'channel',
+    'email',
+    'to',
+    payload -> 'to',
+    'message',
+    jsonb_build_object(
+      'type',
+      'notice',
+      'subject',
+      payload -> 'subject',
+      'body',
+      payload -> 'text'
+    ),
+    'subscription_id',
+    payload -> 'subscription_id'
+  )
+WHERE
+  payload ->> 'kind' = 'email';
+
+-- Chat posts already have the same shape as a post message
+UPDATE Jobs
+SET
+  name = CASE payload ->> 'provider'
+    WHEN 'discord' THEN 'DeliverDiscord'
+    ELSE 'DeliverSlack'
+  END,
+  payload = jsonb_build_object(
+    'kind',
+    'notify',
+    'channel',
+    payload -> 'provider',
+    'to',
+    payload -> 'url',
+    'message',
+    CASE payload -> 'message' ->> 'type'
+      WHEN 'notice' THEN jsonb_build_object(
+        'type',
+        'notice',
+        'subject',
+        '',
+        'body',
+        payload -> 'message' -> 'text'
+      )
+      ELSE payload -> 'message'
+    END,
+    'subscription_id',
+    payload -> 'subscription_id'
+  )
+WHERE
+  payload ->> 'kind' = 'chat';
@@ -0,0 +1,6 @@
+-- Add down migration script here
+ALTER TABLE TermSubscriptions
+DROP COLUMN paused_by_stop;
+
+ALTER TABLE SmsHandleSubscriptions
+DROP COLUMN paused_by_stop;
@@ -0,0 +1,14 @@
+-- Add up migration script here
+ALTER TABLE SmsHandleSubscriptions
+ADD COLUMN paused_by_stop BOOLEAN NOT NULL DEFAULT FALSE;
+
+ALTER TABLE TermSubscriptions
+ADD COLUMN paused_by_stop BOOLEAN NOT NULL DEFAULT FALSE;
+
+-- There's no telling why existing rows were paused, so START can still
+-- resume them like it always has
+UPDATE SmsHandleSubscriptions SET paused_by_stop = TRUE
+WHERE paused_at IS NOT NULL AND phone_number IS NOT NULL;
+
+UPDATE TermSubscriptions SET paused_by_stop = TRUE
+WHERE paused_at IS NOT NULL;
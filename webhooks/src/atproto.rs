@@ -1,58 +1,77 @@
-mod frames;
-
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
 
-use atrium_api::{
-    agent::{store::MemorySessionStore, AtpAgent},
-    app::bsky::feed::post::Record,
-    com::atproto::sync::subscribe_repos::{Commit, NSID},
-    types::{string::Did, Collection as _},
+use atrium_api::{com::atproto::sync::subscribe_repos::Commit, types::string::Did};
+use bsky_firehose::{
+    post_events, ConnectionState, Firehose, FirehoseEvent, FrameError, NewPost, PostEvent, Replay,
+    RepoMessage, DEFAULT_ENDPOINT,
 };
-use atrium_xrpc_client::reqwest::ReqwestClient;
-use chrono::Local;
+use cja::app_state::AppState as _;
 use cja::color_eyre::Result;
-use cja::{app_state::AppState as _, color_eyre};
-use frames::Frame;
-use futures::StreamExt as _;
-use sqlx::PgPool;
+use serde::Deserialize;
+use sqlx::{types::Json, PgPool};
 use tokio::sync::RwLock;
-use tokio_tungstenite::{connect_async, tungstenite::Message};
-
-use crate::{sms, AppState};
 
-const CREATE_ACTION: &str = "create";
-const POST_PATH_TYPE: &str = atrium_api::app::bsky::feed::Post::NSID;
+use crate::{
+    digest,
+    filters::{Filter, PostFacts, PostFilters},
+    http::PublicClient,
+    jobs::{self, Delivery, Destination},
+    notifiers::{Channel, Notifiers, PostMessage},
+    terms::{TermKind, TermMatcher, TermSubscription},
+    webhook::Envelope,
+    AppState,
+};
 
-struct NewPost {
-    record: Record,
-    author: Did,
+/// A row of `SmsHandleSubscriptions`. Despite the name that table holds every
+/// subscription to a handle whatever its channel, it predates email and chat
+/// and `channel` says where each row's notifications go.
+#[derive(Debug, Clone)]
+struct Subscription {
+    id: uuid::Uuid,
+    did: String,
+    handle: String,
+    destination: Destination,
+    notify_deletes: bool,
+    notify_mentions: bool,
+    notify_replies: bool,
+    digest: bool,
+    filter: Arc<Filter>,
 }
 
-impl NewPost {
-    fn print(&self) {
-        println!(
-            "{} - {}",
-            self.record.created_at.as_ref().with_timezone(&Local),
-            self.author.as_str()
-        );
-        for line in self.record.text.split('\n') {
-            println!("  {line}");
-        }
+impl Subscription {
+    fn notice(&self, body: &str) -> Delivery {
+        let subject = format!("Update about @{}", self.handle);
+        self.destination.notice(&subject, body, Some(self.id))
+    }
+
+    /// A post from the account they follow. How much of it shows up is up to
+    /// the channel's notifier.
+    fn new_post(&self, post: &NewPost) -> Result<Delivery> {
+        let record = serde_json::to_value(&post.record)?;
+        let message = PostMessage::new(&self.handle, post.author.as_str(), post.rkey(), &record);
+        Ok(self.destination.post(message, Some(self.id)))
     }
 }
 
 #[derive(Debug, Clone)]
-struct Subscription {
-    did: String,
+struct WebhookSubscription {
+    id: uuid::Uuid,
     handle: String,
-    phone_number: String,
+    filter: Arc<Filter>,
 }
 
-impl Subscription {
-    async fn send_sms(&self, config: &sms::TwilioConfig, post: &NewPost) -> Result<()> {
-        sms::send_sms(config, &self.phone_number, &post.record.text).await?;
-
-        Ok(())
+impl WebhookSubscription {
+    fn delivery(&self, envelope: &Envelope) -> Result<Delivery> {
+        Ok(Delivery::Webhook {
+            subscription_id: self.id,
+            body: serde_json::to_value(envelope)?,
+        })
     }
 }
 
@@ -60,49 +79,152 @@ impl Subscription {
 pub struct Handler {
     state: AppState,
     dids_to_subscriptions: Arc<RwLock<HashMap<Did, Vec<Subscription>>>>,
+    dids_to_webhooks: Arc<RwLock<HashMap<Did, Vec<WebhookSubscription>>>>,
+    term_matcher: Arc<RwLock<Arc<TermMatcher>>>,
+    /// Whether any subscription wants mentions or replies, which means
+    /// decoding posts from every repo rather than only the subscribed ones
+    watching_interactions: Arc<AtomicBool>,
 }
 
-impl Handler {
-    pub async fn from_db(state: &AppState) -> Result<Self> {
-        let subscriptions = sqlx::query_as!(
-            Subscription,
-            "SELECT did, handle, phone_number FROM SmsHandleSubscriptions"
-        )
-        .fetch_all(state.db())
-        .await?;
+/// Subscriptions on a channel this instance can't deliver to are left out,
+/// they'll start firing once it's configured
+async fn load_subscriptions(
+    db: &PgPool,
+    notifiers: &Notifiers,
+) -> Result<HashMap<Did, Vec<Subscription>>> {
+    let rows = sqlx::query!(
+        r#"SELECT id, did, handle, channel, phone_number, email, chat_webhook_url, notify_deletes, notify_mentions, notify_replies, digest, filters as "filters: Json<PostFilters>"
+        FROM SmsHandleSubscriptions WHERE confirmed_at IS NOT NULL AND paused_at IS NULL"#
+    )
+    .fetch_all(db)
+    .await?;
 
-        let mut map: HashMap<Did, Vec<Subscription>> = HashMap::new();
+    let mut map: HashMap<Did, Vec<Subscription>> = HashMap::new();
 
-        for sub in subscriptions {
-            let did = sub.did.parse().unwrap();
-            map.entry(did).or_default().push(sub);
+    for row in rows {
+        let Some(destination) = Destination::from_row(
+            &row.channel,
+            row.phone_number,
+            row.email,
+            row.chat_webhook_url,
+        ) else {
+            tracing::warn!(id = %row.id, channel = %row.channel, "subscription has nowhere to deliver to");
+            continue;
+        };
+        if !notifiers.is_configured(destination.channel) {
+            continue;
         }
+        let Some(did) = parse_did(&row.did, row.id) else {
+            continue;
+        };
+        map.entry(did).or_default().push(Subscription {
+            id: row.id,
+            did: row.did,
+            handle: row.handle,
+            destination,
+            notify_deletes: row.notify_deletes,
+            notify_mentions: row.notify_mentions,
+            notify_replies: row.notify_replies,
+            digest: row.digest,
+            filter: Arc::new(Filter::new(row.filters.0)),
+        });
+    }
+
+    Ok(map)
+}
+
+async fn load_webhooks(db: &PgPool) -> Result<HashMap<Did, Vec<WebhookSubscription>>> {
+    let rows = sqlx::query!(
+        r#"SELECT id, did, handle, filters as "filters: Json<PostFilters>" FROM WebhookSubscriptions"#
+    )
+    .fetch_all(db)
+    .await?;
+
+    let mut map: HashMap<Did, Vec<WebhookSubscription>> = HashMap::new();
+
+    for row in rows {
+        let Some(did) = parse_did(&row.did, row.id) else {
+            continue;
+        };
+        map.entry(did).or_default().push(WebhookSubscription {
+            id: row.id,
+            handle: row.handle,
+            filter: Arc::new(Filter::new(row.filters.0)),
+        });
+    }
+
+    Ok(map)
+}
+
+/// DIDs are checked when subscriptions are saved, so a bad one means the row
+/// was edited by hand. It's skipped rather than taking down the firehose.
+fn parse_did(did: &str, id: uuid::Uuid) -> Option<Did> {
+    did.parse()
+        .inspect_err(|err| tracing::warn!(%err, %id, did, "skipping subscription with invalid DID"))
+        .ok()
+}
+
+async fn load_term_matcher(db: &PgPool, notifiers: &Notifiers) -> Result<TermMatcher> {
+    // Term subscriptions are texts only
+    if !notifiers.is_configured(Channel::Sms) {
+        return Ok(TermMatcher::default());
+    }
+
+    let rows = sqlx::query!(
+        "SELECT id, phone_number, kind, term FROM TermSubscriptions WHERE paused_at IS NULL"
+    )
+    .fetch_all(db)
+    .await?;
+
+    let subscriptions = rows
+        .into_iter()
+        .filter_map(|row| {
+            Some(TermSubscription {
+                id: row.id,
+                phone_number: row.phone_number,
+                kind: TermKind::parse(&row.kind)?,
+                term: row.term,
+            })
+        })
+        .collect();
+
+    Ok(TermMatcher::new(subscriptions))
+}
+
+impl Handler {
+    pub async fn from_db(state: &AppState) -> Result<Self> {
+        let map = load_subscriptions(state.db(), &state.notifiers).await?;
+        let webhooks = load_webhooks(state.db()).await?;
+        let term_matcher = load_term_matcher(state.db(), &state.notifiers).await?;
+        let watching_interactions = watches_interactions(&map);
 
         Ok(Self {
             state: state.clone(),
             dids_to_subscriptions: Arc::new(RwLock::new(map)),
+            dids_to_webhooks: Arc::new(RwLock::new(webhooks)),
+            term_matcher: Arc::new(RwLock::new(Arc::new(term_matcher))),
+            watching_interactions: Arc::new(AtomicBool::new(watching_interactions)),
         })
     }
 
     pub async fn update_from_db(&self) -> Result<()> {
-        let subscriptions = sqlx::query_as!(
-            Subscription,
-            "SELECT did, handle, phone_number FROM SmsHandleSubscriptions"
-        )
-        .fetch_all(self.state.db())
-        .await?;
-
-        let mut map: HashMap<Did, Vec<Subscription>> = HashMap::new();
-
-        for sub in subscriptions {
-            let did = sub.did.parse().unwrap();
-            map.entry(did).or_default().push(sub);
-        }
+        let map = load_subscriptions(self.state.db(), &self.state.notifiers).await?;
+        let webhooks = load_webhooks(self.state.db()).await?;
+        // Built before taking the lock so the firehose isn't kept waiting on it
+        let term_matcher = load_term_matcher(self.state.db(), &self.state.notifiers).await?;
 
+        self.watching_interactions
+            .store(watches_interactions(&map), Ordering::Relaxed);
         let mut write = self.dids_to_subscriptions.write().await;
         *write = map;
         drop(write);
 
+        let mut write = self.dids_to_webhooks.write().await;
+        *write = webhooks;
+        drop(write);
+
+        *self.term_matcher.write().await = Arc::new(term_matcher);
+
         println!("updated subscriptions");
         let map = self.dids_to_subscriptions.read().await;
         let dids: Vec<_> = map.keys().collect();
@@ -112,41 +234,313 @@ impl Handler {
         Ok(())
     }
 
-    async fn handle_commit(&self, commit: &Commit) -> Result<()> {
+    pub async fn handle_event(&self, event: &FirehoseEvent) -> Result<()> {
+        match event {
+            FirehoseEvent::Message(message) => self.handle_message(message).await,
+            FirehoseEvent::Error(err) => {
+                tracing::warn!(%err, "firehose sent an error");
+                Ok(())
+            }
+        }
+    }
+
+    async fn handle_message(&self, message: &RepoMessage) -> Result<()> {
+        match message {
+            RepoMessage::Commit(commit) => self.handle_commit(commit).await,
+            RepoMessage::Identity(identity) => {
+                let Some(handle) = &identity.handle else {
+                    return Ok(());
+                };
+                self.handle_changed(&identity.did, handle.as_str()).await
+            }
+            RepoMessage::Handle(handle) => {
+                self.handle_changed(&handle.did, handle.handle.as_str())
+                    .await
+            }
+            RepoMessage::Account(account) => {
+                if account.active {
+                    return Ok(());
+                }
+                let status = account.status.as_deref().unwrap_or("deactivated");
+                self.notify_subscribers(&account.did, |sub| {
+                    format!("@{}'s account is now {status}", sub.handle)
+                })
+                .await
+            }
+            RepoMessage::Tombstone(tombstone) => {
+                self.notify_subscribers(&tombstone.did, |sub| {
+                    format!("@{}'s account has been deleted", sub.handle)
+                })
+                .await
+            }
+            RepoMessage::Info(info) if info.name == "OutdatedCursor" => {
+                // The relay carries on from the oldest event it still has, so
+                // anything between our cursor and that point was missed
+                tracing::warn!(
+                    message = ?info.message,
+                    "persisted cursor is older than the relay's backfill window, some events were missed"
+                );
+                Ok(())
+            }
+            RepoMessage::Info(info) => {
+                tracing::info!(name = %info.name, message = ?info.message, "firehose info");
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Re-resolve the handle of every DID we have subscriptions for. The
+    /// firehose tells us about handle changes as they happen, this catches any
+    /// that happened while we weren't listening.
+    pub async fn refresh_handles(&self) -> Result<()> {
+        let mut dids_to_handles: HashMap<Did, String> = HashMap::new();
+        for (did, subs) in self.dids_to_subscriptions.read().await.iter() {
+            if let Some(sub) = subs.first() {
+                dids_to_handles.insert(did.clone(), sub.handle.clone());
+            }
+        }
+        for (did, webhooks) in self.dids_to_webhooks.read().await.iter() {
+            if let Some(webhook) = webhooks.first() {
+                dids_to_handles.insert(did.clone(), webhook.handle.clone());
+            }
+        }
+
+        for (did, handle) in dids_to_handles {
+            let current = match current_handle(&self.state, &did).await {
+                Ok(Some(current)) => current,
+                Ok(None) => continue,
+                Err(err) => {
+                    tracing::warn!(?err, did = did.as_str(), "failed to re-resolve handle");
+                    continue;
+                }
+            };
+            if current != handle {
+                tracing::info!(did = did.as_str(), %handle, %current, "handle changed");
+                self.handle_changed(&did, &current).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn handle_changed(&self, did: &Did, new_handle: &str) -> Result<()> {
+        let map = self.dids_to_subscriptions.read().await;
+        let subscriptions = map.get(did).cloned().unwrap_or_default();
+        drop(map);
+
+        let map = self.dids_to_webhooks.read().await;
+        let webhooks_changed = map
+            .get(did)
+            .is_some_and(|webhooks| webhooks.iter().any(|w| w.handle != new_handle));
+        drop(map);
+
+        let changed: Vec<_> = subscriptions
+            .into_iter()
+            .filter(|sub| sub.handle != new_handle)
+            .collect();
+        if changed.is_empty() && !webhooks_changed {
+            return Ok(());
+        }
+
+        sqlx::query!(
+            "UPDATE SmsHandleSubscriptions SET handle = $1, updated_at = NOW() WHERE did = $2",
+            new_handle,
+            did.as_str(),
+        )
+        .execute(self.state.db())
+        .await?;
+        sqlx::query!(
+            "UPDATE WebhookSubscriptions SET handle = $1, updated_at = NOW() WHERE did = $2",
+            new_handle,
+            did.as_str(),
+        )
+        .execute(self.state.db())
+        .await?;
+
+        for sub in &changed {
+            let notice = format!("@{} is now @{new_handle}", sub.handle);
+            self.enqueue(&sub.notice(&notice), "handle changed").await?;
+        }
+
+        self.update_from_db().await
+    }
+
+    async fn notify_subscribers(
+        &self,
+        did: &Did,
+        notice: impl Fn(&Subscription) -> String,
+    ) -> Result<()> {
         let map = self.dids_to_subscriptions.read().await;
-        let subscriptions = map.get(&commit.repo).cloned();
+        let subscriptions = map.get(did).cloned();
         let Some(subscriptions) = subscriptions else {
             return Ok(());
         };
         drop(map);
 
-        for op in &commit.ops {
-            if op.action == CREATE_ACTION {
-                let path_type = op.path.split('/').next().unwrap();
-
-                if path_type == POST_PATH_TYPE {
-                    let (items, _) =
-                        rs_car::car_read_all(&mut commit.blocks.as_slice(), true).await?;
-                    if let Some((_, item)) = items.iter().find(|(cid, _)| {
-                        Some(cid.to_bytes()) == op.cid.as_ref().map(|cid| cid.0.to_bytes())
-                    }) {
-                        let record =
-                            serde_ipld_dagcbor::from_reader::<Record, _>(&mut item.as_slice())?;
-
-                        let new_post = NewPost {
-                            record,
-                            author: commit.repo.clone(),
-                        };
-
-                        for sub in subscriptions.iter() {
-                            sub.send_sms(&self.state.twilio_config, &new_post).await?;
+        for sub in subscriptions.iter() {
+            self.enqueue(&sub.notice(&notice(sub)), "account notice")
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Tell subscribers when someone else mentions or replies to the account
+    /// they're watching. A reply that also mentions them is only sent once.
+    /// Subscriptions' filters and digest settings apply just like they do to
+    /// the account's own posts.
+    async fn notify_interactions(
+        &self,
+        post: &NewPost,
+        facts: &PostFacts,
+        context: &str,
+    ) -> Result<()> {
+        let author = post.author.as_str();
+
+        let map = self.dids_to_subscriptions.read().await;
+        let mut notices: Vec<(Subscription, String)> = vec![];
+        let mut watched = |did: &str, wants: fn(&Subscription) -> bool, notice: &str| {
+            let Ok(did) = did.parse::<Did>() else {
+                return;
+            };
+            let subscriptions = map.get(&did).into_iter().flatten();
+            for sub in subscriptions.filter(|sub| wants(sub) && sub.filter.matches(facts)) {
+                if notices.iter().all(|(notified, _)| notified.id != sub.id) {
+                    notices.push((sub.clone(), format!("{notice} @{}", sub.handle)));
+                }
+            }
+        };
+
+        if let Some(parent) = facts.reply_parent.as_deref().filter(|did| *did != author) {
+            watched(parent, |sub| sub.notify_replies, "New reply to");
+        }
+        for mentioned in facts.mentions.iter().filter(|did| *did != author) {
+            watched(mentioned, |sub| sub.notify_mentions, "New mention of");
+        }
+        drop(map);
+
+        // Only built if someone is getting the post itself
+        let mut message = None;
+        for (sub, notice) in notices {
+            if sub.digest {
+                let entry = format!("{notice}: {}", post.record.text);
+                digest::add_entry(self.state.db(), sub.id, &entry).await?;
+                continue;
+            }
+            let message = match &message {
+                Some(message) => message,
+                None => {
+                    // The handle is looked up when the job runs
+                    let record = serde_json::to_value(&post.record)?;
+                    message.insert(PostMessage::new("", author, post.rkey(), &record))
+                }
+            };
+            let delivery = sub
+                .destination
+                .post(message.clone().with_reason(notice), Some(sub.id));
+            self.enqueue(&delivery, context).await?;
+        }
+        Ok(())
+    }
+
+    async fn enqueue(&self, delivery: &Delivery, context: &str) -> Result<()> {
+        jobs::enqueue(self.state.db(), delivery, context).await
+    }
+
+    /// Everyone who might care about a commit to `repo`
+    pub(crate) async fn watchers(&self, repo: &Did) -> Watchers {
+        let map = self.dids_to_subscriptions.read().await;
+        let subscriptions = map.get(repo).cloned().unwrap_or_default();
+        drop(map);
+
+        let map = self.dids_to_webhooks.read().await;
+        let webhooks = map.get(repo).cloned().unwrap_or_default();
+        drop(map);
+
+        Watchers {
+            subscriptions,
+            webhooks,
+            terms: self.term_matcher.read().await.clone(),
+            interactions: self.watching_interactions.load(Ordering::Relaxed),
+        }
+    }
+
+    async fn handle_commit(&self, commit: &Commit) -> Result<()> {
+        let watchers = self.watchers(&commit.repo).await;
+        if watchers.is_empty() {
+            return Ok(());
+        }
+
+        let events = post_events(commit).await?;
+        self.handle_post_events(&watchers, events).await
+    }
+
+    pub(crate) async fn handle_post_events(
+        &self,
+        watchers: &Watchers,
+        events: Vec<PostEvent>,
+    ) -> Result<()> {
+        let Watchers {
+            subscriptions,
+            webhooks,
+            terms,
+            interactions,
+        } = watchers;
+
+        for event in events {
+            match event {
+                PostEvent::Created(new_post) => {
+                    let context = format!("new post {}", new_post.uri());
+                    let facts = PostFacts::from_record(&new_post.record)?;
+                    for sub in subscriptions
+                        .iter()
+                        .filter(|sub| sub.filter.matches(&facts))
+                    {
+                        if sub.digest {
+                            digest::add_entry(self.state.db(), sub.id, &new_post.record.text)
+                                .await?;
+                        } else {
+                            self.enqueue(&sub.new_post(&new_post)?, &context).await?;
                         }
-                    } else {
-                        return Err(color_eyre::eyre::eyre!(
-                            "FAILED: could not find item with operation cid {:?} out of {} items",
-                            op.cid,
-                            items.len()
-                        ));
+                    }
+                    for webhook in webhooks
+                        .iter()
+                        .filter(|webhook| webhook.filter.matches(&facts))
+                    {
+                        let envelope = Envelope::post_created(&new_post, &webhook.handle);
+                        self.enqueue(&webhook.delivery(&envelope)?, &context)
+                            .await?;
+                    }
+                    for sub in terms.matches(&facts) {
+                        sub.hold(self.state.db(), &new_post).await?;
+                    }
+                    if *interactions {
+                        self.notify_interactions(&new_post, &facts, &context)
+                            .await?;
+                    }
+                }
+                PostEvent::Deleted(deleted) => {
+                    for sub in subscriptions.iter().filter(|sub| sub.notify_deletes) {
+                        let notice = format!(
+                            "@{} deleted a post: https://bsky.app/profile/{}/post/{}",
+                            sub.handle,
+                            sub.did,
+                            deleted.rkey()
+                        );
+                        let context = format!("deleted post {}", deleted.uri());
+                        self.enqueue(&sub.notice(&notice), &context).await?;
+                    }
+                }
+                PostEvent::Updated(updated) => {
+                    let context = format!("updated post {}", updated.uri());
+                    let facts = PostFacts::from_record(&updated.record)?;
+                    for webhook in webhooks
+                        .iter()
+                        .filter(|webhook| webhook.filter.matches(&facts))
+                    {
+                        let envelope = Envelope::post_updated(&updated, &webhook.handle);
+                        self.enqueue(&webhook.delivery(&envelope)?, &context)
+                            .await?;
                     }
                 }
             }
@@ -155,30 +549,479 @@ impl Handler {
     }
 }
 
+/// The subscriptions, webhooks and term matches a commit gets checked against
+pub(crate) struct Watchers {
+    subscriptions: Vec<Subscription>,
+    webhooks: Vec<WebhookSubscription>,
+    terms: Arc<TermMatcher>,
+    interactions: bool,
+}
+
+impl Watchers {
+    /// Term subscriptions can match anyone, so with any active every post on
+    /// the network has to be decoded. Same for mentions and replies.
+    fn is_empty(&self) -> bool {
+        self.subscriptions.is_empty()
+            && self.webhooks.is_empty()
+            && self.terms.is_empty()
+            && !self.interactions
+    }
+}
+
+fn watches_interactions(subscriptions: &HashMap<Did, Vec<Subscription>>) -> bool {
+    subscriptions
+        .values()
+        .flatten()
+        .any(|sub| sub.notify_mentions || sub.notify_replies)
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct DidDocument {
+    #[serde(default)]
+    also_known_as: Vec<String>,
+    #[serde(default)]
+    service: Vec<DidService>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DidService {
+    id: String,
+    service_endpoint: String,
+}
+
+impl DidDocument {
+    /// The handle this DID claims, unverified
+    fn handle(&self) -> Option<&str> {
+        self.also_known_as
+            .iter()
+            .find_map(|aka| aka.strip_prefix("at://"))
+    }
+
+    /// Where the DID's repo is hosted
+    pub(crate) fn pds_endpoint(&self) -> Option<&str> {
+        self.service
+            .iter()
+            .find(|service| service.id == "#atproto_pds" || service.id.ends_with("#atproto_pds"))
+            .map(|service| service.service_endpoint.trim_end_matches('/'))
+    }
+}
+
+/// Where to fetch the DID document for `did:plc` and `did:web` DIDs
+fn did_document_url(did: &str) -> Option<String> {
+    if did.starts_with("did:plc:") {
+        return Some(format!("https://plc.directory/{did}"));
+    }
+    let domain = did.strip_prefix("did:web:")?;
+    Some(format!("https://{domain}/.well-known/did.json"))
+}
+
+/// `did:web` documents live wherever the DID says, so this goes through the
+/// public client
+pub(crate) async fn fetch_did_document(
+    client: &PublicClient,
+    did: &Did,
+) -> Result<Option<DidDocument>> {
+    let Some(url) = did_document_url(did.as_str()) else {
+        return Ok(None);
+    };
+    let document = client
+        .get(url)?
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    Ok(Some(document))
+}
+
+/// The handle a DID currently claims, if it still resolves back to the same
+/// DID. A handle is only valid when both directions agree.
+async fn current_handle(state: &AppState, did: &Did) -> Result<Option<String>> {
+    let Some(document) = fetch_did_document(&state.public_client, did).await? else {
+        return Ok(None);
+    };
+    let Some(handle) = document.handle() else {
+        return Ok(None);
+    };
+
+    let resolved = crate::resolve_handle(state, handle).await?;
+    if &resolved != did {
+        return Ok(None);
+    }
+
+    Ok(Some(handle.to_string()))
+}
+
+const APPVIEW_URL: &str = "https://public.api.bsky.app";
+
+/// The public AppView, for lookups that don't need auth
+#[derive(Debug, Clone)]
+pub struct AppView {
+    client: reqwest::Client,
+    base_url: String,
+}
+
+impl AppView {
+    pub fn new(client: reqwest::Client) -> Self {
+        Self::with_base_url(client, APPVIEW_URL)
+    }
+
+    /// Only changed to point at a fake
+    pub fn with_base_url(client: reqwest::Client, base_url: &str) -> Self {
+        Self {
+            client,
+            base_url: base_url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    async fn get<T: serde::de::DeserializeOwned>(
+        &self,
+        method: &str,
+        params: &[(&str, &str)],
+    ) -> Result<T> {
+        let url =
+            reqwest::Url::parse_with_params(&format!("{}/xrpc/{method}", self.base_url), params)?;
+
+        Ok(self
+            .client
+            .get(url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    /// Handles `did` follows. Only the first page, it's used for suggestions
+    /// rather than anything exhaustive.
+    pub(crate) async fn follows(&self, did: &str) -> Result<Vec<String>> {
+        let resp: FollowsResponse = self
+            .get(
+                "app.bsky.graph.getFollows",
+                &[("actor", did), ("limit", "100")],
+            )
+            .await?;
+
+        Ok(resp.follows.into_iter().map(|f| f.handle).collect())
+    }
+
+    /// Handle, display name and avatar for `did`
+    pub(crate) async fn profile(&self, did: &str) -> Result<Profile> {
+        self.get("app.bsky.actor.getProfile", &[("actor", did)])
+            .await
+    }
+
+    /// `did`'s handle, or the DID itself if it can't be looked up. For
+    /// authors nobody subscribed to, so we don't already know it.
+    pub(crate) async fn handle_or_did(&self, did: &str) -> String {
+        match self.profile(did).await {
+            Ok(profile) => profile.handle,
+            Err(err) => {
+                tracing::warn!(?err, %did, "failed to look up handle");
+                did.to_string()
+            }
+        }
+    }
+
+    /// `None` if the post has been deleted or isn't visible
+    pub(crate) async fn quoted_post(&self, uri: &str) -> Result<Option<QuotedPost>> {
+        let resp: PostsResponse = self.get("app.bsky.feed.getPosts", &[("uris", uri)]).await?;
+
+        let Some(post) = resp.posts.into_iter().next() else {
+            return Ok(None);
+        };
+        let rkey = bsky_firehose::commit::path_rkey(uri);
+
+        Ok(Some(QuotedPost {
+            url: format!("https://bsky.app/profile/{}/post/{rkey}", post.author.did),
+            handle: post.author.handle,
+            text: post.record["text"].as_str().unwrap_or_default().to_string(),
+        }))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct FollowsResponse {
+    follows: Vec<FollowedActor>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FollowedActor {
+    handle: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Profile {
+    pub handle: String,
+    pub display_name: Option<String>,
+    pub avatar: Option<String>,
+}
+
+/// A post quoted by another, with just enough to show it inline
+#[derive(Debug)]
+pub(crate) struct QuotedPost {
+    pub handle: String,
+    pub text: String,
+    /// bsky.app link to the quoted post
+    pub url: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct PostsResponse {
+    posts: Vec<PostView>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PostView {
+    author: PostViewAuthor,
+    record: serde_json::Value,
+}
+
+#[derive(Debug, Deserialize)]
+struct PostViewAuthor {
+    did: String,
+    handle: String,
+}
+
+async fn load_cursor(db: &PgPool, relay: &str) -> Result<Option<i64>> {
+    let row = sqlx::query!("SELECT seq FROM FirehoseCursors WHERE relay = $1", relay)
+        .fetch_optional(db)
+        .await?;
+
+    Ok(row.map(|row| row.seq))
+}
+
+async fn save_cursor(db: &PgPool, relay: &str, seq: i64) -> Result<()> {
+    sqlx::query!(
+        "INSERT INTO FirehoseCursors (relay, seq) VALUES ($1, $2)
+        ON CONFLICT (relay) DO UPDATE SET seq = EXCLUDED.seq, updated_at = NOW()",
+        relay,
+        seq,
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+async fn clear_cursor(db: &PgPool, relay: &str) -> Result<()> {
+    sqlx::query!("DELETE FROM FirehoseCursors WHERE relay = $1", relay)
+        .execute(db)
+        .await?;
+
+    Ok(())
+}
+
+/// Persist the cursor, logging rather than failing so a database blip
+/// doesn't take the firehose down with it
+async fn checkpoint(db: &PgPool, relay: &str, cursor: Option<i64>) {
+    let Some(seq) = cursor else {
+        return;
+    };
+    if let Err(err) = save_cursor(db, relay, seq).await {
+        tracing::error!(?err, seq, "failed to checkpoint firehose cursor");
+    }
+}
+
+/// How far through the firehose it's safe to resume from. Stops moving at the
+/// first event that fails, so a restart picks up from there and retries it.
+#[derive(Debug)]
+struct Progress {
+    handled: Option<i64>,
+    failed: bool,
+}
+
+impl Progress {
+    fn new(cursor: Option<i64>) -> Self {
+        Self {
+            handled: cursor,
+            failed: false,
+        }
+    }
+
+    fn record(&mut self, seq: Option<i64>, ok: bool) {
+        if !ok {
+            self.failed = true;
+        }
+        if !self.failed {
+            self.handled = seq.or(self.handled);
+        }
+    }
+}
+
+/// Cursors are stored by relay host, so `wss://bsky.network` and
+/// `wss://bsky.network/` share one cursor however the endpoint was written
+fn cursor_key(endpoint: &str) -> String {
+    let Ok(url) = reqwest::Url::parse(endpoint) else {
+        return endpoint.to_string();
+    };
+    match (url.host_str(), url.port()) {
+        (Some(host), Some(port)) => format!("{host}:{port}"),
+        (Some(host), None) => host.to_string(),
+        (None, _) => endpoint.to_string(),
+    }
+}
+
 pub async fn consume_firehose(handler: Handler) -> Result<()> {
-    const FIREHOSE_DOMAIN: &str = "bsky.network";
+    const CHECKPOINT_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
 
     let map = handler.dids_to_subscriptions.read().await;
     let dids: Vec<_> = map.keys().collect();
     println!("listening for posts from {:?}", dids);
     drop(map);
 
-    let (mut stream, _) = connect_async(format!("wss://{FIREHOSE_DOMAIN}/xrpc/{NSID}")).await?;
-    while let Some(result) = {
-        if let Some(Ok(Message::Binary(data))) = stream.next().await {
-            Some(Frame::try_from(data.as_slice()))
-        } else {
-            None
-        }
-    } {
-        if let Ok(Frame::Message(Some(t), message)) = result {
-            if t.as_str() == "#commit" {
-                let commit = serde_ipld_dagcbor::from_reader(message.body.as_slice())?;
-                if let Err(err) = handler.handle_commit(&commit).await {
-                    eprintln!("FAILED: {err:?}");
+    // Overridable so we can point at a local fake relay when testing
+    let endpoint =
+        std::env::var("FIREHOSE_ENDPOINT").unwrap_or_else(|_| DEFAULT_ENDPOINT.to_string());
+
+    let relay = cursor_key(&endpoint);
+
+    let db = handler.state.db().clone();
+    let cursor = load_cursor(&db, &relay).await?;
+    tracing::info!(%endpoint, ?cursor, "resuming firehose");
+
+    let mut firehose = Firehose::new(&endpoint).with_cursor(cursor);
+    let mut state = firehose.state();
+    tokio::spawn(async move {
+        while state.changed().await.is_ok() {
+            match &*state.borrow_and_update() {
+                ConnectionState::Connecting => tracing::info!("connecting to firehose"),
+                ConnectionState::Connected => tracing::info!("connected to firehose"),
+                ConnectionState::Reconnecting {
+                    attempt,
+                    delay,
+                    reason,
+                } => tracing::warn!(attempt, ?delay, %reason, "reconnecting to firehose"),
+            }
+        }
+    });
+
+    let mut progress = Progress::new(cursor);
+    let mut last_checkpoint = std::time::Instant::now();
+    loop {
+        match firehose.next().await {
+            Ok(FirehoseEvent::Error(err)) if err.error == FrameError::FutureCursor => {
+                tracing::warn!(%err, "firehose rejected our cursor, resetting it");
+                if let Err(err) = clear_cursor(&db, &relay).await {
+                    tracing::warn!(?err, "failed to clear firehose cursor");
+                }
+                progress = Progress::new(None);
+            }
+            Ok(event) => {
+                let handled = handler.handle_event(&event).await;
+                if let Err(err) = &handled {
+                    tracing::error!(?err, "failed to handle firehose event, holding the cursor");
+                }
+                if let FirehoseEvent::Message(message) = &event {
+                    progress.record(message.seq(), handled.is_ok());
+                }
+            }
+            Err(err) => tracing::warn!(?err, "failed to decode firehose frame"),
+        }
+
+        if last_checkpoint.elapsed() >= CHECKPOINT_INTERVAL {
+            checkpoint(&db, &relay, progress.handled).await;
+            last_checkpoint = std::time::Instant::now();
+        }
+    }
+}
+
+/// Feed a capture written by `firehose-cli --record` through the handler
+/// instead of connecting to a relay. Used for testing against known traffic.
+pub async fn consume_replay(handler: Handler, path: String, speed: Option<f64>) -> Result<()> {
+    tracing::info!(%path, ?speed, "replaying firehose capture");
+
+    let mut replay = Replay::open(&path).await?.with_speed(speed);
+    while let Some(event) = replay.next().await.transpose() {
+        match event {
+            Ok(event) => {
+                if let Err(err) = handler.handle_event(&event).await {
+                    tracing::warn!(?err, "failed to handle replayed event");
                 }
             }
+            Err(err) => tracing::warn!(?err, "failed to decode replayed frame"),
         }
     }
+
+    tracing::info!(%path, "finished replaying firehose capture");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[sqlx::test]
+    async fn rows_with_bad_dids_are_skipped(db: PgPool) {
+        sqlx::query!(
+            "INSERT INTO WebhookSubscriptions (url, secret, handle, did)
+            VALUES ('https://example.com/a', 'secret', 'alice.test', 'did:plc:alice'),
+                ('https://example.com/b', 'secret', 'bob.test', 'not a did')"
+        )
+        .execute(&db)
+        .await
+        .unwrap();
+
+        let webhooks = load_webhooks(&db).await.unwrap();
+        assert_eq!(webhooks.len(), 1);
+        assert!(webhooks.contains_key(&"did:plc:alice".parse::<Did>().unwrap()));
+    }
+
+    #[test]
+    fn progress_stops_at_the_first_failure() {
+        let mut progress = Progress::new(Some(10));
+        progress.record(Some(11), true);
+        progress.record(None, true);
+        assert_eq!(progress.handled, Some(11));
+
+        progress.record(Some(12), false);
+        progress.record(Some(13), true);
+        assert_eq!(progress.handled, Some(11));
+    }
+
+    #[test]
+    fn cursors_are_keyed_by_relay_host() {
+        assert_eq!(cursor_key(DEFAULT_ENDPOINT), "bsky.network");
+        assert_eq!(cursor_key("wss://bsky.network/"), "bsky.network");
+        assert_eq!(cursor_key("ws://127.0.0.1:8765"), "127.0.0.1:8765");
+    }
+
+    #[test]
+    fn did_documents_for_plc_and_web() {
+        assert_eq!(
+            did_document_url("did:plc:ewvi7nxzyoun6zhxrhs64oiz").as_deref(),
+            Some("https://plc.directory/did:plc:ewvi7nxzyoun6zhxrhs64oiz")
+        );
+        assert_eq!(
+            did_document_url("did:web:example.com").as_deref(),
+            Some("https://example.com/.well-known/did.json")
+        );
+        assert_eq!(did_document_url("did:key:z6Mk"), None);
+    }
+
+    #[test]
+    fn reads_handle_and_pds_from_did_document() {
+        let document: DidDocument = serde_json::from_str(
+            r##"{
+                "id": "did:plc:ewvi7nxzyoun6zhxrhs64oiz",
+                "alsoKnownAs": ["at://atproto.com"],
+                "service": [{
+                    "id": "#atproto_pds",
+                    "type": "AtprotoPersonalDataServer",
+                    "serviceEndpoint": "https://enoki.us-east.host.bsky.network/"
+                }]
+            }"##,
+        )
+        .unwrap();
+
+        assert_eq!(document.handle(), Some("atproto.com"));
+        assert_eq!(
+            document.pds_endpoint(),
+            Some("https://enoki.us-east.host.bsky.network")
+        );
+    }
+}
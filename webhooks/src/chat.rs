@@ -0,0 +1,469 @@
+use std::time::Duration;
+
+use cja::{color_eyre, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    atproto::{AppView, Profile, QuotedPost},
+    http::PublicClient,
+    jobs::{Gone, RetryAfter},
+    notifiers::{Channel, Message, Notifier, PostMessage},
+};
+
+/// Bluesky's butterfly blue, used as the Discord embed colour
+const BLUESKY_BLUE: u32 = 0x1185FE;
+
+/// Discord and Slack both cap message text, well above what a post can be
+/// but quoted posts get trimmed to this
+const MAX_QUOTE_CHARS: usize = 300;
+
+/// Which chat service an incoming webhook URL posts to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChatProvider {
+    Discord,
+    Slack,
+}
+
+impl ChatProvider {
+    pub fn channel(self) -> Channel {
+        match self {
+            ChatProvider::Discord => Channel::Discord,
+            ChatProvider::Slack => Channel::Slack,
+        }
+    }
+
+    pub fn display_name(self) -> &'static str {
+        match self {
+            ChatProvider::Discord => "Discord",
+            ChatProvider::Slack => "Slack",
+        }
+    }
+
+    /// Work out the provider from the incoming webhook URL itself, so people
+    /// can paste either kind into the same box
+    pub fn from_url(url: &reqwest::Url) -> Option<Self> {
+        if url.scheme() != "https" {
+            return None;
+        }
+        match (url.host_str()?, url.path()) {
+            ("discord.com" | "discordapp.com", path) if path.starts_with("/api/webhooks/") => {
+                Some(ChatProvider::Discord)
+            }
+            ("hooks.slack.com", path) if path.starts_with("/services/") => {
+                Some(ChatProvider::Slack)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl PostMessage {
+    fn discord_markdown(&self) -> String {
+        self.segments()
+            .into_iter()
+            .map(|(text, link)| match link {
+                Some(link) => format!("[{}](<{}>)", escape_discord(text), link.url),
+                None => escape_discord(text),
+            })
+            .collect()
+    }
+
+    fn slack_mrkdwn(&self) -> String {
+        self.segments()
+            .into_iter()
+            .map(|(text, link)| match link {
+                Some(link) => format!("<{}|{}>", link.url, escape_slack(text)),
+                None => escape_slack(text),
+            })
+            .collect()
+    }
+}
+
+fn escape_discord(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '\\' | '*' | '_' | '~' | '`' | '|' | '[' | ']') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn escape_slack(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn truncate(text: &str, max_chars: usize) -> String {
+    if text.chars().count() <= max_chars {
+        return text.to_string();
+    }
+    let mut truncated: String = text.chars().take(max_chars - 1).collect();
+    truncated.push('…');
+    truncated
+}
+
+fn author_name(post: &PostMessage, profile: Option<&Profile>) -> String {
+    match profile.and_then(|profile| profile.display_name.as_deref()) {
+        Some(name) if !name.is_empty() => format!("{name} (@{})", post.handle),
+        _ => format!("@{}", post.handle),
+    }
+}
+
+fn discord_payload(
+    post: &PostMessage,
+    profile: Option<&Profile>,
+    quoted: Option<&QuotedPost>,
+) -> Value {
+    let mut embed = json!({
+        "author": {
+            "name": author_name(post, profile),
+            "url": format!("https://bsky.app/profile/{}", post.author),
+        },
+        "description": post.discord_markdown(),
+        "url": post.permalink,
+        "color": BLUESKY_BLUE,
+        "footer": { "text": "Bluesky" },
+    });
+    if let Some(avatar) = profile.and_then(|profile| profile.avatar.as_deref()) {
+        embed["author"]["icon_url"] = json!(avatar);
+    }
+    if let Some(created_at) = &post.created_at {
+        embed["timestamp"] = json!(created_at);
+    }
+    if let Some(quoted) = quoted {
+        embed["fields"] = json!([{
+            "name": format!("Quoting @{}", quoted.handle),
+            "value": format!("{}\n[View quoted post](<{}>)", escape_discord(&truncate(&quoted.text, MAX_QUOTE_CHARS)), quoted.url),
+        }]);
+    } else if let Some(uri) = &post.quote_uri {
+        embed["fields"] = json!([{ "name": "Quoting", "value": uri }]);
+    }
+
+    // Discord shows embeds that share a url as one gallery, which is the only
+    // way to get more than one image onto a message
+    let mut images = post.images.iter();
+    if let Some(first) = images.next() {
+        embed["image"] = json!({ "url": first.fullsize });
+    }
+    let mut embeds = vec![embed];
+    embeds.extend(
+        images.map(|image| json!({ "url": post.permalink, "image": { "url": image.fullsize } })),
+    );
+
+    json!({
+        "content": escape_discord(&post.heading()),
+        "embeds": embeds,
+        "allowed_mentions": { "parse": [] },
+    })
+}
+
+fn slack_payload(
+    post: &PostMessage,
+    profile: Option<&Profile>,
+    quoted: Option<&QuotedPost>,
+) -> Value {
+    let mut author = vec![];
+    if let Some(avatar) = profile.and_then(|profile| profile.avatar.as_deref()) {
+        author.push(json!({ "type": "image", "image_url": avatar, "alt_text": post.handle }));
+    }
+    author.push(json!({
+        "type": "mrkdwn",
+        "text": format!("*<https://bsky.app/profile/{}|{}>*", post.author, escape_slack(&author_name(post, profile))),
+    }));
+
+    let mut blocks = vec![json!({ "type": "context", "elements": author })];
+    if !post.text.is_empty() {
+        blocks.push(json!({
+            "type": "section",
+            "text": { "type": "mrkdwn", "text": post.slack_mrkdwn() },
+        }));
+    }
+    for (i, image) in post.images.iter().enumerate() {
+        let alt = if image.alt.is_empty() {
+            format!("Image {}", i + 1)
+        } else {
+            image.alt.clone()
+        };
+        blocks.push(json!({ "type": "image", "image_url": image.thumb, "alt_text": alt }));
+    }
+    let quote = match (quoted, &post.quote_uri) {
+        (Some(quoted), _) => Some(format!(
+            "Quoting <{}|@{}>: {}",
+            quoted.url,
+            quoted.handle,
+            escape_slack(&truncate(&quoted.text, MAX_QUOTE_CHARS))
+        )),
+        (None, Some(uri)) => Some(format!("Quoting {}", escape_slack(uri))),
+        (None, None) => None,
+    };
+    if let Some(quote) = quote {
+        blocks
+            .push(json!({ "type": "context", "elements": [{ "type": "mrkdwn", "text": quote }] }));
+    }
+    blocks.push(json!({
+        "type": "actions",
+        "elements": [{
+            "type": "button",
+            "text": { "type": "plain_text", "text": "View on Bluesky" },
+            "url": post.permalink,
+        }],
+    }));
+
+    json!({
+        "text": format!("{}: {}", escape_slack(&post.heading()), escape_slack(&post.text)),
+        "blocks": blocks,
+        "unfurl_links": false,
+    })
+}
+
+fn notice_payload(provider: ChatProvider, text: &str) -> Value {
+    match provider {
+        ChatProvider::Discord => json!({
+            "content": escape_discord(text),
+            "allowed_mentions": { "parse": [] },
+        }),
+        ChatProvider::Slack => json!({ "text": escape_slack(text) }),
+    }
+}
+
+/// Posts to Discord or Slack incoming webhooks. Subscribers give us the URL,
+/// so there's nothing to configure.
+pub struct ChatNotifier {
+    provider: ChatProvider,
+    /// For the webhook URLs subscribers give us
+    client: PublicClient,
+    /// For profile and quoted post lookups
+    appview: AppView,
+}
+
+impl ChatNotifier {
+    pub fn new(provider: ChatProvider, client: PublicClient, appview: AppView) -> Self {
+        Self {
+            provider,
+            client,
+            appview,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Notifier for ChatNotifier {
+    async fn send(&self, url: &str, message: &Message) -> Result<()> {
+        let provider = self.provider;
+        let payload = match message {
+            Message::Notice { body, .. } => notice_payload(provider, body),
+            Message::Post(post) => {
+                // Nice to have, not worth failing the delivery over
+                let profile = self.appview.profile(&post.author)
+                    .await
+                    .inspect_err(
+                        |err| tracing::warn!(?err, author = %post.author, "failed to fetch profile"),
+                    )
+                    .ok();
+                let quoted = match &post.quote_uri {
+                    Some(uri) => self
+                        .appview
+                        .quoted_post(uri)
+                        .await
+                        .inspect_err(
+                            |err| tracing::warn!(?err, %uri, "failed to fetch quoted post"),
+                        )
+                        .ok()
+                        .flatten(),
+                    None => None,
+                };
+
+                match provider {
+                    ChatProvider::Discord => {
+                        discord_payload(post, profile.as_ref(), quoted.as_ref())
+                    }
+                    ChatProvider::Slack => slack_payload(post, profile.as_ref(), quoted.as_ref()),
+                }
+            }
+        };
+
+        post_payload(&self.client, provider, url, &payload).await
+    }
+}
+
+async fn post_payload(
+    client: &PublicClient,
+    provider: ChatProvider,
+    url: &str,
+    payload: &Value,
+) -> Result<()> {
+    let resp = client.post(url)?.json(payload).send().await?;
+
+    if resp.status() == reqwest::StatusCode::TOO_MANY_REQUESTS {
+        return Err(RetryAfter(retry_after(resp).await).into());
+    }
+    // Discord answers a deleted webhook with a 404, Slack uses 404 for a
+    // revoked one and 410 for an archived channel
+    if matches!(
+        resp.status(),
+        reqwest::StatusCode::NOT_FOUND | reqwest::StatusCode::GONE
+    ) {
+        return Err(Gone.into());
+    }
+    if !resp.status().is_success() {
+        return Err(color_eyre::eyre::eyre!(
+            "{} webhook failed with {}",
+            provider.channel().as_str(),
+            resp.status()
+        ));
+    }
+
+    Ok(())
+}
+
+/// How long a 429 asked us to wait. Both send a `Retry-After` header in
+/// seconds, Discord also puts a more precise `retry_after` in the body.
+async fn retry_after(resp: reqwest::Response) -> Duration {
+    const DEFAULT: Duration = Duration::from_secs(30);
+
+    let header = resp
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<f64>().ok());
+    let body = resp
+        .json::<Value>()
+        .await
+        .ok()
+        .and_then(|body| body["retry_after"].as_f64());
+
+    body.or(header)
+        .filter(|secs| secs.is_finite() && *secs >= 0.0)
+        .map(Duration::from_secs_f64)
+        .unwrap_or(DEFAULT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notifiers::{Link, LinkKind};
+
+    fn post() -> PostMessage {
+        PostMessage::new(
+            "alice.test",
+            "did:plc:alice",
+            "3kabc",
+            &json!({
+                "text": "Hi @bob.test, see example.com #rust_lang",
+                "createdAt": "2024-11-20T12:00:00.000Z",
+                "facets": [
+                    { "index": { "byteStart": 3, "byteEnd": 12 }, "features": [{ "$type": "app.bsky.richtext.facet#mention", "did": "did:plc:bob" }] },
+                    { "index": { "byteStart": 18, "byteEnd": 29 }, "features": [{ "$type": "app.bsky.richtext.facet#link", "uri": "https://example.com" }] },
+                    { "index": { "byteStart": 30, "byteEnd": 40 }, "features": [{ "$type": "app.bsky.richtext.facet#tag", "tag": "rust_lang" }] }
+                ],
+                "embed": {
+                    "$type": "app.bsky.embed.recordWithMedia",
+                    "record": { "record": { "uri": "at://did:plc:carol/app.bsky.feed.post/3kxyz", "cid": "" } },
+                    "media": {
+                        "images": [{ "alt": "", "image": { "ref": { "$link": "bafkcat" } } }]
+                    }
+                }
+            }),
+        )
+    }
+
+    #[test]
+    fn detects_provider_from_url() {
+        let provider = |url: &str| ChatProvider::from_url(&url.parse().unwrap());
+
+        assert_eq!(
+            provider("https://discord.com/api/webhooks/123/abc"),
+            Some(ChatProvider::Discord)
+        );
+        assert_eq!(
+            provider("https://hooks.slack.com/services/T0/B0/xyz"),
+            Some(ChatProvider::Slack)
+        );
+        assert_eq!(provider("http://hooks.slack.com/services/T0/B0/xyz"), None);
+        assert_eq!(provider("https://example.com/api/webhooks/123"), None);
+    }
+
+    #[test]
+    fn facets_become_links() {
+        let post = post();
+
+        assert_eq!(
+            post.discord_markdown(),
+            "Hi [@bob.test](<https://bsky.app/profile/did:plc:bob>), see [example.com](<https://example.com>) [#rust\\_lang](<https://bsky.app/hashtag/rust_lang>)"
+        );
+        assert_eq!(
+            post.slack_mrkdwn(),
+            "Hi <https://bsky.app/profile/did:plc:bob|@bob.test>, see <https://example.com|example.com> <https://bsky.app/hashtag/rust_lang|#rust_lang>"
+        );
+    }
+
+    #[test]
+    fn bad_facets_are_left_as_text() {
+        let mut post = post();
+        post.text = "héllo".to_string();
+        post.links = vec![
+            Link {
+                byte_start: 0,
+                byte_end: 2,
+                url: "https://a.test".to_string(),
+                kind: LinkKind::Link,
+            },
+            Link {
+                byte_start: 3,
+                byte_end: 99,
+                url: "https://b.test".to_string(),
+                kind: LinkKind::Link,
+            },
+        ];
+
+        assert_eq!(post.slack_mrkdwn(), "héllo");
+    }
+
+    #[test]
+    fn payloads_carry_author_images_and_quote() {
+        let post = post();
+        let profile = Profile {
+            handle: "alice.test".to_string(),
+            display_name: Some("Alice".to_string()),
+            avatar: Some("https://cdn.bsky.app/avatar.jpg".to_string()),
+        };
+        let quoted = QuotedPost {
+            handle: "carol.test".to_string(),
+            text: "the original".to_string(),
+            url: "https://bsky.app/profile/did:plc:carol/post/3kxyz".to_string(),
+        };
+
+        let discord = discord_payload(&post, Some(&profile), Some(&quoted));
+        let embed = &discord["embeds"][0];
+        assert_eq!(embed["author"]["name"], "Alice (@alice.test)");
+        assert_eq!(
+            embed["author"]["icon_url"],
+            "https://cdn.bsky.app/avatar.jpg"
+        );
+        assert_eq!(embed["url"], post.permalink);
+        assert_eq!(embed["fields"][0]["name"], "Quoting @carol.test");
+        assert_eq!(
+            embed["image"]["url"],
+            "https://cdn.bsky.app/img/feed_fullsize/plain/did:plc:alice/bafkcat@jpeg"
+        );
+
+        let slack = slack_payload(&post, None, None);
+        let blocks = slack["blocks"].as_array().unwrap();
+        assert_eq!(
+            blocks[0]["elements"][0]["text"],
+            "*<https://bsky.app/profile/did:plc:alice|@alice.test>*"
+        );
+        assert_eq!(blocks[2]["type"], "image");
+        assert_eq!(blocks[2]["alt_text"], "Image 1");
+        assert_eq!(
+            blocks[3]["elements"][0]["text"],
+            "Quoting at://did:plc:carol/app.bsky.feed.post/3kxyz"
+        );
+        assert_eq!(blocks[4]["elements"][0]["url"], post.permalink);
+    }
+}
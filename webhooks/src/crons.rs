@@ -0,0 +1,222 @@
+use std::{future::Future, pin::Pin, sync::Arc, time::Duration};
+
+use cja::{app_state::AppState as _, Result};
+use sqlx::PgPool;
+
+use crate::{atproto::Handler, digest, jobs, terms, AppState};
+
+type CronFn = Arc<dyn Fn() -> Pin<Box<dyn Future<Output = Result<()>> + Send>> + Send + Sync>;
+
+/// Where a cron's schedule lives
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    /// Runs once per interval across every replica. The last run is tracked in
+    /// `Crons.last_run_at` and claimed atomically, so only one replica fires it.
+    Global,
+    /// Runs on every replica, for work that touches in-process state like the
+    /// subscription cache.
+    Replica,
+}
+
+struct Cron {
+    name: &'static str,
+    interval: Duration,
+    scope: Scope,
+    run: CronFn,
+}
+
+#[derive(Default)]
+pub struct CronRegistry {
+    crons: Vec<Cron>,
+}
+
+impl CronRegistry {
+    pub fn register<F, Fut>(&mut self, name: &'static str, interval: Duration, scope: Scope, f: F)
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.crons.push(Cron {
+            name,
+            interval,
+            scope,
+            run: Arc::new(move || Box::pin(f())),
+        });
+    }
+
+    /// Run every registered cron on its own schedule until the process exits
+    pub async fn run(self, db: PgPool) -> Result<()> {
+        let tasks = self
+            .crons
+            .into_iter()
+            .map(|cron| tokio::spawn(run_cron(db.clone(), cron)));
+        futures::future::try_join_all(tasks).await?;
+
+        Ok(())
+    }
+}
+
+/// Claim this cron's slot if `interval` has passed since anyone last ran it.
+/// Returns true if we won and should run it.
+async fn try_claim(db: &PgPool, name: &str, interval: Duration) -> Result<bool> {
+    let claimed = sqlx::query!(
+        "INSERT INTO Crons (cron_id, name, last_run_at, created_at, updated_at)
+        VALUES ($1, $2, NOW(), NOW(), NOW())
+        ON CONFLICT (name) DO UPDATE SET last_run_at = NOW(), updated_at = NOW()
+        WHERE Crons.last_run_at <= NOW() - make_interval(secs => $3)
+        RETURNING cron_id",
+        uuid::Uuid::new_v4(),
+        name,
+        interval.as_secs_f64(),
+    )
+    .fetch_optional(db)
+    .await?;
+
+    Ok(claimed.is_some())
+}
+
+async fn run_cron(db: PgPool, cron: Cron) {
+    // Global crons poll more often than they run, so a replica notices
+    // promptly when the one that ran it last has gone away
+    let poll = match cron.scope {
+        Scope::Global => cron.interval.min(Duration::from_secs(60)),
+        Scope::Replica => cron.interval,
+    };
+    let mut ticker = tokio::time::interval(poll);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        ticker.tick().await;
+
+        if cron.scope == Scope::Global {
+            match try_claim(&db, cron.name, cron.interval).await {
+                Ok(true) => {}
+                Ok(false) => continue,
+                Err(err) => {
+                    tracing::error!(?err, name = cron.name, "failed to claim cron");
+                    continue;
+                }
+            }
+        }
+
+        tracing::debug!(name = cron.name, "running cron");
+        if let Err(err) = (cron.run)().await {
+            tracing::error!(?err, name = cron.name, "cron failed");
+        }
+    }
+}
+
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+const REFRESH_HANDLES_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const DELIVERY_LOG_RETENTION: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+const DIGEST_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+/// Also the most often a number can be texted about its term subscriptions
+const TERM_MATCH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// SMS subscriptions whose verification code expired without being confirmed
+async fn prune_pending_subscriptions(db: &PgPool) -> Result<()> {
+    let pruned = sqlx::query!(
+        "DELETE FROM SmsHandleSubscriptions
+        WHERE confirmed_at IS NULL AND verification_expires_at < NOW() - INTERVAL '1 day'"
+    )
+    .execute(db)
+    .await?;
+
+    tracing::info!(
+        subscriptions = pruned.rows_affected(),
+        "pruned pending subscriptions"
+    );
+    Ok(())
+}
+
+async fn prune_sessions(db: &PgPool) -> Result<()> {
+    let pruned = sqlx::query!("DELETE FROM Sessions WHERE expires_at < NOW()")
+        .execute(db)
+        .await?;
+    // Logins that were abandoned at the authorization server
+    let requests =
+        sqlx::query!("DELETE FROM OAuthRequests WHERE created_at < NOW() - INTERVAL '1 hour'")
+            .execute(db)
+            .await?;
+
+    tracing::info!(
+        sessions = pruned.rows_affected(),
+        oauth_requests = requests.rows_affected(),
+        "pruned sessions"
+    );
+    Ok(())
+}
+
+pub fn registry(state: &AppState, handler: &Handler) -> CronRegistry {
+    let mut registry = CronRegistry::default();
+
+    let h = handler.clone();
+    registry.register(
+        "reload_subscriptions",
+        RELOAD_INTERVAL,
+        Scope::Replica,
+        move || {
+            let h = h.clone();
+            async move { h.update_from_db().await }
+        },
+    );
+
+    let h = handler.clone();
+    registry.register(
+        "refresh_handles",
+        REFRESH_HANDLES_INTERVAL,
+        Scope::Global,
+        move || {
+            let h = h.clone();
+            async move { h.refresh_handles().await }
+        },
+    );
+
+    let db = state.db().clone();
+    registry.register(
+        "prune_delivery_logs",
+        PRUNE_INTERVAL,
+        Scope::Global,
+        move || {
+            let db = db.clone();
+            async move { jobs::prune_delivery_logs(&db, DELIVERY_LOG_RETENTION).await }
+        },
+    );
+
+    let db = state.db().clone();
+    registry.register(
+        "prune_pending_subscriptions",
+        PRUNE_INTERVAL,
+        Scope::Global,
+        move || {
+            let db = db.clone();
+            async move { prune_pending_subscriptions(&db).await }
+        },
+    );
+
+    let db = state.db().clone();
+    registry.register("prune_sessions", PRUNE_INTERVAL, Scope::Global, move || {
+        let db = db.clone();
+        async move { prune_sessions(&db).await }
+    });
+
+    let db = state.db().clone();
+    registry.register("send_digests", DIGEST_INTERVAL, Scope::Global, move || {
+        let db = db.clone();
+        async move { digest::send_digests(&db).await }
+    });
+
+    let db = state.db().clone();
+    registry.register(
+        "send_term_matches",
+        TERM_MATCH_INTERVAL,
+        Scope::Global,
+        move || {
+            let db = db.clone();
+            async move { terms::send_matches(&db).await }
+        },
+    );
+
+    registry
+}
@@ -0,0 +1,187 @@
+use std::collections::BTreeMap;
+
+use cja::Result;
+use sqlx::PgPool;
+
+use crate::jobs::{self, Destination};
+
+/// Longest a single post gets in a digest before it's cut off
+const MAX_ENTRY_CHARS: usize = 80;
+
+/// Hold a post for the subscription's next digest instead of texting it now
+pub async fn add_entry(db: &PgPool, subscription_id: uuid::Uuid, body: &str) -> Result<()> {
+    sqlx::query!(
+        "INSERT INTO DigestEntries (subscription_id, body) VALUES ($1, $2)",
+        subscription_id,
+        body,
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+struct PendingEntry {
+    id: uuid::Uuid,
+    subscription_id: uuid::Uuid,
+    channel: String,
+    phone_number: Option<String>,
+    email: Option<String>,
+    chat_webhook_url: Option<String>,
+    handle: String,
+    body: String,
+}
+
+/// Roll every held post up into one text or email per subscription. Entries are
+/// removed in the same transaction that queues the texts, so a crash can't
+/// send a digest twice or drop one. Posts held for a subscription that has
+/// since been paused are dropped rather than saved up for when it resumes.
+pub async fn send_digests(db: &PgPool) -> Result<()> {
+    let mut tx = db.begin().await?;
+
+    let skipped = sqlx::query!(
+        "DELETE FROM DigestEntries
+        USING SmsHandleSubscriptions
+        WHERE SmsHandleSubscriptions.id = DigestEntries.subscription_id
+        AND (confirmed_at IS NULL OR paused_at IS NOT NULL)"
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    let entries = sqlx::query_as!(
+        PendingEntry,
+        "SELECT DigestEntries.id, subscription_id, channel, phone_number, email, chat_webhook_url, handle, body
+        FROM DigestEntries
+        JOIN SmsHandleSubscriptions ON SmsHandleSubscriptions.id = DigestEntries.subscription_id
+        WHERE confirmed_at IS NOT NULL AND paused_at IS NULL
+        ORDER BY DigestEntries.created_at ASC
+        FOR UPDATE OF DigestEntries SKIP LOCKED"
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    let mut by_subscription: BTreeMap<uuid::Uuid, Vec<&PendingEntry>> = BTreeMap::new();
+    for entry in &entries {
+        by_subscription
+            .entry(entry.subscription_id)
+            .or_default()
+            .push(entry);
+    }
+
+    for entries in by_subscription.values() {
+        let first = entries[0];
+        let Some(destination) = Destination::from_row(
+            &first.channel,
+            first.phone_number.clone(),
+            first.email.clone(),
+            first.chat_webhook_url.clone(),
+        ) else {
+            continue;
+        };
+        let bodies: Vec<_> = entries.iter().map(|e| e.body.as_str()).collect();
+        let delivery = destination.notice(
+            &format!("Your digest for @{}", first.handle),
+            &digest_body(&first.handle, &bodies),
+            Some(first.subscription_id),
+        );
+        jobs::enqueue(
+            &mut *tx,
+            &delivery,
+            &format!("digest for @{}", first.handle),
+        )
+        .await?;
+    }
+
+    let ids: Vec<_> = entries.iter().map(|e| e.id).collect();
+    sqlx::query!("DELETE FROM DigestEntries WHERE id = ANY($1)", &ids)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+
+    tracing::info!(
+        entries = entries.len(),
+        digests = by_subscription.len(),
+        skipped = skipped.rows_affected(),
+        "sent digests"
+    );
+    Ok(())
+}
+
+fn digest_body(handle: &str, bodies: &[&str]) -> String {
+    let noun = if bodies.len() == 1 { "post" } else { "posts" };
+    // Replies and mentions end up here too, so this doesn't say who wrote them
+    let mut digest = format!("{} new {noun} for @{handle}:", bodies.len());
+    for body in bodies {
+        digest.push_str("\n- ");
+        digest.push_str(&truncate(body, MAX_ENTRY_CHARS));
+    }
+    digest
+}
+
+pub(crate) fn truncate(text: &str, max_chars: usize) -> String {
+    let text = text.replace('\n', " ");
+    if text.chars().count() <= max_chars {
+        return text;
+    }
+    let mut truncated: String = text.chars().take(max_chars - 1).collect();
+    truncated.push('…');
+    truncated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn digest_lists_each_post() {
+        assert_eq!(
+            digest_body("alice.test", &["hello", "second\nline"]),
+            "2 new posts for @alice.test:\n- hello\n- second line"
+        );
+        assert_eq!(
+            digest_body("alice.test", &["hello"]),
+            "1 new post for @alice.test:\n- hello"
+        );
+    }
+
+    #[sqlx::test]
+    async fn paused_subscriptions_get_no_digest(db: PgPool) {
+        let subscribe = |phone_number: &'static str, paused: bool| {
+            sqlx::query_scalar!(
+                "INSERT INTO SmsHandleSubscriptions (phone_number, handle, did, digest, confirmed_at, paused_at)
+                VALUES ($1, 'alice.test', 'did:plc:alice', TRUE, NOW(), CASE WHEN $2 THEN NOW() END)
+                RETURNING id",
+                phone_number,
+                paused,
+            )
+            .fetch_one(&db)
+        };
+        let active = subscribe("+15555550100", false).await.unwrap();
+        let paused = subscribe("+15555550101", true).await.unwrap();
+        add_entry(&db, active, "hello").await.unwrap();
+        add_entry(&db, paused, "hello").await.unwrap();
+
+        send_digests(&db).await.unwrap();
+
+        let jobs = sqlx::query_scalar!("SELECT payload FROM Jobs")
+            .fetch_all(&db)
+            .await
+            .unwrap();
+        assert_eq!(jobs.len(), 1);
+        assert_eq!(jobs[0]["to"], "+15555550100");
+        let remaining = sqlx::query_scalar!("SELECT COUNT(*) FROM DigestEntries")
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        assert_eq!(remaining, Some(0));
+    }
+
+    #[test]
+    fn long_posts_are_truncated() {
+        let long = "a".repeat(100);
+        let truncated = truncate(&long, 10);
+        assert_eq!(truncated, format!("{}…", "a".repeat(9)));
+        assert_eq!(truncated.chars().count(), 10);
+    }
+}
@@ -0,0 +1,403 @@
+use atrium_api::app::bsky::feed::post::Record;
+use cja::Result;
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+
+/// What a subscriber has asked to be notified about, stored as JSON in each
+/// subscription's `filters` column. The default lets everything through.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PostFilters {
+    pub exclude_replies: bool,
+    /// No replies and no quote posts, only posts that stand on their own
+    pub top_level_only: bool,
+    /// The post must contain at least one of these
+    pub require_keywords: Vec<String>,
+    pub exclude_keywords: Vec<String>,
+    pub require_regex: Option<String>,
+    pub exclude_regex: Option<String>,
+    /// Language tags like `en` or `pt-BR`. `en` also matches `en-US`.
+    pub langs: Vec<String>,
+    /// Only posts with images or video attached
+    pub media_only: bool,
+}
+
+impl PostFilters {
+    /// Errors with a message for the user if a regex doesn't compile
+    pub fn validate(&self) -> std::result::Result<(), String> {
+        for pattern in [&self.require_regex, &self.exclude_regex]
+            .into_iter()
+            .flatten()
+        {
+            compile(pattern).map_err(|e| format!("Invalid regex {pattern:?}: {e}"))?;
+        }
+        Ok(())
+    }
+
+    pub fn is_default(&self) -> bool {
+        self == &Self::default()
+    }
+}
+
+fn compile(pattern: &str) -> std::result::Result<Regex, regex::Error> {
+    RegexBuilder::new(pattern)
+        .case_insensitive(true)
+        .size_limit(1 << 16)
+        .build()
+}
+
+/// The bits of a post the filters look at, worked out once per post rather
+/// than once per subscription
+#[derive(Debug, Default)]
+pub struct PostFacts {
+    /// Lowercased so keyword matching can ignore case
+    pub(crate) text: String,
+    is_reply: bool,
+    is_quote: bool,
+    has_media: bool,
+    langs: Vec<String>,
+    /// Hashtags from the post's `tag` facets, lowercased and without the `#`
+    pub(crate) tags: Vec<String>,
+    /// Hosts of every link in the post, from link facets and external embeds
+    pub(crate) domains: Vec<String>,
+    /// DIDs of everyone the post mentions
+    pub(crate) mentions: Vec<String>,
+    /// DID of whoever wrote the post this one replies to
+    pub(crate) reply_parent: Option<String>,
+}
+
+impl PostFacts {
+    pub fn from_record(record: &Record) -> Result<Self> {
+        Ok(Self::from_json(&serde_json::to_value(record)?))
+    }
+
+    pub(crate) fn from_json(record: &serde_json::Value) -> Self {
+        let embed_type = record["embed"]["$type"].as_str().unwrap_or_default();
+
+        let features = record["facets"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|facet| facet["features"].as_array())
+            .flatten();
+        let mut tags = vec![];
+        let mut links = vec![];
+        let mut mentions = vec![];
+        for feature in features {
+            match feature["$type"].as_str() {
+                Some("app.bsky.richtext.facet#tag") => {
+                    if let Some(tag) = feature["tag"].as_str() {
+                        tags.push(tag.trim_start_matches('#').to_lowercase());
+                    }
+                }
+                Some("app.bsky.richtext.facet#link") => links.extend(feature["uri"].as_str()),
+                Some("app.bsky.richtext.facet#mention") => {
+                    mentions.extend(feature["did"].as_str().map(ToString::to_string))
+                }
+                _ => {}
+            }
+        }
+        links.extend(record["embed"]["external"]["uri"].as_str());
+        let domains = links
+            .into_iter()
+            .filter_map(|link| reqwest::Url::parse(link).ok())
+            .filter_map(|url| url.host_str().map(str::to_lowercase))
+            .collect();
+
+        Self {
+            text: record["text"].as_str().unwrap_or_default().to_lowercase(),
+            is_reply: !record["reply"].is_null(),
+            is_quote: matches!(
+                embed_type,
+                "app.bsky.embed.record" | "app.bsky.embed.recordWithMedia"
+            ),
+            has_media: matches!(
+                embed_type,
+                "app.bsky.embed.images" | "app.bsky.embed.video" | "app.bsky.embed.recordWithMedia"
+            ),
+            langs: record["langs"]
+                .as_array()
+                .map(|langs| {
+                    langs
+                        .iter()
+                        .filter_map(|lang| lang.as_str())
+                        .map(str::to_lowercase)
+                        .collect()
+                })
+                .unwrap_or_default(),
+            tags,
+            domains,
+            mentions,
+            reply_parent: record["reply"]["parent"]["uri"]
+                .as_str()
+                .and_then(uri_authority)
+                .map(ToString::to_string),
+        }
+    }
+}
+
+/// Whether `word` appears in `text` other than as part of a longer word
+fn contains_word(text: &str, word: &str) -> bool {
+    !word.is_empty()
+        && text
+            .match_indices(word)
+            .any(|(start, m)| is_whole_word(text, start, start + m.len()))
+}
+
+/// So `rust` matches "I love rust!" but not "trust"
+pub(crate) fn is_whole_word(text: &str, start: usize, end: usize) -> bool {
+    let before = text[..start].chars().next_back();
+    let after = text[end..].chars().next();
+    !before.is_some_and(char::is_alphanumeric) && !after.is_some_and(char::is_alphanumeric)
+}
+
+/// The repo an `at://` URI points into, e.g. the DID in
+/// `at://did:plc:abc/app.bsky.feed.post/3kabc`
+fn uri_authority(uri: &str) -> Option<&str> {
+    uri.strip_prefix("at://")?.split('/').next()
+}
+
+/// [`PostFilters`] with the regexes compiled, ready to match posts against
+#[derive(Debug, Default)]
+pub struct Filter {
+    filters: PostFilters,
+    require_keywords: Vec<String>,
+    exclude_keywords: Vec<String>,
+    require_regex: Option<Regex>,
+    exclude_regex: Option<Regex>,
+}
+
+impl Filter {
+    pub fn new(filters: PostFilters) -> Self {
+        // Patterns are validated when they're saved, so a bad one here means
+        // the row was edited by hand. Ignore it rather than drop every post.
+        let compile_or_warn = |pattern: &Option<String>| {
+            let pattern = pattern.as_deref()?;
+            compile(pattern)
+                .inspect_err(|err| tracing::warn!(%err, pattern, "ignoring invalid filter regex"))
+                .ok()
+        };
+        let lowercase = |keywords: &[String]| keywords.iter().map(|k| k.to_lowercase()).collect();
+
+        Self {
+            require_keywords: lowercase(&filters.require_keywords),
+            exclude_keywords: lowercase(&filters.exclude_keywords),
+            require_regex: compile_or_warn(&filters.require_regex),
+            exclude_regex: compile_or_warn(&filters.exclude_regex),
+            filters,
+        }
+    }
+
+    pub fn matches(&self, post: &PostFacts) -> bool {
+        let f = &self.filters;
+
+        if (f.exclude_replies || f.top_level_only) && post.is_reply {
+            return false;
+        }
+        if f.top_level_only && post.is_quote {
+            return false;
+        }
+        if f.media_only && !post.has_media {
+            return false;
+        }
+        if !self.require_keywords.is_empty()
+            && !self
+                .require_keywords
+                .iter()
+                .any(|k| contains_word(&post.text, k))
+        {
+            return false;
+        }
+        if self
+            .exclude_keywords
+            .iter()
+            .any(|k| contains_word(&post.text, k))
+        {
+            return false;
+        }
+        if let Some(regex) = &self.require_regex {
+            if !regex.is_match(&post.text) {
+                return false;
+            }
+        }
+        if let Some(regex) = &self.exclude_regex {
+            if regex.is_match(&post.text) {
+                return false;
+            }
+        }
+        if !f.langs.is_empty() && !post.langs.iter().any(|lang| lang_matches(&f.langs, lang)) {
+            return false;
+        }
+
+        true
+    }
+}
+
+fn lang_matches(wanted: &[String], lang: &str) -> bool {
+    wanted.iter().any(|wanted| {
+        let wanted = wanted.to_lowercase();
+        lang == wanted
+            || lang
+                .strip_prefix(&wanted)
+                .is_some_and(|rest| rest.starts_with('-'))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn post(record: serde_json::Value) -> PostFacts {
+        PostFacts::from_json(&record)
+    }
+
+    fn filter(filters: PostFilters) -> Filter {
+        Filter::new(filters)
+    }
+
+    #[test]
+    fn default_lets_everything_through() {
+        let reply = post(json!({ "text": "hi", "reply": { "root": {}, "parent": {} } }));
+        assert!(filter(PostFilters::default()).matches(&reply));
+    }
+
+    #[test]
+    fn excludes_replies_and_quotes() {
+        let reply = post(json!({ "text": "hi", "reply": { "root": {}, "parent": {} } }));
+        let quote = post(json!({ "text": "hi", "embed": { "$type": "app.bsky.embed.record" } }));
+        let plain = post(json!({ "text": "hi" }));
+
+        let no_replies = filter(PostFilters {
+            exclude_replies: true,
+            ..Default::default()
+        });
+        assert!(!no_replies.matches(&reply));
+        assert!(no_replies.matches(&quote));
+
+        let top_level = filter(PostFilters {
+            top_level_only: true,
+            ..Default::default()
+        });
+        assert!(!top_level.matches(&reply));
+        assert!(!top_level.matches(&quote));
+        assert!(top_level.matches(&plain));
+    }
+
+    #[test]
+    fn keywords_and_regex_ignore_case() {
+        let p = post(json!({ "text": "Shipping the new Rust release" }));
+
+        let require = filter(PostFilters {
+            require_keywords: vec!["rust".to_string(), "go".to_string()],
+            ..Default::default()
+        });
+        assert!(require.matches(&p));
+
+        let exclude = filter(PostFilters {
+            exclude_keywords: vec!["SHIPPING".to_string()],
+            ..Default::default()
+        });
+        assert!(!exclude.matches(&p));
+
+        let regex = filter(PostFilters {
+            require_regex: Some(r"\brust\b".to_string()),
+            exclude_regex: Some("beta".to_string()),
+            ..Default::default()
+        });
+        assert!(regex.matches(&p));
+    }
+
+    #[test]
+    fn keywords_match_whole_words() {
+        let p = post(json!({ "text": "I trust the process, go team!" }));
+
+        let rust = filter(PostFilters {
+            require_keywords: vec!["rust".to_string()],
+            ..Default::default()
+        });
+        assert!(!rust.matches(&p));
+
+        let go = filter(PostFilters {
+            exclude_keywords: vec!["go".to_string()],
+            ..Default::default()
+        });
+        assert!(!go.matches(&p));
+    }
+
+    #[test]
+    fn langs_match_on_primary_subtag() {
+        let us = post(json!({ "text": "hi", "langs": ["en-US"] }));
+        let pt = post(json!({ "text": "oi", "langs": ["pt-BR"] }));
+        let undeclared = post(json!({ "text": "hi" }));
+
+        let english = filter(PostFilters {
+            langs: vec!["en".to_string()],
+            ..Default::default()
+        });
+        assert!(english.matches(&us));
+        assert!(!english.matches(&pt));
+        assert!(!english.matches(&undeclared));
+        assert!(!lang_matches(&["e".to_string()], "en"));
+    }
+
+    #[test]
+    fn media_only() {
+        let images = post(json!({ "text": "", "embed": { "$type": "app.bsky.embed.images" } }));
+        let link = post(json!({ "text": "", "embed": { "$type": "app.bsky.embed.external" } }));
+
+        let media = filter(PostFilters {
+            media_only: true,
+            ..Default::default()
+        });
+        assert!(media.matches(&images));
+        assert!(!media.matches(&link));
+    }
+
+    #[test]
+    fn reads_tags_and_link_domains() {
+        let p = post(json!({
+            "text": "New release #RustLang",
+            "facets": [
+                { "features": [{ "$type": "app.bsky.richtext.facet#tag", "tag": "RustLang" }] },
+                { "features": [{ "$type": "app.bsky.richtext.facet#link", "uri": "https://Blog.Rust-Lang.org/2024" }] }
+            ],
+            "embed": {
+                "$type": "app.bsky.embed.external",
+                "external": { "uri": "https://github.com/rust-lang/rust", "title": "", "description": "" }
+            }
+        }));
+
+        assert_eq!(p.tags, vec!["rustlang"]);
+        assert_eq!(p.domains, vec!["blog.rust-lang.org", "github.com"]);
+    }
+
+    #[test]
+    fn reads_mentions_and_reply_parent() {
+        let p = post(json!({
+            "text": "@atproto.com nice",
+            "facets": [
+                { "features": [{ "$type": "app.bsky.richtext.facet#mention", "did": "did:plc:ewvi7nxzyoun6zhxrhs64oiz" }] }
+            ],
+            "reply": {
+                "root": { "uri": "at://did:plc:root/app.bsky.feed.post/1", "cid": "" },
+                "parent": { "uri": "at://did:plc:parent/app.bsky.feed.post/2", "cid": "" }
+            }
+        }));
+
+        assert_eq!(p.mentions, vec!["did:plc:ewvi7nxzyoun6zhxrhs64oiz"]);
+        assert_eq!(p.reply_parent.as_deref(), Some("did:plc:parent"));
+        assert_eq!(post(json!({ "text": "hi" })).reply_parent, None);
+    }
+
+    #[test]
+    fn validate_rejects_bad_regex() {
+        let filters = PostFilters {
+            require_regex: Some("(".to_string()),
+            ..Default::default()
+        };
+        assert!(filters.validate().is_err());
+        assert!(PostFilters::default().validate().is_ok());
+    }
+}
@@ -0,0 +1,193 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use cja::{color_eyre, Result};
+use hyper::client::connect::dns::Name;
+use reqwest::{
+    dns::{Addrs, Resolve, Resolving},
+    redirect, IntoUrl, Method, RequestBuilder, Url,
+};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Covers the whole request, so a server that trickles its response can't
+/// hold a job worker forever
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(20);
+const MAX_REDIRECTS: usize = 5;
+
+fn builder() -> reqwest::ClientBuilder {
+    reqwest::Client::builder()
+        .connect_timeout(CONNECT_TIMEOUT)
+        .timeout(REQUEST_TIMEOUT)
+}
+
+/// For services we chose ourselves, like Twilio and the Bluesky APIs
+pub fn client() -> reqwest::Client {
+    builder().build().expect("http client config is valid")
+}
+
+/// For URLs someone else gave us: webhook callbacks, chat webhooks, PDSes.
+/// Only https, and only to hosts that resolve to public addresses, so a
+/// subscriber can't point us at our own network. Redirects are held to the
+/// same rules.
+#[derive(Clone, Debug)]
+pub struct PublicClient(reqwest::Client);
+
+impl PublicClient {
+    pub fn new() -> Self {
+        let client = builder()
+            .https_only(true)
+            .dns_resolver(Arc::new(PublicResolver))
+            .redirect(redirect::Policy::custom(|attempt| {
+                if attempt.previous().len() >= MAX_REDIRECTS {
+                    attempt.error("too many redirects")
+                } else if let Err(err) = check_url(attempt.url()) {
+                    attempt.error(err.to_string())
+                } else {
+                    attempt.follow()
+                }
+            }))
+            .build()
+            .expect("http client config is valid");
+        Self(client)
+    }
+
+    pub fn get(&self, url: impl IntoUrl) -> Result<RequestBuilder> {
+        self.request(Method::GET, url)
+    }
+
+    pub fn post(&self, url: impl IntoUrl) -> Result<RequestBuilder> {
+        self.request(Method::POST, url)
+    }
+
+    /// The resolver never sees hosts that are already IP addresses, so those
+    /// are checked here before the request is built
+    pub fn request(&self, method: Method, url: impl IntoUrl) -> Result<RequestBuilder> {
+        let url = url.into_url()?;
+        check_url(&url)?;
+        Ok(self.0.request(method, url))
+    }
+}
+
+impl Default for PublicClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Rejects anything that isn't https, and hosts that are private IP literals.
+/// Names are checked when they're resolved.
+pub fn check_url(url: &Url) -> Result<()> {
+    if url.scheme() != "https" {
+        return Err(color_eyre::eyre::eyre!("{url} is not https"));
+    }
+    let Some(host) = url.host_str() else {
+        return Err(color_eyre::eyre::eyre!("{url} has no host"));
+    };
+    // IPv6 hosts come back in brackets
+    let Ok(ip) = host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+    else {
+        return Ok(());
+    };
+    if !is_public(ip) {
+        return Err(color_eyre::eyre::eyre!("{url} is not a public address"));
+    }
+    Ok(())
+}
+
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let host = name.as_str().to_string();
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), 0))
+                .await?
+                .filter(|addr| is_public(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(format!("{host} does not resolve to a public address").into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_v4(ip),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || ip.is_documentation()
+        // 0.0.0.0/8, "this network"
+        || a == 0
+        // 100.64.0.0/10, carrier-grade NAT
+        || (a == 100 && (64..128).contains(&b)))
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // fc00::/7, unique local
+        || (first & 0xfe00) == 0xfc00
+        // fe80::/10, link-local
+        || (first & 0xffc0) == 0xfe80)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_public_addresses_are_allowed() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{ip} should be blocked");
+        }
+        for ip in ["1.1.1.1", "93.184.216.34", "2606:4700::1111"] {
+            assert!(is_public(ip.parse().unwrap()), "{ip} should be allowed");
+        }
+    }
+
+    #[test]
+    fn checks_scheme_and_literal_hosts() {
+        let check = |url: &str| check_url(&Url::parse(url).unwrap()).is_ok();
+        assert!(check("https://example.com/hook"));
+        assert!(check("https://1.1.1.1/hook"));
+        assert!(!check("http://example.com/hook"));
+        assert!(!check("https://127.0.0.1/hook"));
+        assert!(!check("https://[::1]/hook"));
+        assert!(!check("https://169.254.169.254/latest/meta-data"));
+    }
+}
@@ -0,0 +1,287 @@
+use axum::{
+    extract::{OriginalUri, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse as _, Response},
+    Form,
+};
+use cja::Result;
+
+use crate::{resolve_handle, sms, AppState};
+
+const HELP_TEXT: &str = "bsky-webhooks: LIST to see who you follow, FOLLOW <handle> or UNFOLLOW <handle> to change it, STOP to pause all texts, START to resume.";
+
+#[derive(Debug, PartialEq, Eq)]
+enum Command {
+    Stop,
+    Start,
+    Help,
+    List,
+    Follow(String),
+    Unfollow(String),
+    Unknown,
+}
+
+impl Command {
+    fn parse(body: &str) -> Self {
+        let mut words = body.split_whitespace();
+        let Some(keyword) = words.next() else {
+            return Command::Unknown;
+        };
+        let handle = words
+            .next()
+            .map(|handle| handle.trim_start_matches('@').to_lowercase());
+
+        // Matches the opt-out and opt-in keywords Twilio and the carriers
+        // recognise, so we agree with them about who is opted out
+        match (keyword.to_uppercase().as_str(), handle) {
+            ("STOP" | "STOPALL" | "UNSUBSCRIBE" | "CANCEL" | "END" | "QUIT", _) => Command::Stop,
+            ("START" | "UNSTOP" | "YES", _) => Command::Start,
+            ("HELP" | "INFO", _) => Command::Help,
+            ("LIST", _) => Command::List,
+            ("FOLLOW", Some(handle)) => Command::Follow(handle),
+            ("UNFOLLOW", Some(handle)) => Command::Unfollow(handle),
+            _ => Command::Unknown,
+        }
+    }
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// A TwiML reply that texts `message` back to the sender
+fn twiml(message: &str) -> Response {
+    let body = format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?><Response><Message>{}</Message></Response>"#,
+        escape_xml(message)
+    );
+
+    ([(header::CONTENT_TYPE, "text/xml")], body).into_response()
+}
+
+fn param<'a>(params: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    params
+        .iter()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.as_str())
+}
+
+/// Twilio POSTs here for every text sent to our number
+pub async fn inbound_sms(
+    State(state): State<AppState>,
+    OriginalUri(uri): OriginalUri,
+    headers: HeaderMap,
+    Form(params): Form<Vec<(String, String)>>,
+) -> Result<Response, Response> {
+    let Some(twilio_config) = &state.twilio_config else {
+        return Err((StatusCode::NOT_FOUND, "SMS isn't enabled").into_response());
+    };
+
+    // Twilio signs the public URL it was configured with, which isn't what we
+    // see behind a proxy, so rebuild it from our own base URL
+    let url = format!("{}{}", state.base_url.trim_end_matches('/'), uri);
+    let signature = headers
+        .get(sms::SIGNATURE_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    if !sms::validate_signature(&twilio_config.auth_token, &url, &params, signature) {
+        return Err((StatusCode::FORBIDDEN, "Invalid Twilio signature").into_response());
+    }
+
+    let (Some(from), Some(body)) = (param(&params, "From"), param(&params, "Body")) else {
+        return Err((StatusCode::BAD_REQUEST, "Missing From or Body").into_response());
+    };
+
+    let reply = run_command(&state, from, Command::parse(body))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response())?;
+
+    Ok(twiml(&reply))
+}
+
+async fn run_command(state: &AppState, phone_number: &str, command: Command) -> Result<String> {
+    let reply = match command {
+        Command::Stop => {
+            sqlx::query!(
+                "UPDATE SmsHandleSubscriptions SET paused_at = NOW(), paused_by_stop = TRUE, updated_at = NOW()
+                WHERE phone_number = $1 AND paused_at IS NULL",
+                phone_number,
+            )
+            .execute(&state.db)
+            .await?;
+            sqlx::query!(
+                "UPDATE TermSubscriptions SET paused_at = NOW(), paused_by_stop = TRUE, updated_at = NOW()
+                WHERE phone_number = $1 AND paused_at IS NULL",
+                phone_number,
+            )
+            .execute(&state.db)
+            .await?;
+            "You won't get any more texts from bsky-webhooks. Reply START to resume.".to_string()
+        }
+        // Only what STOP paused, anything paused on the manage page stays that way
+        Command::Start => {
+            sqlx::query!(
+                "UPDATE SmsHandleSubscriptions SET paused_at = NULL, paused_by_stop = FALSE, updated_at = NOW()
+                WHERE phone_number = $1 AND paused_by_stop",
+                phone_number,
+            )
+            .execute(&state.db)
+            .await?;
+            sqlx::query!(
+                "UPDATE TermSubscriptions SET paused_at = NULL, paused_by_stop = FALSE, updated_at = NOW()
+                WHERE phone_number = $1 AND paused_by_stop",
+                phone_number,
+            )
+            .execute(&state.db)
+            .await?;
+            "Texts from bsky-webhooks are back on. Reply HELP for commands.".to_string()
+        }
+        Command::Help | Command::Unknown => HELP_TEXT.to_string(),
+        Command::List => {
+            let subscriptions = sqlx::query!(
+                "SELECT handle, paused_at IS NOT NULL AS paused FROM SmsHandleSubscriptions
+                WHERE phone_number = $1 AND confirmed_at IS NOT NULL
+                ORDER BY handle",
+                phone_number,
+            )
+            .fetch_all(&state.db)
+            .await?;
+
+            if subscriptions.is_empty() {
+                "You aren't following anyone. Reply FOLLOW <handle> to start.".to_string()
+            } else {
+                let handles: Vec<_> = subscriptions
+                    .iter()
+                    .map(|sub| match sub.paused {
+                        Some(true) => format!("@{} (paused)", sub.handle),
+                        _ => format!("@{}", sub.handle),
+                    })
+                    .collect();
+                format!("You follow {}", handles.join(", "))
+            }
+        }
+        Command::Follow(handle) => {
+            let did = match resolve_handle(state, &handle).await {
+                Ok(did) => did,
+                Err(_) => return Ok(format!("Couldn't find @{handle} on Bluesky")),
+            };
+
+            // Texting us from this number proves they own it, so there's no
+            // need for the verification code dance
+            sqlx::query!(
+                "INSERT INTO SmsHandleSubscriptions (phone_number, handle, did, confirmed_at)
+                VALUES ($1, $2, $3, NOW())
+                ON CONFLICT (phone_number, did) DO UPDATE SET
+                    handle = EXCLUDED.handle,
+                    confirmed_at = COALESCE(SmsHandleSubscriptions.confirmed_at, NOW()),
+                    verification_code = NULL,
+                    updated_at = NOW()",
+                phone_number,
+                &handle,
+                did.as_str(),
+            )
+            .execute(&state.db)
+            .await?;
+            format!("You'll now get texts when @{handle} posts")
+        }
+        Command::Unfollow(handle) => {
+            let deleted = sqlx::query!(
+                "DELETE FROM SmsHandleSubscriptions WHERE phone_number = $1 AND lower(handle) = $2",
+                phone_number,
+                &handle,
+            )
+            .execute(&state.db)
+            .await?;
+
+            if deleted.rows_affected() == 0 {
+                format!("You weren't following @{handle}")
+            } else {
+                format!("You won't get texts about @{handle} anymore")
+            }
+        }
+    };
+
+    Ok(reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::PgPool;
+
+    use super::*;
+    use crate::sms::fake::FakeTwilio;
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(Command::parse("STOP"), Command::Stop);
+        assert_eq!(Command::parse(" stop "), Command::Stop);
+        assert_eq!(Command::parse("Unsubscribe"), Command::Stop);
+        assert_eq!(Command::parse("start"), Command::Start);
+        assert_eq!(Command::parse("HELP"), Command::Help);
+        assert_eq!(Command::parse("list"), Command::List);
+        assert_eq!(
+            Command::parse("follow @Coreyja.com"),
+            Command::Follow("coreyja.com".to_string())
+        );
+        assert_eq!(
+            Command::parse("UNFOLLOW coreyja.com"),
+            Command::Unfollow("coreyja.com".to_string())
+        );
+        assert_eq!(Command::parse("FOLLOW"), Command::Unknown);
+        assert_eq!(Command::parse("hello there"), Command::Unknown);
+        assert_eq!(Command::parse(""), Command::Unknown);
+    }
+
+    #[test]
+    fn escapes_twiml_messages() {
+        assert_eq!(
+            escape_xml(r#"<b> & "it's""#),
+            "&lt;b&gt; &amp; &quot;it&apos;s&quot;"
+        );
+    }
+
+    #[sqlx::test]
+    async fn start_only_resumes_what_stop_paused(db: PgPool) {
+        let twilio = FakeTwilio::start().await.unwrap();
+        let state = crate::tests::test_state(db, &twilio).await;
+        let number = "+15555550100";
+        sqlx::query!(
+            "INSERT INTO SmsHandleSubscriptions (phone_number, handle, did, confirmed_at, paused_at)
+            VALUES ($1, 'alice.test', 'did:plc:alice', NOW(), NULL),
+                ($1, 'bob.test', 'did:plc:bob', NOW(), NOW())",
+            number,
+        )
+        .execute(&state.db)
+        .await
+        .unwrap();
+        sqlx::query!(
+            "INSERT INTO TermSubscriptions (phone_number, kind, term) VALUES ($1, 'keyword', 'rust')",
+            number,
+        )
+        .execute(&state.db)
+        .await
+        .unwrap();
+
+        run_command(&state, number, Command::Stop).await.unwrap();
+        run_command(&state, number, Command::Start).await.unwrap();
+
+        let paused = sqlx::query_scalar!(
+            "SELECT handle FROM SmsHandleSubscriptions WHERE paused_at IS NOT NULL"
+        )
+        .fetch_all(&state.db)
+        .await
+        .unwrap();
+        assert_eq!(paused, ["bob.test"]);
+        let terms_paused = sqlx::query_scalar!(
+            "SELECT COUNT(*) FROM TermSubscriptions WHERE paused_at IS NOT NULL"
+        )
+        .fetch_one(&state.db)
+        .await
+        .unwrap();
+        assert_eq!(terms_paused, Some(0));
+    }
+}
@@ -0,0 +1,689 @@
+use std::time::Duration;
+
+use cja::{app_state::AppState as _, color_eyre, Result};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+use crate::{
+    notifiers::{Channel, Message, PostMessage},
+    subscriptions, webhook, AppState,
+};
+
+/// After this many failed attempts a job is dead-lettered instead of retried
+const MAX_ATTEMPTS: i32 = 8;
+
+/// Rate limits don't count as attempts at first, but after this many they do,
+/// so a destination that never stops asking us to back off still runs out of
+/// retries
+const MAX_RATE_LIMITS: i32 = 20;
+
+/// A worker that dies mid-job leaves its lock behind, so locks older than this
+/// are considered abandoned and the job is up for grabs again
+const LOCK_TIMEOUT_SECS: f64 = 5.0 * 60.0;
+
+const IDLE_POLL: Duration = Duration::from_secs(1);
+
+/// One outbound notification to one subscriber. Everything needed to deliver it
+/// is captured at enqueue time, so a job still goes out even if the
+/// subscription changes underneath it. The exception is webhook secrets, which
+/// are looked up at send time so they're only ever stored in one place.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Delivery {
+    /// Sent through whichever [`Notifier`](crate::notifiers::Notifier) is
+    /// registered for `channel`
+    Notify {
+        channel: Channel,
+        to: String,
+        message: Box<Message>,
+        /// Set when the message is for an `SmsHandleSubscriptions` row, so we
+        /// can record when it last fired
+        #[serde(default)]
+        subscription_id: Option<uuid::Uuid>,
+        /// Set for texts about term matches, which aren't for any one
+        /// subscription
+        #[serde(default)]
+        term_matches: bool,
+    },
+    /// POSTed to the subscription's URL, signed with its secret. Dropped if
+    /// the subscription has been deleted by the time it runs.
+    Webhook {
+        subscription_id: uuid::Uuid,
+        body: serde_json::Value,
+    },
+}
+
+/// Where a subscription's notifications go, from its `channel` column and
+/// whichever address column that channel uses
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Destination {
+    pub channel: Channel,
+    pub to: String,
+}
+
+impl Destination {
+    /// `None` if the channel is unknown or the row is missing the column it
+    /// needs
+    pub fn from_row(
+        channel: &str,
+        phone_number: Option<String>,
+        email: Option<String>,
+        chat_webhook_url: Option<String>,
+    ) -> Option<Self> {
+        let channel = Channel::parse(channel)?;
+        let to = match channel {
+            Channel::Sms => phone_number,
+            Channel::Email => email,
+            Channel::Discord | Channel::Slack => chat_webhook_url,
+        }?;
+        Some(Self { channel, to })
+    }
+
+    pub fn deliver(&self, message: Message, subscription_id: Option<uuid::Uuid>) -> Delivery {
+        Delivery::Notify {
+            channel: self.channel,
+            to: self.to.clone(),
+            message: Box::new(message),
+            subscription_id,
+            term_matches: false,
+        }
+    }
+
+    /// Matches for any of the term subscriptions on this number
+    pub fn term_matches(&self, message: Message) -> Delivery {
+        Delivery::Notify {
+            channel: self.channel,
+            to: self.to.clone(),
+            message: Box::new(message),
+            subscription_id: None,
+            term_matches: true,
+        }
+    }
+
+    /// A short notification. Only emails show the subject.
+    pub fn notice(
+        &self,
+        subject: &str,
+        body: &str,
+        subscription_id: Option<uuid::Uuid>,
+    ) -> Delivery {
+        self.deliver(Message::notice(subject, body), subscription_id)
+    }
+
+    pub fn post(&self, post: PostMessage, subscription_id: Option<uuid::Uuid>) -> Delivery {
+        self.deliver(Message::Post(Box::new(post)), subscription_id)
+    }
+}
+
+impl Delivery {
+    fn name(&self) -> &'static str {
+        match self {
+            Delivery::Notify { channel, .. } => match channel {
+                Channel::Sms => "DeliverSms",
+                Channel::Email => "DeliverEmail",
+                Channel::Discord => "DeliverDiscord",
+                Channel::Slack => "DeliverSlack",
+            },
+            Delivery::Webhook { .. } => "DeliverWebhook",
+        }
+    }
+
+    /// `job_id` doubles as the webhook delivery id, it's stable across retries
+    async fn run(&self, state: &AppState, job_id: uuid::Uuid) -> Result<()> {
+        match self {
+            Delivery::Notify {
+                channel,
+                to,
+                message,
+                subscription_id,
+                term_matches,
+            } => {
+                // Jobs can sit in the queue for a while, so anything turned
+                // off since they were queued is dropped
+                let wanted = match subscription_id {
+                    Some(id) => subscription_is_active(state, *id).await?,
+                    None if *term_matches => terms_are_active(state, to).await?,
+                    None => true,
+                };
+                if !wanted {
+                    tracing::info!(?subscription_id, "subscription is off, dropping delivery");
+                    return Ok(());
+                }
+
+                let message = with_author_handle(state, message).await;
+                let sent = match subscription_id {
+                    Some(id) => {
+                        let unsubscribe_url = subscriptions::unsubscribe_url(state, *id);
+                        state
+                            .notifiers
+                            .send_to_subscriber(*channel, to, &message, &unsubscribe_url)
+                            .await
+                    }
+                    None => state.notifiers.send(*channel, to, &message).await,
+                };
+                if let Err(err) = sent {
+                    if let (Some(Gone), Some(id)) = (err.downcast_ref::<Gone>(), subscription_id) {
+                        pause_subscription(state, *id).await?;
+                    }
+                    return Err(err);
+                }
+                record_fired(state, *subscription_id).await
+            }
+            Delivery::Webhook {
+                subscription_id,
+                body,
+            } => {
+                let subscription = sqlx::query!(
+                    "SELECT url, secret FROM WebhookSubscriptions WHERE id = $1",
+                    subscription_id,
+                )
+                .fetch_optional(state.db())
+                .await?;
+                let Some(subscription) = subscription else {
+                    tracing::info!(%subscription_id, "webhook subscription is gone, dropping delivery");
+                    return Ok(());
+                };
+                webhook::send_webhook(
+                    &state.public_client,
+                    job_id,
+                    &subscription.url,
+                    &subscription.secret,
+                    body,
+                )
+                .await
+            }
+        }
+    }
+}
+
+/// Posts are queued without the author's handle when nobody subscribed to
+/// them, it's looked up here rather than holding up the firehose
+async fn with_author_handle(state: &AppState, message: &Message) -> Message {
+    let mut message = message.clone();
+    if let Message::Post(post) = &mut message {
+        if post.handle.is_empty() {
+            post.handle = state.appview.handle_or_did(&post.author).await;
+        }
+    }
+    message
+}
+
+/// Still there, confirmed and not paused
+async fn subscription_is_active(state: &AppState, subscription_id: uuid::Uuid) -> Result<bool> {
+    let active = sqlx::query_scalar!(
+        r#"SELECT (confirmed_at IS NOT NULL AND paused_at IS NULL) as "active!"
+        FROM SmsHandleSubscriptions WHERE id = $1"#,
+        subscription_id,
+    )
+    .fetch_optional(state.db())
+    .await?;
+    Ok(active.unwrap_or(false))
+}
+
+async fn terms_are_active(state: &AppState, phone_number: &str) -> Result<bool> {
+    let active = sqlx::query_scalar!(
+        r#"SELECT EXISTS (
+            SELECT 1 FROM TermSubscriptions WHERE phone_number = $1 AND paused_at IS NULL
+        ) as "active!""#,
+        phone_number,
+    )
+    .fetch_one(state.db())
+    .await?;
+    Ok(active)
+}
+
+async fn record_fired(state: &AppState, subscription_id: Option<uuid::Uuid>) -> Result<()> {
+    if let Some(subscription_id) = subscription_id {
+        sqlx::query!(
+            "UPDATE SmsHandleSubscriptions SET last_fired_at = NOW() WHERE id = $1",
+            subscription_id,
+        )
+        .execute(state.db())
+        .await?;
+    }
+    Ok(())
+}
+
+/// Nobody is going to see it anymore, so stop sending until the owner turns
+/// the subscription back on
+async fn pause_subscription(state: &AppState, subscription_id: uuid::Uuid) -> Result<()> {
+    tracing::warn!(%subscription_id, "destination is gone, pausing subscription");
+    sqlx::query!(
+        "UPDATE SmsHandleSubscriptions SET paused_at = NOW() WHERE id = $1 AND paused_at IS NULL",
+        subscription_id,
+    )
+    .execute(state.db())
+    .await?;
+    Ok(())
+}
+
+/// Returned by a delivery when the provider told us to back off. The job is
+/// put back for exactly that long, and up to [`MAX_RATE_LIMITS`] times it doesn't
+/// count as a failed attempt.
+#[derive(Debug)]
+pub struct RetryAfter(pub Duration);
+
+impl std::fmt::Display for RetryAfter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "rate limited, retry in {:?}", self.0)
+    }
+}
+
+impl std::error::Error for RetryAfter {}
+
+/// Returned by a delivery when the destination doesn't exist anymore, like a
+/// deleted Discord webhook. The job is dead-lettered straight away and its
+/// subscription paused.
+#[derive(Debug)]
+pub struct Gone;
+
+impl std::fmt::Display for Gone {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "destination no longer exists")
+    }
+}
+
+impl std::error::Error for Gone {}
+
+/// `context` is a short human readable note on why the job exists, it's only
+/// there to make the table easier to read when debugging
+pub async fn enqueue(
+    db: impl sqlx::PgExecutor<'_>,
+    delivery: &Delivery,
+    context: &str,
+) -> Result<()> {
+    sqlx::query!(
+        "INSERT INTO Jobs (job_id, name, payload, priority, context) VALUES ($1, $2, $3, $4, $5)",
+        uuid::Uuid::new_v4(),
+        delivery.name(),
+        serde_json::to_value(delivery)?,
+        0,
+        context,
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+/// How long to wait before retrying a job that has failed `attempts` times.
+/// Doubles from 30s and tops out at an hour.
+fn retry_delay(attempts: i32) -> Duration {
+    let exponent = attempts.saturating_sub(1).clamp(0, 7) as u32;
+    Duration::from_secs(30 * 2u64.pow(exponent)).min(Duration::from_secs(60 * 60))
+}
+
+struct ClaimedJob {
+    job_id: uuid::Uuid,
+    name: String,
+    payload: serde_json::Value,
+    attempts: i32,
+    rate_limits: i32,
+    context: String,
+}
+
+async fn claim(db: &PgPool, worker_id: &str) -> Result<Option<ClaimedJob>> {
+    let job = sqlx::query_as!(
+        ClaimedJob,
+        "UPDATE Jobs
+        SET locked_at = NOW(), locked_by = $1, attempts = attempts + 1
+        WHERE job_id = (
+            SELECT job_id FROM Jobs
+            WHERE dead_at IS NULL
+            AND run_at <= NOW()
+            AND (locked_at IS NULL OR locked_at < NOW() - make_interval(secs => $2))
+            ORDER BY priority DESC, run_at ASC
+            LIMIT 1
+            FOR UPDATE SKIP LOCKED
+        )
+        RETURNING job_id, name, payload, attempts, rate_limits, context",
+        worker_id,
+        LOCK_TIMEOUT_SECS,
+    )
+    .fetch_optional(db)
+    .await?;
+
+    Ok(job)
+}
+
+/// Record one delivery attempt in `DeliveryLogs`
+async fn log_attempt(
+    db: &PgPool,
+    job: &ClaimedJob,
+    status: &str,
+    error: Option<&str>,
+) -> Result<()> {
+    sqlx::query!(
+        "INSERT INTO DeliveryLogs (job_id, name, context, attempt, status, error) VALUES ($1, $2, $3, $4, $5, $6)",
+        job.job_id,
+        job.name,
+        job.context,
+        job.attempts,
+        status,
+        error,
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+async fn complete(db: &PgPool, job: &ClaimedJob) -> Result<()> {
+    sqlx::query!("DELETE FROM Jobs WHERE job_id = $1", job.job_id)
+        .execute(db)
+        .await?;
+    log_attempt(db, job, "delivered", None).await
+}
+
+async fn fail(db: &PgPool, job: &ClaimedJob, err: &color_eyre::Report) -> Result<()> {
+    let error = format!("{err:?}");
+
+    let rate_limit = err
+        .downcast_ref::<RetryAfter>()
+        .filter(|_| job.rate_limits < MAX_RATE_LIMITS);
+    if let Some(RetryAfter(delay)) = rate_limit {
+        tracing::info!(job_id = %job.job_id, name = %job.name, ?delay, "job rate limited, will retry");
+        sqlx::query!(
+            "UPDATE Jobs
+            SET run_at = NOW() + make_interval(secs => $2), attempts = attempts - 1, rate_limits = rate_limits + 1, locked_at = NULL, locked_by = NULL
+            WHERE job_id = $1",
+            job.job_id,
+            delay.as_secs_f64(),
+        )
+        .execute(db)
+        .await?;
+        return log_attempt(db, job, "rate_limited", Some(&error)).await;
+    }
+
+    let gone = err.downcast_ref::<Gone>().is_some();
+    if job.attempts >= MAX_ATTEMPTS || gone {
+        tracing::error!(job_id = %job.job_id, name = %job.name, attempts = job.attempts, %error, "job dead-lettered");
+        sqlx::query!(
+            "UPDATE Jobs SET dead_at = NOW(), last_error = $2, locked_at = NULL, locked_by = NULL
+            WHERE job_id = $1",
+            job.job_id,
+            error,
+        )
+        .execute(db)
+        .await?;
+        let status = if gone { "gone" } else { "dead" };
+        log_attempt(db, job, status, Some(&error)).await?;
+    } else {
+        let delay = retry_delay(job.attempts);
+        tracing::warn!(job_id = %job.job_id, name = %job.name, attempts = job.attempts, ?delay, %error, "job failed, will retry");
+        sqlx::query!(
+            "UPDATE Jobs
+            SET run_at = NOW() + make_interval(secs => $2), last_error = $3, locked_at = NULL, locked_by = NULL
+            WHERE job_id = $1",
+            job.job_id,
+            delay.as_secs_f64(),
+            error,
+        )
+        .execute(db)
+        .await?;
+        log_attempt(db, job, "failed", Some(&error)).await?;
+    }
+
+    Ok(())
+}
+
+/// Drop delivery logs, and the dead-lettered jobs they describe, once they're
+/// older than `max_age`
+pub async fn prune_delivery_logs(db: &PgPool, max_age: Duration) -> Result<()> {
+    let logs = sqlx::query!(
+        "DELETE FROM DeliveryLogs WHERE created_at < NOW() - make_interval(secs => $1)",
+        max_age.as_secs_f64(),
+    )
+    .execute(db)
+    .await?;
+    let jobs = sqlx::query!(
+        "DELETE FROM Jobs WHERE dead_at < NOW() - make_interval(secs => $1)",
+        max_age.as_secs_f64(),
+    )
+    .execute(db)
+    .await?;
+
+    tracing::info!(
+        logs = logs.rows_affected(),
+        dead_jobs = jobs.rows_affected(),
+        "pruned delivery logs"
+    );
+    Ok(())
+}
+
+async fn run_job(state: &AppState, job: &ClaimedJob) -> Result<()> {
+    let delivery: Delivery = serde_json::from_value(job.payload.clone())?;
+    delivery.run(state, job.job_id).await
+}
+
+/// Pull jobs off the queue until the process exits. Run several of these with
+/// distinct `worker_id`s to deliver in parallel, `SKIP LOCKED` keeps them from
+/// picking up the same job.
+pub async fn run_worker(state: AppState, worker_id: String) -> Result<()> {
+    tracing::info!(%worker_id, "starting job worker");
+
+    loop {
+        match run_next(&state, &worker_id).await {
+            Ok(true) => {}
+            Ok(false) => tokio::time::sleep(IDLE_POLL).await,
+            Err(err) => {
+                tracing::error!(?err, %worker_id, "failed to claim job");
+                tokio::time::sleep(IDLE_POLL).await;
+            }
+        }
+    }
+}
+
+/// Claim and run a single job. `false` if there was nothing due.
+pub async fn run_next(state: &AppState, worker_id: &str) -> Result<bool> {
+    let Some(job) = claim(state.db(), worker_id).await? else {
+        return Ok(false);
+    };
+
+    let result = match run_job(state, &job).await {
+        Ok(()) => complete(state.db(), &job).await,
+        Err(err) => fail(state.db(), &job, &err).await,
+    };
+    if let Err(err) = result {
+        tracing::error!(?err, job_id = %job.job_id, "failed to record job result");
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn retry_delay_doubles_and_caps() {
+        assert_eq!(retry_delay(1), Duration::from_secs(30));
+        assert_eq!(retry_delay(2), Duration::from_secs(60));
+        assert_eq!(retry_delay(3), Duration::from_secs(120));
+        assert_eq!(retry_delay(7), Duration::from_secs(30 * 64));
+        assert_eq!(retry_delay(8), Duration::from_secs(60 * 60));
+        assert_eq!(retry_delay(100), Duration::from_secs(60 * 60));
+    }
+
+    #[sqlx::test]
+    async fn turned_off_subscriptions_are_not_sent(db: PgPool) {
+        let twilio = crate::sms::fake::FakeTwilio::start().await.unwrap();
+        let state = crate::tests::test_state(db, &twilio).await;
+        let destination = Destination {
+            channel: Channel::Sms,
+            to: "+15555550100".to_string(),
+        };
+        let subscription_id = sqlx::query_scalar!(
+            "INSERT INTO SmsHandleSubscriptions (phone_number, handle, did, confirmed_at)
+            VALUES ('+15555550100', 'alice.test', 'did:plc:alice', NOW())
+            RETURNING id"
+        )
+        .fetch_one(&state.db)
+        .await
+        .unwrap();
+        sqlx::query!(
+            "INSERT INTO TermSubscriptions (phone_number, kind, term, paused_at)
+            VALUES ('+15555550100', 'keyword', 'rust', NOW())"
+        )
+        .execute(&state.db)
+        .await
+        .unwrap();
+
+        let queued = [
+            destination.notice("Subject", "post", Some(subscription_id)),
+            destination.term_matches(Message::notice("Subject", "term")),
+            destination.notice("Subject", "code", None),
+        ];
+        for delivery in &queued {
+            enqueue(&state.db, delivery, "test").await.unwrap();
+        }
+        // Paused after the job was queued, like a STOP would
+        sqlx::query!(
+            "UPDATE SmsHandleSubscriptions SET paused_at = NOW() WHERE id = $1",
+            subscription_id,
+        )
+        .execute(&state.db)
+        .await
+        .unwrap();
+
+        while run_next(&state, "test").await.unwrap() {}
+
+        let messages = twilio.messages();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].body, "code");
+        let remaining = sqlx::query_scalar!("SELECT COUNT(*) FROM Jobs")
+            .fetch_one(&state.db)
+            .await
+            .unwrap();
+        assert_eq!(remaining, Some(0));
+    }
+
+    #[sqlx::test]
+    async fn missing_handles_are_looked_up_when_sent(db: PgPool) {
+        let twilio = crate::sms::fake::FakeTwilio::start().await.unwrap();
+        let state = crate::tests::test_state(db, &twilio).await;
+        let post = PostMessage::new("", "did:plc:bob", "3kabc", &json!({ "text": "hi" }));
+        let delivery = Destination {
+            channel: Channel::Sms,
+            to: "+15555550100".to_string(),
+        }
+        .post(post, None);
+        enqueue(&state.db, &delivery, "test").await.unwrap();
+
+        while run_next(&state, "test").await.unwrap() {}
+
+        let messages = twilio.messages();
+        assert_eq!(messages.len(), 1);
+        assert!(
+            messages[0].body.starts_with("@bob.test: hi"),
+            "{}",
+            messages[0].body
+        );
+    }
+
+    #[sqlx::test]
+    async fn rate_limits_and_gone_destinations_stop_retrying(db: PgPool) {
+        let delivery = Destination {
+            channel: Channel::Discord,
+            to: "https://discord.com/api/webhooks/1/x".to_string(),
+        }
+        .notice("Subject", "hello", None);
+        enqueue(&db, &delivery, "test").await.unwrap();
+        let dead = || sqlx::query_scalar!("SELECT dead_at IS NOT NULL FROM Jobs").fetch_one(&db);
+
+        let rate_limited = color_eyre::Report::new(RetryAfter(Duration::ZERO));
+        for _ in 0..MAX_RATE_LIMITS {
+            let job = claim(&db, "test").await.unwrap().unwrap();
+            assert_eq!(job.attempts, 1);
+            fail(&db, &job, &rate_limited).await.unwrap();
+        }
+        // Out of free rate limits, so this one counts as an attempt
+        let job = claim(&db, "test").await.unwrap().unwrap();
+        fail(&db, &job, &rate_limited).await.unwrap();
+        let attempts = sqlx::query_scalar!("SELECT attempts FROM Jobs")
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        assert_eq!(attempts, 1);
+        assert_eq!(dead().await.unwrap(), Some(false));
+
+        fail(&db, &job, &color_eyre::Report::new(Gone))
+            .await
+            .unwrap();
+        assert_eq!(dead().await.unwrap(), Some(true));
+    }
+
+    #[test]
+    fn delivery_payload_is_tagged() {
+        let delivery = Destination {
+            channel: Channel::Sms,
+            to: "+15555550100".to_string(),
+        }
+        .notice("Subject", "hello", None);
+        let value = serde_json::to_value(&delivery).unwrap();
+        assert_eq!(value["kind"], "notify");
+        assert_eq!(value["channel"], "sms");
+        assert_eq!(value["message"]["type"], "notice");
+        assert_eq!(delivery.name(), "DeliverSms");
+
+        let round_trip: Delivery = serde_json::from_value(value).unwrap();
+        let Delivery::Notify { message, .. } = round_trip else {
+            panic!("expected a notification");
+        };
+        assert!(matches!(*message, Message::Notice { body, .. } if body == "hello"));
+    }
+
+    #[test]
+    fn webhook_payload_has_no_secret() {
+        let subscription_id = uuid::Uuid::new_v4();
+        let delivery = Delivery::Webhook {
+            subscription_id,
+            body: serde_json::json!({ "type": "post.created" }),
+        };
+        let value = serde_json::to_value(&delivery).unwrap();
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "kind": "webhook",
+                "subscription_id": subscription_id,
+                "body": { "type": "post.created" },
+            })
+        );
+    }
+
+    #[test]
+    fn destination_follows_channel() {
+        let phone = Some("+15555550100".to_string());
+        let email = Some("alice@example.com".to_string());
+        let destination = |channel, to: &str| {
+            Some(Destination {
+                channel,
+                to: to.to_string(),
+            })
+        };
+
+        assert_eq!(
+            Destination::from_row("email", phone.clone(), email.clone(), None),
+            destination(Channel::Email, "alice@example.com")
+        );
+        assert_eq!(
+            Destination::from_row("sms", phone, email, None),
+            destination(Channel::Sms, "+15555550100")
+        );
+        assert_eq!(Destination::from_row("email", None, None, None), None);
+        assert_eq!(
+            Destination::from_row(
+                "slack",
+                None,
+                None,
+                Some("https://hooks.slack.com/services/x".to_string())
+            ),
+            destination(Channel::Slack, "https://hooks.slack.com/services/x")
+        );
+        assert_eq!(
+            Destination::from_row("pager", Some("+15555550100".to_string()), None, None),
+            None
+        );
+    }
+}
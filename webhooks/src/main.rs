@@ -1,6 +1,6 @@
 use std::sync::Arc;
 
-use atproto::{consume_firehose, Handler};
+use atproto::{consume_firehose, consume_replay, Handler};
 use atrium_api::{
     agent::{store::MemorySessionStore, AtpAgent},
     types::string::Did,
@@ -13,6 +13,7 @@ use axum::{
     routing::{get, post},
     Form,
 };
+use chat::ChatProvider;
 use cja::{
     app_state::AppState as AS,
     color_eyre,
@@ -20,15 +21,45 @@ use cja::{
     setup::{setup_sentry, setup_tracing},
 };
 use maud::html;
+use notifiers::{Channel, Message, Notifiers};
 use serde::Deserialize;
 use sms::TwilioConfig;
 use sqlx::{postgres::PgPoolOptions, PgPool};
+use tower_cookies::Cookies;
 use tracing::info;
 
 mod sms;
 
+mod email;
+
+mod chat;
+
 mod atproto;
 
+mod webhook;
+
+mod http;
+
+mod jobs;
+
+mod crons;
+
+mod digest;
+
+mod inbound;
+
+mod sessions;
+
+mod subscriptions;
+
+mod oauth;
+
+mod filters;
+
+mod terms;
+
+mod notifiers;
+
 fn main() -> color_eyre::Result<()> {
     let _sentry_guard = setup_sentry();
 
@@ -39,7 +70,12 @@ fn main() -> color_eyre::Result<()> {
         .block_on(async { _main().await })
 }
 
-const HANDLES: &[&str] = &["coreyja.com"];
+const JOB_WORKERS: usize = 4;
+const VERIFICATION_CODE_TTL: std::time::Duration = std::time::Duration::from_secs(10 * 60);
+const MAX_VERIFICATION_ATTEMPTS: i32 = 5;
+/// How long a phone number has to wait between verification texts, however
+/// many handles they're being sent for
+const VERIFICATION_COOLDOWN: std::time::Duration = std::time::Duration::from_secs(60);
 async fn _main() -> cja::Result<()> {
     setup_tracing("bsky-webhooks")?;
 
@@ -49,12 +85,34 @@ async fn _main() -> cja::Result<()> {
 
     let handler = Handler::from_db(&app_state).await?;
 
+    // Setting FIREHOSE_REPLAY_FILE swaps the live relay for a capture from
+    // `firehose-cli --record`, which is handy for testing
+    let firehose = match std::env::var("FIREHOSE_REPLAY_FILE") {
+        Ok(path) => {
+            let speed = std::env::var("FIREHOSE_REPLAY_SPEED")
+                .ok()
+                .map(|speed| speed.parse())
+                .transpose()?;
+            tokio::spawn(consume_replay(handler.clone(), path, speed))
+        }
+        Err(_) => tokio::spawn(consume_firehose(handler.clone())),
+    };
+
     info!("Spawning Tasks");
     let mut futures = vec![
         tokio::spawn(run_server(routes(app_state.clone()))),
-        tokio::spawn(consume_firehose(handler.clone())),
-        tokio::spawn(update_handler(handler.clone())),
+        firehose,
+        tokio::spawn(crons::registry(&app_state, &handler).run(app_state.db().clone())),
     ];
+    // Deliveries are queued in the Jobs table so a slow or failing Twilio call
+    // doesn't hold up the firehose or the rest of the subscribers
+    let worker_prefix = uuid::Uuid::new_v4();
+    for i in 0..JOB_WORKERS {
+        futures.push(tokio::spawn(jobs::run_worker(
+            app_state.clone(),
+            format!("{worker_prefix}-{i}"),
+        )));
+    }
     info!("Tasks Spawned");
 
     futures::future::try_join_all(futures).await?;
@@ -67,7 +125,17 @@ pub struct AppState {
     pub db: sqlx::PgPool,
     pub cookie_key: cja::server::cookies::CookieKey,
     pub atproto_agent: Arc<AtpAgent<MemorySessionStore, ReqwestClient>>,
-    pub twilio_config: TwilioConfig,
+    /// Profile, follow and post lookups. Shares its client with Twilio so
+    /// outbound requests reuse connections.
+    pub appview: atproto::AppView,
+    /// For URLs subscribers give us, refuses to reach private addresses
+    pub public_client: http::PublicClient,
+    /// Only needed for inbound texts and phone number lookups, sending goes
+    /// through `notifiers`
+    pub twilio_config: Option<TwilioConfig>,
+    pub notifiers: Notifiers,
+    /// The public URL this app is served from, e.g. `https://bsky-webhooks.example.com`
+    pub base_url: String,
 }
 
 impl AppState {
@@ -79,11 +147,22 @@ impl AppState {
         let client = ReqwestClient::new("https://bsky.social");
         let agent = AtpAgent::new(client, MemorySessionStore::default());
 
+        let http_client = http::client();
+        let appview = atproto::AppView::new(http_client.clone());
+        let twilio_config = TwilioConfig::from_env(http_client, &appview);
+        let public_client = http::PublicClient::new();
+        let notifiers = Notifiers::from_env(twilio_config.as_ref(), &appview, &public_client)?;
+
         Ok(Self {
             db: pool,
             cookie_key,
             atproto_agent: Arc::new(agent),
-            twilio_config: TwilioConfig::from_env()?,
+            appview,
+            public_client,
+            twilio_config,
+            notifiers,
+            base_url: std::env::var("BASE_URL")
+                .unwrap_or_else(|_| "http://localhost:3000".to_string()),
         })
     }
 }
@@ -141,43 +220,549 @@ fn routes(app_state: AppState) -> axum::Router {
     axum::Router::new()
         .route("/", get(handler))
         .route("/sms_subscription", post(sms_subscription))
+        .route("/sms_subscription/confirm", post(confirm_sms_subscription))
+        .route("/email_subscription", post(email_subscription))
+        .route("/chat_subscription", post(chat_subscription))
+        .route("/webhook_subscription", post(webhook_subscription))
+        .route("/twilio/inbound", post(inbound::inbound_sms))
+        .route("/login", get(sessions::login_page).post(sessions::login))
+        .route("/login/confirm", post(sessions::confirm_login))
+        .route("/logout", post(sessions::logout))
+        .route("/subscriptions", get(subscriptions::list))
+        .route("/subscriptions/:id/pause", post(subscriptions::pause))
+        .route("/subscriptions/:id/resume", post(subscriptions::resume))
+        .route("/subscriptions/:id/delete", post(subscriptions::delete))
+        .route(
+            "/unsubscribe/:id",
+            get(subscriptions::unsubscribe_page).post(subscriptions::unsubscribe),
+        )
+        .route(
+            "/subscriptions/:id/filters",
+            get(subscriptions::edit_filters).post(subscriptions::save_filters),
+        )
+        .route(
+            "/webhook_subscriptions/:id/delete",
+            post(subscriptions::delete_webhook),
+        )
+        .route(
+            "/webhook_subscriptions/:id/filters",
+            get(subscriptions::edit_webhook_filters).post(subscriptions::save_webhook_filters),
+        )
+        .route("/term_subscriptions", post(subscriptions::create_term))
+        .route(
+            "/term_subscriptions/:id/delete",
+            post(subscriptions::delete_term),
+        )
+        .route("/oauth/client-metadata.json", get(oauth::client_metadata))
+        .route("/oauth/login", post(oauth::login))
+        .route("/oauth/callback", get(oauth::callback))
+        .layer(tower_cookies::CookieManagerLayer::new())
         .with_state(app_state)
 }
 
-async fn handler() -> impl axum::response::IntoResponse {
-    html! {
-        form action="/sms_subscription" method="post" {
-            input type="text" name="phone_number" placeholder="Phone Number" {}
-            input type="text" name="handle" placeholder="Handle" {}
-            input type="submit" value="Subscribe" {}
+async fn handler(State(state): State<AppState>, cookies: Cookies) -> Result<Response, Response> {
+    let session = sessions::current_session(&state, &cookies)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response())?;
+    let did = session.and_then(|session| session.did);
+
+    // Signed in users get their follows as suggestions for the handle fields
+    let follows = match &did {
+        Some(did) => state.appview.follows(did).await.unwrap_or_else(|err| {
+            tracing::warn!(?err, %did, "failed to fetch follows");
+            vec![]
+        }),
+        None => vec![],
+    };
+
+    Ok(html! {
+        @if did.is_none() {
+            a href="/login" { "Sign in with Bluesky" }
+        }
+        datalist id="follows" {
+            @for handle in &follows {
+                option value=(handle) {}
+            }
         }
+        @if state.notifiers.is_configured(Channel::Sms) {
+            form action="/sms_subscription" method="post" {
+                input type="text" name="phone_number" placeholder="Phone Number" {}
+                input type="text" name="handle" placeholder="Handle" list="follows" {}
+                label {
+                    input type="checkbox" name="notify_deletes" value="true" {}
+                    "Also tell me when they delete a post"
+                }
+                label {
+                    input type="checkbox" name="notify_mentions" value="true" {}
+                    "Tell me when someone mentions them"
+                }
+                label {
+                    input type="checkbox" name="notify_replies" value="true" {}
+                    "Tell me when someone replies to them"
+                }
+                label {
+                    input type="checkbox" name="digest" value="true" {}
+                    "Send me a daily digest instead of every post"
+                }
+                input type="submit" value="Subscribe" {}
+            }
+        }
+        @if state.notifiers.is_configured(Channel::Email) && did.is_none() {
+            p { "Sign in with Bluesky to subscribe by email." }
+        }
+        @if state.notifiers.is_configured(Channel::Email) && did.is_some() {
+            form action="/email_subscription" method="post" {
+                input type="email" name="email" placeholder="Email" {}
+                input type="text" name="handle" placeholder="Handle" list="follows" {}
+                label {
+                    input type="checkbox" name="notify_deletes" value="true" {}
+                    "Also tell me when they delete a post"
+                }
+                label {
+                    input type="checkbox" name="notify_mentions" value="true" {}
+                    "Tell me when someone mentions them"
+                }
+                label {
+                    input type="checkbox" name="notify_replies" value="true" {}
+                    "Tell me when someone replies to them"
+                }
+                label {
+                    input type="checkbox" name="digest" value="true" {}
+                    "Send me a daily digest instead of every post"
+                }
+                input type="submit" value="Subscribe by Email" {}
+            }
+        }
+        @if did.is_none() {
+            p { "Sign in with Bluesky to post to Discord or Slack, or to add a webhook." }
+        } @else {
+            form action="/chat_subscription" method="post" {
+                input type="url" name="url" placeholder="Discord or Slack webhook URL" {}
+                input type="text" name="handle" placeholder="Handle" list="follows" {}
+                label {
+                    input type="checkbox" name="notify_deletes" value="true" {}
+                    "Also post when they delete a post"
+                }
+                label {
+                    input type="checkbox" name="notify_mentions" value="true" {}
+                    "Post when someone mentions them"
+                }
+                label {
+                    input type="checkbox" name="notify_replies" value="true" {}
+                    "Post when someone replies to them"
+                }
+                label {
+                    input type="checkbox" name="digest" value="true" {}
+                    "Post a daily digest instead of every post"
+                }
+                input type="submit" value="Post to Chat" {}
+            }
+        }
+        @if did.is_some() {
+            form action="/webhook_subscription" method="post" {
+                input type="url" name="url" placeholder="https://example.com/webhook" {}
+                input type="text" name="handle" placeholder="Handle" list="follows" {}
+                input type="submit" value="Add Webhook" {}
+            }
+        }
+        a href="/subscriptions" { "Manage your subscriptions" }
+    }
+    .into_response())
+}
+
+/// The DID of whoever is signed in with Bluesky, if anyone is. Subscriptions
+/// they create are owned by it.
+async fn owner_did(state: &AppState, cookies: &Cookies) -> Result<Option<String>, Response> {
+    let session = sessions::current_session(state, cookies)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response())?;
+
+    Ok(session.and_then(|session| session.did))
+}
+
+/// Every code we send costs money or sender reputation, so one number or
+/// address can't be used to rack up texts or emails by resubmitting a form.
+/// `to` is a phone number or an email address.
+async fn verification_cooldown(state: &AppState, to: &str, message: &str) -> Result<(), Response> {
+    let recently_sent = sqlx::query_scalar!(
+        r#"SELECT EXISTS (
+            SELECT 1 FROM SmsHandleSubscriptions
+            WHERE (phone_number = $1 OR email = $1)
+            AND verification_sent_at > NOW() - make_interval(secs => $2)
+        ) AS "exists!""#,
+        to,
+        VERIFICATION_COOLDOWN.as_secs_f64(),
+    )
+    .fetch_one(&state.db)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response())?;
+    if recently_sent {
+        return Err((StatusCode::TOO_MANY_REQUESTS, message.to_string()).into_response());
     }
+    Ok(())
 }
 
 #[derive(Debug, Deserialize, Clone)]
 struct SmsSubscriptionForm {
     phone_number: String,
     handle: String,
+    #[serde(default)]
+    notify_deletes: bool,
+    #[serde(default)]
+    notify_mentions: bool,
+    #[serde(default)]
+    notify_replies: bool,
+    #[serde(default)]
+    digest: bool,
 }
 
 async fn sms_subscription(
     State(state): State<AppState>,
+    cookies: Cookies,
     Form(form): Form<SmsSubscriptionForm>,
 ) -> Result<Response, Response> {
+    if !state.notifiers.is_configured(Channel::Sms) {
+        return Err((StatusCode::NOT_FOUND, "SMS subscriptions aren't enabled").into_response());
+    }
+    let owner_did = owner_did(&state, &cookies).await?;
+
     let did = resolve_handle(&state, &form.handle)
         .await
         .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response())?;
 
     let verified_phone_number =
-        sms::find_verified_phone_numbers(&state.twilio_config, &form.phone_number)
+        sms::lookup_phone_number(state.twilio_config.as_ref(), &form.phone_number)
             .await
             .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response())?;
+    if !verified_phone_number.valid {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "That doesn't look like a valid phone number",
+        )
+            .into_response());
+    }
 
-    sqlx::query!(
-        "INSERT INTO SmsHandleSubscriptions (phone_number, handle, did) VALUES ($1, $2, $3)",
+    verification_cooldown(
+        &state,
+        &verified_phone_number.phone_number,
+        "We just sent a code to that number, please wait a minute and try again",
+    )
+    .await?;
+
+    let code = sms::generate_verification_code();
+
+    let subscription = sqlx::query!(
+        "INSERT INTO SmsHandleSubscriptions (phone_number, handle, did, notify_deletes, digest, verification_code, verification_expires_at, verification_sent_at, owner_did, notify_mentions, notify_replies)
+        VALUES ($1, $2, $3, $4, $5, $6, NOW() + make_interval(secs => $7), NOW(), $8, $9, $10)
+        ON CONFLICT (phone_number, did) DO UPDATE SET
+            handle = EXCLUDED.handle,
+            owner_did = COALESCE(EXCLUDED.owner_did, SmsHandleSubscriptions.owner_did),
+            notify_deletes = EXCLUDED.notify_deletes,
+            notify_mentions = EXCLUDED.notify_mentions,
+            notify_replies = EXCLUDED.notify_replies,
+            digest = EXCLUDED.digest,
+            verification_code = EXCLUDED.verification_code,
+            verification_expires_at = EXCLUDED.verification_expires_at,
+            verification_sent_at = EXCLUDED.verification_sent_at,
+            verification_attempts = 0,
+            updated_at = NOW()
+        WHERE SmsHandleSubscriptions.confirmed_at IS NULL
+        RETURNING id",
         verified_phone_number.phone_number,
         &form.handle,
         did.as_str(),
+        form.notify_deletes,
+        form.digest,
+        &code,
+        VERIFICATION_CODE_TTL.as_secs_f64(),
+        owner_did,
+        form.notify_mentions,
+        form.notify_replies,
+    )
+    .fetch_optional(&state.db)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response())?;
+
+    // A pending signup for the same handle just gets a fresh code, a confirmed
+    // one is left alone
+    let Some(subscription) = subscription else {
+        return Ok(html! {
+            p { (verified_phone_number.national_format) " is already subscribed to @" (form.handle) "." }
+            a href="/subscriptions" { "Manage your subscriptions" }
+        }
+        .into_response());
+    };
+
+    // Sent inline rather than through the job queue, the person is sitting on
+    // the confirmation form waiting for it
+    let message = Message::notice(
+        "Your bsky-webhooks verification code",
+        format!("Your bsky-webhooks verification code is {code}"),
+    );
+    state
+        .notifiers
+        .send(Channel::Sms, &verified_phone_number.phone_number, &message)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response())?;
+
+    Ok(html! {
+        p { "We sent a code to " (verified_phone_number.national_format) ". Enter it below to confirm your subscription to @" (form.handle) "." }
+        form action="/sms_subscription/confirm" method="post" {
+            input type="hidden" name="subscription_id" value=(subscription.id) {}
+            input type="text" name="code" inputmode="numeric" autocomplete="one-time-code" placeholder="123456" {}
+            input type="submit" value="Confirm" {}
+        }
+    }
+    .into_response())
+}
+
+#[derive(Debug, Deserialize, Clone)]
+struct EmailSubscriptionForm {
+    email: String,
+    handle: String,
+    #[serde(default)]
+    notify_deletes: bool,
+    #[serde(default)]
+    notify_mentions: bool,
+    #[serde(default)]
+    notify_replies: bool,
+    #[serde(default)]
+    digest: bool,
+}
+
+async fn email_subscription(
+    State(state): State<AppState>,
+    cookies: Cookies,
+    Form(form): Form<EmailSubscriptionForm>,
+) -> Result<Response, Response> {
+    if !state.notifiers.is_configured(Channel::Email) {
+        return Err((StatusCode::NOT_FOUND, "Email subscriptions aren't enabled").into_response());
+    }
+    let Some(address) = email::parse_address(&form.email) else {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "That doesn't look like an email address",
+        )
+            .into_response());
+    };
+    let address = address.to_string();
+
+    // Without an owner there'd be nothing to log in as to manage it later
+    let Some(owner_did) = owner_did(&state, &cookies).await? else {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "Sign in with Bluesky to subscribe by email",
+        )
+            .into_response());
+    };
+
+    let did = resolve_handle(&state, &form.handle)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response())?;
+
+    verification_cooldown(
+        &state,
+        &address,
+        "We just emailed a code to that address, please wait a minute and try again",
+    )
+    .await?;
+
+    let code = sms::generate_verification_code();
+
+    let subscription = sqlx::query!(
+        "INSERT INTO SmsHandleSubscriptions (channel, email, handle, did, notify_deletes, digest, verification_code, verification_expires_at, verification_sent_at, owner_did, notify_mentions, notify_replies)
+        VALUES ('email', $1, $2, $3, $4, $5, $6, NOW() + make_interval(secs => $7), NOW(), $8, $9, $10)
+        ON CONFLICT (email, did) DO UPDATE SET
+            handle = EXCLUDED.handle,
+            owner_did = COALESCE(EXCLUDED.owner_did, SmsHandleSubscriptions.owner_did),
+            notify_deletes = EXCLUDED.notify_deletes,
+            notify_mentions = EXCLUDED.notify_mentions,
+            notify_replies = EXCLUDED.notify_replies,
+            digest = EXCLUDED.digest,
+            verification_code = EXCLUDED.verification_code,
+            verification_expires_at = EXCLUDED.verification_expires_at,
+            verification_sent_at = EXCLUDED.verification_sent_at,
+            verification_attempts = 0,
+            updated_at = NOW()
+        WHERE SmsHandleSubscriptions.confirmed_at IS NULL
+        RETURNING id",
+        &address,
+        &form.handle,
+        did.as_str(),
+        form.notify_deletes,
+        form.digest,
+        &code,
+        VERIFICATION_CODE_TTL.as_secs_f64(),
+        owner_did,
+        form.notify_mentions,
+        form.notify_replies,
+    )
+    .fetch_optional(&state.db)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response())?;
+
+    let Some(subscription) = subscription else {
+        return Ok(html! {
+            p { (address) " is already subscribed to @" (form.handle) "." }
+        }
+        .into_response());
+    };
+
+    let message = Message::notice(
+        "Confirm your bsky-webhooks subscription",
+        format!("Your bsky-webhooks verification code is {code}"),
+    );
+    state
+        .notifiers
+        .send(Channel::Email, &address, &message)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response())?;
+
+    Ok(html! {
+        p { "We emailed a code to " (address) ". Enter it below to confirm your subscription to @" (form.handle) "." }
+        form action="/sms_subscription/confirm" method="post" {
+            input type="hidden" name="subscription_id" value=(subscription.id) {}
+            input type="text" name="code" inputmode="numeric" autocomplete="one-time-code" placeholder="123456" {}
+            input type="submit" value="Confirm" {}
+        }
+    }
+    .into_response())
+}
+
+#[derive(Debug, Deserialize, Clone)]
+struct ChatSubscriptionForm {
+    url: String,
+    handle: String,
+    #[serde(default)]
+    notify_deletes: bool,
+    #[serde(default)]
+    notify_mentions: bool,
+    #[serde(default)]
+    notify_replies: bool,
+    #[serde(default)]
+    digest: bool,
+}
+
+async fn chat_subscription(
+    State(state): State<AppState>,
+    cookies: Cookies,
+    Form(form): Form<ChatSubscriptionForm>,
+) -> Result<Response, Response> {
+    let Some(owner_did) = owner_did(&state, &cookies).await? else {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "Sign in with Bluesky to post to Discord or Slack",
+        )
+            .into_response());
+    };
+
+    let url = reqwest::Url::parse(&form.url)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()).into_response())?;
+    let Some(provider) = ChatProvider::from_url(&url) else {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "That isn't a Discord or Slack incoming webhook URL",
+        )
+            .into_response());
+    };
+
+    let did = resolve_handle(&state, &form.handle)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response())?;
+
+    // Anyone with the URL can post to the channel anyway, so a message that
+    // goes through is all the confirmation we need
+    let hello = Message::notice(
+        "Hello",
+        format!("bsky-webhooks will post here when @{} posts", form.handle),
+    );
+    state
+        .notifiers
+        .send(provider.channel(), url.as_str(), &hello)
+        .await
+        .map_err(|e| {
+            (
+                StatusCode::BAD_REQUEST,
+                format!("Couldn't post to that webhook: {e}"),
+            )
+                .into_response()
+        })?;
+
+    sqlx::query!(
+        "INSERT INTO SmsHandleSubscriptions (channel, chat_webhook_url, handle, did, notify_deletes, digest, owner_did, notify_mentions, notify_replies, confirmed_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, NOW())
+        ON CONFLICT (chat_webhook_url, did) DO UPDATE SET
+            handle = EXCLUDED.handle,
+            owner_did = COALESCE(EXCLUDED.owner_did, SmsHandleSubscriptions.owner_did),
+            notify_deletes = EXCLUDED.notify_deletes,
+            notify_mentions = EXCLUDED.notify_mentions,
+            notify_replies = EXCLUDED.notify_replies,
+            digest = EXCLUDED.digest,
+            updated_at = NOW()",
+        provider.channel().as_str(),
+        url.as_str(),
+        &form.handle,
+        did.as_str(),
+        form.notify_deletes,
+        form.digest,
+        owner_did,
+        form.notify_mentions,
+        form.notify_replies,
+    )
+    .execute(&state.db)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response())?;
+
+    Ok(html! {
+        p { "Posts from @" (form.handle) " will show up in " (provider.display_name()) "." }
+        a href="/subscriptions" { "Manage your subscriptions" }
+    }
+    .into_response())
+}
+
+#[derive(Debug, Deserialize, Clone)]
+struct ConfirmSmsSubscriptionForm {
+    subscription_id: uuid::Uuid,
+    code: String,
+}
+
+async fn confirm_sms_subscription(
+    State(state): State<AppState>,
+    Form(form): Form<ConfirmSmsSubscriptionForm>,
+) -> Result<Response, Response> {
+    // Count the attempt before checking it, so guesses are capped even if
+    // several arrive at once
+    let pending = sqlx::query!(
+        "UPDATE SmsHandleSubscriptions
+        SET verification_attempts = verification_attempts + 1, updated_at = NOW()
+        WHERE id = $1 AND confirmed_at IS NULL
+        RETURNING verification_code, verification_expires_at < NOW() AS expired, verification_attempts",
+        form.subscription_id,
+    )
+    .fetch_optional(&state.db)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response())?;
+
+    let Some(pending) = pending else {
+        return Err((StatusCode::NOT_FOUND, "No pending subscription found").into_response());
+    };
+    if pending.expired.unwrap_or(true) || pending.verification_attempts > MAX_VERIFICATION_ATTEMPTS
+    {
+        return Err((
+            StatusCode::GONE,
+            "This code has expired, please subscribe again",
+        )
+            .into_response());
+    }
+    if !sms::verification_code_matches(pending.verification_code.as_deref(), &form.code) {
+        return Err((StatusCode::BAD_REQUEST, "That code didn't match").into_response());
+    }
+
+    sqlx::query!(
+        "UPDATE SmsHandleSubscriptions
+        SET confirmed_at = NOW(), verification_code = NULL, updated_at = NOW()
+        WHERE id = $1",
+        form.subscription_id,
     )
     .execute(&state.db)
     .await
@@ -186,6 +771,66 @@ async fn sms_subscription(
     Ok("Success".into_response())
 }
 
+#[derive(Debug, Deserialize, Clone)]
+struct WebhookSubscriptionForm {
+    url: String,
+    handle: String,
+}
+
+async fn webhook_subscription(
+    State(state): State<AppState>,
+    cookies: Cookies,
+    Form(form): Form<WebhookSubscriptionForm>,
+) -> Result<Response, Response> {
+    // Webhooks are only managed by their owner, an anonymous one could never
+    // be turned off
+    let Some(owner_did) = owner_did(&state, &cookies).await? else {
+        return Err((
+            StatusCode::UNAUTHORIZED,
+            "Sign in with Bluesky to add a webhook",
+        )
+            .into_response());
+    };
+
+    let url = reqwest::Url::parse(&form.url)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()).into_response())?;
+    http::check_url(&url).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()).into_response())?;
+
+    let did = resolve_handle(&state, &form.handle)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response())?;
+
+    let secret = webhook::generate_secret();
+
+    sqlx::query!(
+        "INSERT INTO WebhookSubscriptions (url, secret, handle, did, owner_did) VALUES ($1, $2, $3, $4, $5)",
+        url.as_str(),
+        &secret,
+        &form.handle,
+        did.as_str(),
+        owner_did,
+    )
+    .execute(&state.db)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response())?;
+
+    // The secret is only shown once, it's what subscribers verify the
+    // signature header against
+    Ok(html! {
+        p { "Webhook added for @" (form.handle) }
+        p { "Signing secret: " code { (secret) } }
+        p {
+            "Each request carries a " code { (webhook::SIGNATURE_HEADER) }
+            " header with the HMAC-SHA256 of the body. Save this secret now, it won't be shown again."
+        }
+        p {
+            "Retries of the same event carry the same " code { (webhook::DELIVERY_HEADER) }
+            ", so you can skip ones you've already handled."
+        }
+    }
+    .into_response())
+}
+
 async fn resolve_handle(state: &AppState, handle: &str) -> cja::Result<Did> {
     let resp = state
         .atproto_agent
@@ -206,12 +851,310 @@ async fn resolve_handle(state: &AppState, handle: &str) -> cja::Result<Did> {
     Ok(did)
 }
 
-pub async fn update_handler(handler: Handler) -> cja::Result<()> {
-    loop {
-        handler.update_from_db().await?;
+#[cfg(test)]
+mod tests {
+    use bsky_firehose::{NewPost, PostEvent};
+    use serde_json::json;
+    use sms::fake::FakeTwilio;
+
+    use super::*;
 
-        tokio::time::sleep(std::time::Duration::from_secs(10)).await;
+    const ALICE: &str = "did:plc:alice";
+    const PHONE_NUMBER: &str = "+15555550100";
+
+    /// Serve a router on a random local port, returning its base URL
+    async fn spawn(router: axum::Router) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let _ = axum::serve(listener, router).await;
+        });
+        base_url
     }
 
-    Ok(())
+    /// Stands in for bsky.social and the AppView. Every handle resolves to
+    /// Alice, and every profile is Bob's.
+    async fn fake_pds() -> String {
+        spawn(
+            axum::Router::new()
+                .route(
+                    "/xrpc/com.atproto.identity.resolveHandle",
+                    get(|| async { axum::Json(json!({ "did": ALICE })) }),
+                )
+                .route(
+                    "/xrpc/app.bsky.actor.getProfile",
+                    get(|| async { axum::Json(json!({ "handle": "bob.test" })) }),
+                ),
+        )
+        .await
+    }
+
+    pub(crate) async fn test_state(db: PgPool, twilio: &FakeTwilio) -> AppState {
+        let http_client = reqwest::Client::new();
+        let pds = fake_pds().await;
+        let appview = atproto::AppView::with_base_url(http_client.clone(), &pds);
+        let twilio_config = twilio.config(http_client, appview.clone());
+        let mut notifiers = Notifiers::default();
+        notifiers.register(Channel::Sms, twilio_config.clone());
+        let agent = AtpAgent::new(ReqwestClient::new(&pds), MemorySessionStore::default());
+
+        AppState {
+            db,
+            cookie_key: cja::server::cookies::CookieKey::from_env_or_generate().unwrap(),
+            atproto_agent: Arc::new(agent),
+            appview,
+            public_client: http::PublicClient::new(),
+            twilio_config: Some(twilio_config),
+            notifiers,
+            base_url: "http://localhost:3000".to_string(),
+        }
+    }
+
+    async fn run_jobs(state: &AppState) {
+        while jobs::run_next(state, "test").await.unwrap() {}
+    }
+
+    #[sqlx::test]
+    async fn subscribes_and_texts_new_posts(db: PgPool) {
+        let twilio = FakeTwilio::start().await.unwrap();
+        twilio.add_number("5555550100", PHONE_NUMBER, "(555) 555-0100");
+        let state = test_state(db, &twilio).await;
+        let app = spawn(routes(state.clone())).await;
+        let client = reqwest::Client::new();
+
+        let resp = client
+            .post(format!("{app}/sms_subscription"))
+            .form(&[("phone_number", "5555550100"), ("handle", "alice.test")])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+        let messages = twilio.messages();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].to, PHONE_NUMBER);
+        let code = messages[0].body.rsplit(' ').next().unwrap().to_string();
+
+        // Asking again straight away doesn't send another text
+        let resp = client
+            .post(format!("{app}/sms_subscription"))
+            .form(&[("phone_number", "5555550100"), ("handle", "alice.test")])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(twilio.messages().len(), 1);
+
+        let subscription_id = sqlx::query_scalar!(
+            "SELECT id FROM SmsHandleSubscriptions WHERE phone_number = $1",
+            PHONE_NUMBER,
+        )
+        .fetch_one(&state.db)
+        .await
+        .unwrap();
+        let confirm = |code: String| {
+            client
+                .post(format!("{app}/sms_subscription/confirm"))
+                .form(&[
+                    ("subscription_id", subscription_id.to_string()),
+                    ("code", code),
+                ])
+                .send()
+        };
+        assert_eq!(
+            confirm("000000x".to_string()).await.unwrap().status(),
+            reqwest::StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            confirm(code).await.unwrap().status(),
+            reqwest::StatusCode::OK
+        );
+
+        let handler = Handler::from_db(&state).await.unwrap();
+        let did: Did = ALICE.parse().unwrap();
+        let post = NewPost {
+            record: serde_json::from_value(json!({
+                "$type": "app.bsky.feed.post",
+                "text": "hello world",
+                "createdAt": "2024-11-20T12:00:00.000Z"
+            }))
+            .unwrap(),
+            author: did.clone(),
+            path: "app.bsky.feed.post/3kabc".to_string(),
+            cid: None,
+            prev: None,
+        };
+        let watchers = handler.watchers(&did).await;
+        handler
+            .handle_post_events(&watchers, vec![PostEvent::Created(post)])
+            .await
+            .unwrap();
+        run_jobs(&state).await;
+
+        let messages = twilio.messages();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[1].to, PHONE_NUMBER);
+        assert_eq!(
+            messages[1].body,
+            "@alice.test: hello world\nhttps://bsky.app/profile/alice.test/post/3kabc"
+        );
+    }
+
+    #[sqlx::test]
+    async fn reply_that_mentions_is_sent_once(db: PgPool) {
+        let twilio = FakeTwilio::start().await.unwrap();
+        let state = test_state(db, &twilio).await;
+        sqlx::query!(
+            "INSERT INTO SmsHandleSubscriptions (phone_number, handle, did, notify_mentions, notify_replies, confirmed_at)
+            VALUES ($1, 'alice.test', $2, TRUE, TRUE, NOW())",
+            PHONE_NUMBER,
+            ALICE,
+        )
+        .execute(&state.db)
+        .await
+        .unwrap();
+        // Filters apply to replies and mentions too
+        sqlx::query!(
+            r#"INSERT INTO SmsHandleSubscriptions (phone_number, handle, did, notify_mentions, notify_replies, confirmed_at, filters)
+            VALUES ('+15555550101', 'alice.test', $1, TRUE, TRUE, NOW(), '{"exclude_replies": true}')"#,
+            ALICE,
+        )
+        .execute(&state.db)
+        .await
+        .unwrap();
+
+        let handler = Handler::from_db(&state).await.unwrap();
+        let bob: Did = "did:plc:bob".parse().unwrap();
+        let reply = NewPost {
+            record: serde_json::from_value(json!({
+                "$type": "app.bsky.feed.post",
+                "text": "@alice.test agreed",
+                "createdAt": "2024-11-20T12:00:00.000Z",
+                "reply": {
+                    "root": { "uri": format!("at://{ALICE}/app.bsky.feed.post/3kabc"), "cid": "bafyreie5737gdxlw5i64vzichcalba3z2v5n6icifvx5xytvske7mr3hpm" },
+                    "parent": { "uri": format!("at://{ALICE}/app.bsky.feed.post/3kabc"), "cid": "bafyreie5737gdxlw5i64vzichcalba3z2v5n6icifvx5xytvske7mr3hpm" }
+                },
+                "facets": [{
+                    "index": { "byteStart": 0, "byteEnd": 11 },
+                    "features": [{ "$type": "app.bsky.richtext.facet#mention", "did": ALICE }]
+                }]
+            }))
+            .unwrap(),
+            author: bob.clone(),
+            path: "app.bsky.feed.post/3kdef".to_string(),
+            cid: None,
+            prev: None,
+        };
+        let watchers = handler.watchers(&bob).await;
+        handler
+            .handle_post_events(&watchers, vec![PostEvent::Created(reply)])
+            .await
+            .unwrap();
+        run_jobs(&state).await;
+
+        let messages = twilio.messages();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].to, PHONE_NUMBER);
+        assert_eq!(
+            messages[0].body,
+            "New reply to @alice.test\n@bob.test: @alice.test agreed\nhttps://bsky.app/profile/bob.test/post/3kdef"
+        );
+    }
+
+    #[sqlx::test]
+    async fn login_only_texts_subscribed_numbers(db: PgPool) {
+        let twilio = FakeTwilio::start().await.unwrap();
+        twilio.add_number("5555550100", PHONE_NUMBER, "(555) 555-0100");
+        let state = test_state(db, &twilio).await;
+        let app = spawn(routes(state)).await;
+
+        let resp = reqwest::Client::new()
+            .post(format!("{app}/login"))
+            .form(&[("phone_number", "5555550100")])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+        assert!(twilio.messages().is_empty());
+    }
+
+    #[sqlx::test]
+    async fn login_codes_are_rate_limited(db: PgPool) {
+        let twilio = FakeTwilio::start().await.unwrap();
+        twilio.add_number("5555550100", PHONE_NUMBER, "(555) 555-0100");
+        let state = test_state(db, &twilio).await;
+        sqlx::query!(
+            "INSERT INTO SmsHandleSubscriptions (phone_number, handle, did, confirmed_at)
+            VALUES ($1, 'alice.test', $2, NOW())",
+            PHONE_NUMBER,
+            ALICE,
+        )
+        .execute(&state.db)
+        .await
+        .unwrap();
+        let app = spawn(routes(state)).await;
+
+        for _ in 0..3 {
+            let resp = reqwest::Client::new()
+                .post(format!("{app}/login"))
+                .form(&[("phone_number", "5555550100")])
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), reqwest::StatusCode::OK);
+        }
+        assert_eq!(twilio.messages().len(), 1);
+    }
+
+    #[sqlx::test]
+    async fn anonymous_webhooks_are_rejected(db: PgPool) {
+        let twilio = FakeTwilio::start().await.unwrap();
+        let state = test_state(db, &twilio).await;
+        let app = spawn(routes(state.clone())).await;
+
+        let resp = reqwest::Client::new()
+            .post(format!("{app}/webhook_subscription"))
+            .form(&[
+                ("url", "https://example.com/webhook"),
+                ("handle", "alice.test"),
+            ])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::UNAUTHORIZED);
+        let webhooks = sqlx::query_scalar!("SELECT COUNT(*) FROM WebhookSubscriptions")
+            .fetch_one(&state.db)
+            .await
+            .unwrap();
+        assert_eq!(webhooks, Some(0));
+    }
+
+    #[sqlx::test]
+    async fn invalid_numbers_are_rejected(db: PgPool) {
+        // Lookup says anything it wasn't told about is invalid
+        let twilio = FakeTwilio::start().await.unwrap();
+        let state = test_state(db, &twilio).await;
+        let app = spawn(routes(state.clone())).await;
+
+        let resp = reqwest::Client::new()
+            .post(format!("{app}/sms_subscription"))
+            .form(&[("phone_number", "555"), ("handle", "alice.test")])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::BAD_REQUEST);
+        let resp = reqwest::Client::new()
+            .post(format!("{app}/login"))
+            .form(&[("phone_number", "555")])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::BAD_REQUEST);
+
+        assert!(twilio.messages().is_empty());
+        let subscriptions = sqlx::query_scalar!("SELECT COUNT(*) FROM SmsHandleSubscriptions")
+            .fetch_one(&state.db)
+            .await
+            .unwrap();
+        assert_eq!(subscriptions, Some(0));
+    }
 }
@@ -0,0 +1,455 @@
+use std::{collections::HashMap, sync::Arc};
+
+use cja::{color_eyre, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    atproto::AppView,
+    chat::{ChatNotifier, ChatProvider},
+    email::EmailConfig,
+    http::PublicClient,
+    sms::TwilioConfig,
+};
+
+/// Where a subscription's notifications get sent, stored in its `channel`
+/// column
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Channel {
+    Sms,
+    Email,
+    Discord,
+    Slack,
+}
+
+impl Channel {
+    pub const ALL: [Channel; 4] = [
+        Channel::Sms,
+        Channel::Email,
+        Channel::Discord,
+        Channel::Slack,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Channel::Sms => "sms",
+            Channel::Email => "email",
+            Channel::Discord => "discord",
+            Channel::Slack => "slack",
+        }
+    }
+
+    pub fn parse(channel: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|candidate| candidate.as_str() == channel)
+    }
+}
+
+/// What gets sent. Each notifier decides how much of a post it can show.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Message {
+    Post(Box<PostMessage>),
+    /// Anything that isn't a post, like a handle change or a digest. Only
+    /// email shows the subject.
+    Notice {
+        subject: String,
+        body: String,
+    },
+}
+
+impl Message {
+    pub fn notice(subject: impl Into<String>, body: impl Into<String>) -> Self {
+        Message::Notice {
+            subject: subject.into(),
+            body: body.into(),
+        }
+    }
+}
+
+/// A way of getting a [`Message`] to someone. `to` is whatever the channel
+/// addresses people by: a phone number, an email address or a webhook URL.
+#[async_trait::async_trait]
+pub trait Notifier: Send + Sync {
+    async fn send(&self, to: &str, message: &Message) -> Result<()>;
+
+    /// A message for a subscription, with a link that turns it off. Only
+    /// email does anything with the link.
+    async fn send_to_subscriber(
+        &self,
+        to: &str,
+        message: &Message,
+        _unsubscribe_url: &str,
+    ) -> Result<()> {
+        self.send(to, message).await
+    }
+}
+
+/// Logs messages instead of sending them, so the whole flow can be run
+/// locally without Twilio or an SMTP server
+pub struct LogNotifier(pub Channel);
+
+#[async_trait::async_trait]
+impl Notifier for LogNotifier {
+    async fn send(&self, to: &str, message: &Message) -> Result<()> {
+        tracing::info!(channel = self.0.as_str(), %to, ?message, "not sending notification");
+        Ok(())
+    }
+}
+
+/// The channels this instance can deliver to. Anything not registered is
+/// hidden from the forms and its subscriptions are skipped.
+#[derive(Clone, Default)]
+pub struct Notifiers(HashMap<Channel, Arc<dyn Notifier>>);
+
+impl Notifiers {
+    /// Discord and Slack only need the URL each subscriber gives us so
+    /// they're always available. SMS needs Twilio and email needs
+    /// `SMTP_URL`. With `LOG_NOTIFICATIONS` set, any channel that isn't
+    /// configured logs its messages instead.
+    pub fn from_env(
+        twilio: Option<&TwilioConfig>,
+        appview: &AppView,
+        public_client: &PublicClient,
+    ) -> Result<Self> {
+        let mut notifiers = Self::default();
+        notifiers.register(
+            Channel::Discord,
+            ChatNotifier::new(
+                ChatProvider::Discord,
+                public_client.clone(),
+                appview.clone(),
+            ),
+        );
+        notifiers.register(
+            Channel::Slack,
+            ChatNotifier::new(ChatProvider::Slack, public_client.clone(), appview.clone()),
+        );
+        if let Some(twilio) = twilio {
+            notifiers.register(Channel::Sms, twilio.clone());
+        }
+        if let Some(email) = EmailConfig::from_env()? {
+            notifiers.register(Channel::Email, email);
+        }
+        if std::env::var("LOG_NOTIFICATIONS").is_ok() {
+            notifiers.log_unconfigured();
+        }
+
+        let configured: Vec<_> = Channel::ALL
+            .into_iter()
+            .filter(|&channel| notifiers.is_configured(channel))
+            .map(Channel::as_str)
+            .collect();
+        tracing::info!(?configured, "notification channels");
+
+        Ok(notifiers)
+    }
+
+    pub fn register(&mut self, channel: Channel, notifier: impl Notifier + 'static) {
+        self.0.insert(channel, Arc::new(notifier));
+    }
+
+    /// Fill every channel that doesn't have a real notifier with a
+    /// [`LogNotifier`]
+    pub fn log_unconfigured(&mut self) {
+        for channel in Channel::ALL {
+            self.0
+                .entry(channel)
+                .or_insert_with(|| Arc::new(LogNotifier(channel)));
+        }
+    }
+
+    pub fn is_configured(&self, channel: Channel) -> bool {
+        self.0.contains_key(&channel)
+    }
+
+    fn get(&self, channel: Channel) -> Result<&Arc<dyn Notifier>> {
+        self.0.get(&channel).ok_or_else(|| {
+            color_eyre::eyre::eyre!("{} notifications aren't configured", channel.as_str())
+        })
+    }
+
+    pub async fn send(&self, channel: Channel, to: &str, message: &Message) -> Result<()> {
+        self.get(channel)?.send(to, message).await
+    }
+
+    pub async fn send_to_subscriber(
+        &self,
+        channel: Channel,
+        to: &str,
+        message: &Message,
+        unsubscribe_url: &str,
+    ) -> Result<()> {
+        self.get(channel)?
+            .send_to_subscriber(to, message, unsubscribe_url)
+            .await
+    }
+}
+
+/// A facet that turns a byte range of the post text into a link
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Link {
+    pub byte_start: usize,
+    pub byte_end: usize,
+    pub url: String,
+    #[serde(default)]
+    pub kind: LinkKind,
+}
+
+/// Which kind of facet a [`Link`] came from
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LinkKind {
+    /// A link to anywhere. The text is often a shortened form of the URL.
+    #[default]
+    Link,
+    Mention,
+    Tag,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Image {
+    pub thumb: String,
+    pub fullsize: String,
+    pub alt: String,
+}
+
+/// Everything about a post a notifier needs, captured when it comes off the
+/// firehose. Chat channels look up the author's profile and any quoted post
+/// when the message is sent.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PostMessage {
+    pub author: String,
+    /// Empty for authors nobody subscribed to until the job sending it looks
+    /// it up
+    pub handle: String,
+    pub text: String,
+    pub links: Vec<Link>,
+    pub images: Vec<Image>,
+    pub quote_uri: Option<String>,
+    pub permalink: String,
+    pub created_at: Option<String>,
+    #[serde(default)]
+    pub rkey: String,
+    #[serde(default)]
+    pub video: bool,
+    /// The link card attached to the post, if it has one
+    #[serde(default)]
+    pub external_uri: Option<String>,
+    /// Why it was sent, when it isn't just a new post from someone they
+    /// subscribed to. Like `New reply to @alice.test`.
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+impl PostMessage {
+    pub fn new(handle: &str, author: &str, rkey: &str, record: &Value) -> Self {
+        let links = record["facets"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|facet| {
+                let (kind, url) = facet["features"].as_array()?.iter().find_map(facet_link)?;
+                Some(Link {
+                    byte_start: facet["index"]["byteStart"].as_u64()? as usize,
+                    byte_end: facet["index"]["byteEnd"].as_u64()? as usize,
+                    url,
+                    kind,
+                })
+            })
+            .collect();
+
+        let embed = &record["embed"];
+        let images = embed["images"]
+            .as_array()
+            .or_else(|| embed["media"]["images"].as_array())
+            .into_iter()
+            .flatten()
+            .filter_map(|image| {
+                let cid = image["image"]["ref"]["$link"].as_str()?;
+                Some(Image {
+                    thumb: format!(
+                        "https://cdn.bsky.app/img/feed_thumbnail/plain/{author}/{cid}@jpeg"
+                    ),
+                    fullsize: format!(
+                        "https://cdn.bsky.app/img/feed_fullsize/plain/{author}/{cid}@jpeg"
+                    ),
+                    alt: image["alt"].as_str().unwrap_or_default().to_string(),
+                })
+            })
+            .collect();
+
+        // A plain quote has the record at `embed.record`, a quote with media
+        // nests it one level further down
+        let quote_uri = match embed["$type"].as_str() {
+            Some("app.bsky.embed.record") => embed["record"]["uri"].as_str(),
+            Some("app.bsky.embed.recordWithMedia") => embed["record"]["record"]["uri"].as_str(),
+            _ => None,
+        };
+        // Media alongside a quote lives under `embed.media`
+        let media = match embed["$type"].as_str() {
+            Some("app.bsky.embed.recordWithMedia") => &embed["media"],
+            _ => embed,
+        };
+
+        Self {
+            author: author.to_string(),
+            handle: handle.to_string(),
+            text: record["text"].as_str().unwrap_or_default().to_string(),
+            links,
+            images,
+            quote_uri: quote_uri.map(ToString::to_string),
+            permalink: format!("https://bsky.app/profile/{author}/post/{rkey}"),
+            created_at: record["createdAt"].as_str().map(ToString::to_string),
+            rkey: rkey.to_string(),
+            video: media["$type"].as_str() == Some("app.bsky.embed.video"),
+            external_uri: media["external"]["uri"].as_str().map(ToString::to_string),
+            reason: None,
+        }
+    }
+
+    pub fn with_reason(mut self, reason: impl Into<String>) -> Self {
+        self.reason = Some(reason.into());
+        self
+    }
+
+    /// One line saying what this is, used as an email subject and to open
+    /// chat messages
+    pub fn heading(&self) -> String {
+        match &self.reason {
+            Some(reason) => reason.clone(),
+            None => format!("New post from @{}", self.handle),
+        }
+    }
+
+    /// The text split into plain runs and linked runs. Facets that overlap or
+    /// don't land on character boundaries are left as plain text.
+    pub(crate) fn segments(&self) -> Vec<(&str, Option<&Link>)> {
+        let mut links: Vec<&Link> = self.links.iter().collect();
+        links.sort_by_key(|link| link.byte_start);
+
+        let mut segments = vec![];
+        let mut at = 0;
+        for link in links {
+            let valid = link.byte_start >= at
+                && link.byte_start < link.byte_end
+                && self.text.is_char_boundary(link.byte_start)
+                && self.text.is_char_boundary(link.byte_end);
+            if !valid {
+                continue;
+            }
+            if link.byte_start > at {
+                segments.push((&self.text[at..link.byte_start], None));
+            }
+            segments.push((&self.text[link.byte_start..link.byte_end], Some(link)));
+            at = link.byte_end;
+        }
+        if at < self.text.len() {
+            segments.push((&self.text[at..], None));
+        }
+        segments
+    }
+}
+
+/// Where a single facet feature should link to
+fn facet_link(feature: &Value) -> Option<(LinkKind, String)> {
+    match feature["$type"].as_str()? {
+        "app.bsky.richtext.facet#link" => {
+            let uri = feature["uri"].as_str()?;
+            Some((LinkKind::Link, uri.to_string()))
+        }
+        "app.bsky.richtext.facet#mention" => {
+            let did = feature["did"].as_str()?;
+            Some((LinkKind::Mention, format!("https://bsky.app/profile/{did}")))
+        }
+        "app.bsky.richtext.facet#tag" => {
+            let tag = feature["tag"].as_str()?;
+            Some((LinkKind::Tag, format!("https://bsky.app/hashtag/{tag}")))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn channels_round_trip() {
+        for channel in Channel::ALL {
+            assert_eq!(Channel::parse(channel.as_str()), Some(channel));
+        }
+        assert_eq!(Channel::parse("pager"), None);
+    }
+
+    #[tokio::test]
+    async fn only_registered_channels_send() {
+        let mut notifiers = Notifiers::default();
+        notifiers.register(Channel::Email, LogNotifier(Channel::Email));
+        let message = Message::notice("Subject", "Body");
+
+        assert!(notifiers.is_configured(Channel::Email));
+        assert!(!notifiers.is_configured(Channel::Sms));
+        assert!(notifiers
+            .send(Channel::Email, "alice@example.com", &message)
+            .await
+            .is_ok());
+        assert!(notifiers
+            .send(Channel::Sms, "+15555550100", &message)
+            .await
+            .is_err());
+
+        notifiers.log_unconfigured();
+        assert!(Channel::ALL
+            .into_iter()
+            .all(|channel| notifiers.is_configured(channel)));
+    }
+
+    #[test]
+    fn reads_facets_images_and_quote() {
+        let post = PostMessage::new(
+            "alice.test",
+            "did:plc:alice",
+            "3kabc",
+            &json!({
+                "text": "Hi @bob.test",
+                "facets": [
+                    { "index": { "byteStart": 3, "byteEnd": 12 }, "features": [{ "$type": "app.bsky.richtext.facet#mention", "did": "did:plc:bob" }] }
+                ],
+                "embed": {
+                    "$type": "app.bsky.embed.recordWithMedia",
+                    "record": { "record": { "uri": "at://did:plc:carol/app.bsky.feed.post/3kxyz", "cid": "" } },
+                    "media": {
+                        "images": [{ "alt": "", "image": { "ref": { "$link": "bafkcat" } } }]
+                    }
+                }
+            }),
+        );
+
+        assert_eq!(post.links.len(), 1);
+        assert_eq!(post.links[0].url, "https://bsky.app/profile/did:plc:bob");
+        assert_eq!(
+            post.images[0].thumb,
+            "https://cdn.bsky.app/img/feed_thumbnail/plain/did:plc:alice/bafkcat@jpeg"
+        );
+        assert_eq!(
+            post.quote_uri.as_deref(),
+            Some("at://did:plc:carol/app.bsky.feed.post/3kxyz")
+        );
+        assert_eq!(
+            post.permalink,
+            "https://bsky.app/profile/did:plc:alice/post/3kabc"
+        );
+        assert_eq!(post.rkey, "3kabc");
+        assert!(!post.video);
+        assert_eq!(post.links[0].kind, LinkKind::Mention);
+        assert_eq!(
+            post.segments(),
+            vec![("Hi ", None), ("@bob.test", Some(&post.links[0]))]
+        );
+    }
+}
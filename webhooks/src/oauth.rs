@@ -0,0 +1,543 @@
+//! Sign in with Bluesky. We're a public atproto OAuth client: the client
+//! metadata document is served from our own URL, authorization requests go
+//! through PAR, and every token request carries a DPoP proof from a key made
+//! for that one login. We only ask for the `atproto` scope, all we want is to
+//! know which DID the user is.
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse as _, Redirect, Response},
+    Form, Json,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use cja::{color_eyre, Result};
+use jsonwebtoken::{
+    jwk::{
+        AlgorithmParameters, CommonParameters, EllipticCurve, EllipticCurveKeyParameters,
+        EllipticCurveKeyType, Jwk,
+    },
+    Algorithm, EncodingKey, Header,
+};
+use p256::{
+    elliptic_curve::sec1::ToEncodedPoint as _,
+    pkcs8::{DecodePrivateKey as _, EncodePrivateKey as _},
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+use tower_cookies::Cookies;
+
+use crate::{atproto::fetch_did_document, http::PublicClient, resolve_handle, sessions, AppState};
+
+const SCOPE: &str = "atproto";
+const DPOP_NONCE_HEADER: &str = "DPoP-Nonce";
+/// How long someone has to finish logging in at their PDS
+const REQUEST_TTL_SECS: f64 = 10.0 * 60.0;
+
+/// Loopback clients don't publish metadata, the authorization server derives
+/// it from the client_id instead. That's what lets login work in development.
+fn is_loopback(base_url: &str) -> bool {
+    let Ok(url) = reqwest::Url::parse(base_url) else {
+        return false;
+    };
+    url.scheme() == "http" && matches!(url.host_str(), Some("localhost" | "127.0.0.1" | "[::1]"))
+}
+
+fn redirect_uri(base_url: &str) -> String {
+    let base_url = base_url.trim_end_matches('/');
+    if is_loopback(base_url) {
+        // Loopback redirects have to use an IP, not `localhost`
+        return format!(
+            "{}/oauth/callback",
+            base_url.replace("localhost", "127.0.0.1")
+        );
+    }
+    format!("{base_url}/oauth/callback")
+}
+
+fn client_id(base_url: &str) -> String {
+    if is_loopback(base_url) {
+        let url = reqwest::Url::parse_with_params(
+            "http://localhost",
+            &[
+                ("redirect_uri", redirect_uri(base_url).as_str()),
+                ("scope", SCOPE),
+            ],
+        )
+        .expect("static base URL parses");
+        return url.to_string();
+    }
+    format!(
+        "{}/oauth/client-metadata.json",
+        base_url.trim_end_matches('/')
+    )
+}
+
+#[derive(Serialize)]
+pub struct ClientMetadata {
+    client_id: String,
+    client_name: &'static str,
+    client_uri: String,
+    redirect_uris: Vec<String>,
+    grant_types: Vec<&'static str>,
+    response_types: Vec<&'static str>,
+    scope: &'static str,
+    token_endpoint_auth_method: &'static str,
+    application_type: &'static str,
+    dpop_bound_access_tokens: bool,
+}
+
+pub async fn client_metadata(State(state): State<AppState>) -> Json<ClientMetadata> {
+    Json(ClientMetadata {
+        client_id: client_id(&state.base_url),
+        client_name: "bsky-webhooks",
+        client_uri: state.base_url.clone(),
+        redirect_uris: vec![redirect_uri(&state.base_url)],
+        grant_types: vec!["authorization_code"],
+        response_types: vec!["code"],
+        scope: SCOPE,
+        token_endpoint_auth_method: "none",
+        application_type: "web",
+        dpop_bound_access_tokens: true,
+    })
+}
+
+fn random_token() -> String {
+    URL_SAFE_NO_PAD.encode(rand::random::<[u8; 32]>())
+}
+
+/// The S256 PKCE challenge for `verifier`
+fn pkce_challenge(verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()))
+}
+
+/// A throwaway P-256 key that the tokens from one login are bound to
+struct DpopKey {
+    secret: p256::SecretKey,
+}
+
+#[derive(Serialize)]
+struct DpopClaims<'a> {
+    jti: String,
+    htm: &'a str,
+    htu: &'a str,
+    iat: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nonce: Option<&'a str>,
+}
+
+impl DpopKey {
+    fn generate() -> Self {
+        Self {
+            secret: p256::SecretKey::random(&mut rand::rngs::OsRng),
+        }
+    }
+
+    fn to_pkcs8(&self) -> Result<String> {
+        let der = self
+            .secret
+            .to_pkcs8_der()
+            .map_err(|e| color_eyre::eyre::eyre!("failed to encode DPoP key: {e}"))?;
+        Ok(URL_SAFE_NO_PAD.encode(der.as_bytes()))
+    }
+
+    fn from_pkcs8(encoded: &str) -> Result<Self> {
+        let der = URL_SAFE_NO_PAD.decode(encoded)?;
+        let secret = p256::SecretKey::from_pkcs8_der(&der)
+            .map_err(|e| color_eyre::eyre::eyre!("failed to decode DPoP key: {e}"))?;
+        Ok(Self { secret })
+    }
+
+    fn jwk(&self) -> Jwk {
+        let point = self.secret.public_key().to_encoded_point(false);
+        Jwk {
+            common: CommonParameters::default(),
+            algorithm: AlgorithmParameters::EllipticCurve(EllipticCurveKeyParameters {
+                key_type: EllipticCurveKeyType::EC,
+                curve: EllipticCurve::P256,
+                x: URL_SAFE_NO_PAD.encode(point.x().expect("uncompressed point has x")),
+                y: URL_SAFE_NO_PAD.encode(point.y().expect("uncompressed point has y")),
+            }),
+        }
+    }
+
+    /// A DPoP proof JWT for one request
+    fn proof(&self, method: &str, url: &str, nonce: Option<&str>) -> Result<String> {
+        let mut header = Header::new(Algorithm::ES256);
+        header.typ = Some("dpop+jwt".to_string());
+        header.jwk = Some(self.jwk());
+
+        let claims = DpopClaims {
+            jti: random_token(),
+            htm: method,
+            htu: url,
+            iat: chrono::Utc::now().timestamp(),
+            nonce,
+        };
+
+        let der = self
+            .secret
+            .to_pkcs8_der()
+            .map_err(|e| color_eyre::eyre::eyre!("failed to encode DPoP key: {e}"))?;
+        Ok(jsonwebtoken::encode(
+            &header,
+            &claims,
+            &EncodingKey::from_ec_der(der.as_bytes()),
+        )?)
+    }
+}
+
+/// POST a form with a DPoP proof. Authorization servers hand out nonces by
+/// rejecting the first request and sending one back in `DPoP-Nonce`, so we
+/// retry once with whatever nonce we were given. Returns the response along
+/// with the latest nonce so later requests can start with it.
+async fn dpop_post(
+    client: &PublicClient,
+    key: &DpopKey,
+    url: &str,
+    form: &[(&str, &str)],
+    mut nonce: Option<String>,
+) -> Result<(reqwest::Response, Option<String>)> {
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        let proof = key.proof("POST", url, nonce.as_deref())?;
+        let resp = client
+            .post(url)?
+            .header("DPoP", proof)
+            .form(form)
+            .send()
+            .await?;
+
+        let new_nonce = resp
+            .headers()
+            .get(DPOP_NONCE_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .map(ToString::to_string);
+        let needs_retry = matches!(resp.status().as_u16(), 400 | 401)
+            && new_nonce.is_some()
+            && new_nonce != nonce;
+        if new_nonce.is_some() {
+            nonce = new_nonce;
+        }
+
+        if !needs_retry || attempts >= 2 {
+            return Ok((resp, nonce));
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ProtectedResourceMetadata {
+    authorization_servers: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct AuthorizationServerMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    pushed_authorization_request_endpoint: String,
+}
+
+#[derive(Deserialize)]
+struct ParResponse {
+    request_uri: String,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    sub: String,
+    token_type: String,
+    scope: String,
+}
+
+/// Find the authorization server responsible for `pds`. Both come from
+/// whoever controls the DID, so they're fetched like any other untrusted URL.
+async fn discover(client: &PublicClient, pds: &str) -> Result<AuthorizationServerMetadata> {
+    let resource: ProtectedResourceMetadata = client
+        .get(format!("{pds}/.well-known/oauth-protected-resource"))?
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    let Some(issuer) = resource.authorization_servers.first() else {
+        return Err(color_eyre::eyre::eyre!("{pds} has no authorization server"));
+    };
+
+    let server: AuthorizationServerMetadata = client
+        .get(format!("{issuer}/.well-known/oauth-authorization-server"))?
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    if &server.issuer != issuer {
+        return Err(color_eyre::eyre::eyre!(
+            "authorization server {issuer} claims to be {}",
+            server.issuer
+        ));
+    }
+
+    Ok(server)
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct LoginForm {
+    handle: String,
+}
+
+pub async fn login(
+    State(state): State<AppState>,
+    Form(form): Form<LoginForm>,
+) -> Result<Response, Response> {
+    start_login(&state, form.handle.trim_start_matches('@'))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response())
+}
+
+async fn start_login(state: &AppState, handle: &str) -> Result<Response> {
+    let did = resolve_handle(state, handle).await?;
+    let Some(document) = fetch_did_document(&state.public_client, &did).await? else {
+        return Err(color_eyre::eyre::eyre!("unsupported DID {}", did.as_str()));
+    };
+    let Some(pds) = document.pds_endpoint() else {
+        return Err(color_eyre::eyre::eyre!("{} has no PDS", did.as_str()));
+    };
+    let server = discover(&state.public_client, pds).await?;
+
+    let key = DpopKey::generate();
+    let verifier = random_token();
+    let oauth_state = random_token();
+    let client_id = client_id(&state.base_url);
+    let redirect_uri = redirect_uri(&state.base_url);
+    let challenge = pkce_challenge(&verifier);
+
+    let (resp, nonce) = dpop_post(
+        &state.public_client,
+        &key,
+        &server.pushed_authorization_request_endpoint,
+        &[
+            ("client_id", client_id.as_str()),
+            ("response_type", "code"),
+            ("redirect_uri", redirect_uri.as_str()),
+            ("scope", SCOPE),
+            ("state", oauth_state.as_str()),
+            ("code_challenge", challenge.as_str()),
+            ("code_challenge_method", "S256"),
+            ("login_hint", handle),
+        ],
+        None,
+    )
+    .await?;
+    let par: ParResponse = resp.error_for_status()?.json().await?;
+
+    sqlx::query!(
+        "INSERT INTO OAuthRequests (state, did, issuer, token_endpoint, pkce_verifier, dpop_key, dpop_nonce)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)",
+        &oauth_state,
+        did.as_str(),
+        &server.issuer,
+        &server.token_endpoint,
+        &verifier,
+        key.to_pkcs8()?,
+        nonce,
+    )
+    .execute(&state.db)
+    .await?;
+
+    let authorize = reqwest::Url::parse_with_params(
+        &server.authorization_endpoint,
+        &[
+            ("client_id", client_id.as_str()),
+            ("request_uri", par.request_uri.as_str()),
+        ],
+    )?;
+    Ok(Redirect::to(authorize.as_str()).into_response())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CallbackParams {
+    state: String,
+    code: Option<String>,
+    iss: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+pub async fn callback(
+    State(state): State<AppState>,
+    cookies: Cookies,
+    Query(params): Query<CallbackParams>,
+) -> Result<Response, Response> {
+    // Each request can only be completed once
+    let request = sqlx::query!(
+        "DELETE FROM OAuthRequests
+        WHERE state = $1 AND created_at > NOW() - make_interval(secs => $2)
+        RETURNING did, issuer, token_endpoint, pkce_verifier, dpop_key, dpop_nonce",
+        &params.state,
+        REQUEST_TTL_SECS,
+    )
+    .fetch_optional(&state.db)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response())?;
+    let Some(request) = request else {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Unknown or expired login, please try again",
+        )
+            .into_response());
+    };
+
+    if let Some(error) = params.error {
+        let description = params.error_description.unwrap_or_default();
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("Login failed: {error} {description}"),
+        )
+            .into_response());
+    }
+    let Some(code) = params.code else {
+        return Err((StatusCode::BAD_REQUEST, "Missing authorization code").into_response());
+    };
+    if params.iss.as_deref() != Some(request.issuer.as_str()) {
+        return Err((StatusCode::BAD_REQUEST, "Authorization server mismatch").into_response());
+    }
+
+    let did = exchange_code(
+        &state,
+        &code,
+        &request.token_endpoint,
+        &request.pkce_verifier,
+        &request.dpop_key,
+        request.dpop_nonce,
+    )
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response())?;
+
+    // The token has to be for the account we started logging in as,
+    // otherwise the authorization server is vouching for someone it doesn't
+    // host
+    if did != request.did {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Logged in as an unexpected account",
+        )
+            .into_response());
+    }
+
+    let session_id = sessions::create_did_session(&state, &did)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response())?;
+    sessions::set_session_cookie(&state, &cookies, session_id);
+
+    Ok(Redirect::to("/subscriptions").into_response())
+}
+
+/// Trade the authorization code for tokens and return the DID they're for.
+/// We don't keep the tokens, the DID is all we wanted.
+async fn exchange_code(
+    state: &AppState,
+    code: &str,
+    token_endpoint: &str,
+    verifier: &str,
+    dpop_key: &str,
+    nonce: Option<String>,
+) -> Result<String> {
+    let key = DpopKey::from_pkcs8(dpop_key)?;
+    let client_id = client_id(&state.base_url);
+    let redirect_uri = redirect_uri(&state.base_url);
+
+    let (resp, _) = dpop_post(
+        &state.public_client,
+        &key,
+        token_endpoint,
+        &[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", redirect_uri.as_str()),
+            ("code_verifier", verifier),
+            ("client_id", client_id.as_str()),
+        ],
+        nonce,
+    )
+    .await?;
+    let token: TokenResponse = resp.error_for_status()?.json().await?;
+
+    if !token.token_type.eq_ignore_ascii_case("DPoP") {
+        return Err(color_eyre::eyre::eyre!(
+            "expected a DPoP token, got {}",
+            token.token_type
+        ));
+    }
+    if !token.scope.split_whitespace().any(|scope| scope == SCOPE) {
+        return Err(color_eyre::eyre::eyre!(
+            "token is missing the atproto scope"
+        ));
+    }
+
+    Ok(token.sub)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // From RFC 7636 appendix B
+    #[test]
+    fn pkce_challenge_matches_rfc() {
+        assert_eq!(
+            pkce_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk"),
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+        );
+    }
+
+    #[test]
+    fn client_id_for_hosted_and_loopback() {
+        assert_eq!(
+            client_id("https://bsky-webhooks.example.com/"),
+            "https://bsky-webhooks.example.com/oauth/client-metadata.json"
+        );
+        assert_eq!(
+            redirect_uri("https://bsky-webhooks.example.com"),
+            "https://bsky-webhooks.example.com/oauth/callback"
+        );
+
+        assert_eq!(
+            redirect_uri("http://localhost:3000"),
+            "http://127.0.0.1:3000/oauth/callback"
+        );
+        assert_eq!(
+            client_id("http://localhost:3000"),
+            "http://localhost/?redirect_uri=http%3A%2F%2F127.0.0.1%3A3000%2Foauth%2Fcallback&scope=atproto"
+        );
+    }
+
+    #[test]
+    fn dpop_proof_verifies_against_its_jwk() {
+        let key = DpopKey::generate();
+        let key = DpopKey::from_pkcs8(&key.to_pkcs8().unwrap()).unwrap();
+        let proof = key
+            .proof("POST", "https://example.com/oauth/token", Some("abc"))
+            .unwrap();
+
+        let header = jsonwebtoken::decode_header(&proof).unwrap();
+        assert_eq!(header.typ.as_deref(), Some("dpop+jwt"));
+        let jwk = header.jwk.unwrap();
+
+        let mut validation = jsonwebtoken::Validation::new(Algorithm::ES256);
+        validation.required_spec_claims.clear();
+        validation.validate_exp = false;
+        let decoded = jsonwebtoken::decode::<serde_json::Value>(
+            &proof,
+            &jsonwebtoken::DecodingKey::from_jwk(&jwk).unwrap(),
+            &validation,
+        )
+        .unwrap();
+
+        assert_eq!(decoded.claims["htm"], "POST");
+        assert_eq!(decoded.claims["htu"], "https://example.com/oauth/token");
+        assert_eq!(decoded.claims["nonce"], "abc");
+    }
+}
@@ -0,0 +1,256 @@
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse as _, Redirect, Response},
+    Form,
+};
+use cja::Result;
+use maud::html;
+use serde::Deserialize;
+use tower_cookies::{cookie::SameSite, Cookie, Cookies};
+
+use crate::{
+    notifiers::{Channel, Message},
+    sms, AppState, MAX_VERIFICATION_ATTEMPTS, VERIFICATION_CODE_TTL, VERIFICATION_COOLDOWN,
+};
+
+const SESSION_COOKIE: &str = "session_id";
+const SESSION_TTL: std::time::Duration = std::time::Duration::from_secs(30 * 24 * 60 * 60);
+
+/// Someone who has either proven they own `phone_number` by entering the
+/// code we texted to it, or signed in with Bluesky as `did`
+pub struct Session {
+    pub session_id: uuid::Uuid,
+    pub phone_number: Option<String>,
+    pub did: Option<String>,
+}
+
+/// The verified session for this request, if the cookie points at one that
+/// hasn't expired. The cookie is encrypted with the app's cookie key, so the
+/// session id can't be read or forged client side.
+pub async fn current_session(state: &AppState, cookies: &Cookies) -> Result<Option<Session>> {
+    let Some(session_id) = cookies
+        .private(&state.cookie_key.0)
+        .get(SESSION_COOKIE)
+        .and_then(|cookie| cookie.value().parse::<uuid::Uuid>().ok())
+    else {
+        return Ok(None);
+    };
+
+    let session = sqlx::query_as!(
+        Session,
+        "SELECT session_id, phone_number, did FROM Sessions
+        WHERE session_id = $1 AND verified_at IS NOT NULL AND expires_at > NOW()",
+        session_id,
+    )
+    .fetch_optional(&state.db)
+    .await?;
+
+    Ok(session)
+}
+
+/// Start a session for someone who signed in with Bluesky
+pub async fn create_did_session(state: &AppState, did: &str) -> Result<uuid::Uuid> {
+    let session = sqlx::query!(
+        "INSERT INTO Sessions (did, verified_at, expires_at)
+        VALUES ($1, NOW(), NOW() + make_interval(secs => $2))
+        RETURNING session_id",
+        did,
+        SESSION_TTL.as_secs_f64(),
+    )
+    .fetch_one(&state.db)
+    .await?;
+
+    Ok(session.session_id)
+}
+
+pub async fn login_page(State(state): State<AppState>) -> Response {
+    html! {
+        p { "Sign in with your Bluesky account." }
+        form action="/oauth/login" method="post" {
+            input type="text" name="handle" placeholder="Handle" {}
+            input type="submit" value="Sign In" {}
+        }
+        @if state.notifiers.is_configured(Channel::Sms) {
+            p { "Or enter the phone number you subscribed with and we'll text you a code." }
+            form action="/login" method="post" {
+                input type="text" name="phone_number" placeholder="Phone Number" {}
+                input type="submit" value="Send Code" {}
+            }
+        }
+    }
+    .into_response()
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct LoginForm {
+    phone_number: String,
+}
+
+pub async fn login(
+    State(state): State<AppState>,
+    Form(form): Form<LoginForm>,
+) -> Result<Response, Response> {
+    if !state.notifiers.is_configured(Channel::Sms) {
+        return Err((StatusCode::NOT_FOUND, "Phone number login isn't enabled").into_response());
+    }
+    let verified_phone_number =
+        sms::lookup_phone_number(state.twilio_config.as_ref(), &form.phone_number)
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response())?;
+    if !verified_phone_number.valid {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "That doesn't look like a valid phone number",
+        )
+            .into_response());
+    }
+
+    // Checked before this request's session exists. Like subscribing, one
+    // number can't be sent a stream of codes by resubmitting the form.
+    let recently_sent = sqlx::query_scalar!(
+        r#"SELECT EXISTS (
+            SELECT 1 FROM Sessions
+            WHERE phone_number = $1 AND created_at > NOW() - make_interval(secs => $2)
+        ) AS "exists!""#,
+        verified_phone_number.phone_number,
+        VERIFICATION_COOLDOWN.as_secs_f64(),
+    )
+    .fetch_one(&state.db)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response())?;
+
+    let code = sms::generate_verification_code();
+    let session = sqlx::query!(
+        "INSERT INTO Sessions (phone_number, verification_code, expires_at)
+        VALUES ($1, $2, NOW() + make_interval(secs => $3))
+        RETURNING session_id",
+        verified_phone_number.phone_number,
+        &code,
+        VERIFICATION_CODE_TTL.as_secs_f64(),
+    )
+    .fetch_one(&state.db)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response())?;
+
+    // Only text numbers that have subscribed to something, so this can't be
+    // used to send codes to strangers. The page looks the same either way,
+    // and the same when we've just texted a code.
+    let has_subscriptions = sqlx::query!(
+        "SELECT EXISTS (SELECT 1 FROM SmsHandleSubscriptions WHERE phone_number = $1 AND confirmed_at IS NOT NULL) AS \"exists!\"",
+        verified_phone_number.phone_number,
+    )
+    .fetch_one(&state.db)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response())?
+    .exists;
+
+    if has_subscriptions && !recently_sent {
+        let message = Message::notice(
+            "Your bsky-webhooks login code",
+            format!("Your bsky-webhooks login code is {code}"),
+        );
+        state
+            .notifiers
+            .send(Channel::Sms, &verified_phone_number.phone_number, &message)
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response())?;
+    }
+
+    Ok(html! {
+        p { "If " (verified_phone_number.national_format) " has any subscriptions we've texted it a code." }
+        form action="/login/confirm" method="post" {
+            input type="hidden" name="session_id" value=(session.session_id) {}
+            input type="text" name="code" inputmode="numeric" autocomplete="one-time-code" placeholder="123456" {}
+            input type="submit" value="Log In" {}
+        }
+    }
+    .into_response())
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ConfirmLoginForm {
+    session_id: uuid::Uuid,
+    code: String,
+}
+
+pub async fn confirm_login(
+    State(state): State<AppState>,
+    cookies: Cookies,
+    Form(form): Form<ConfirmLoginForm>,
+) -> Result<Response, Response> {
+    let pending = sqlx::query!(
+        "UPDATE Sessions
+        SET verification_attempts = verification_attempts + 1, updated_at = NOW()
+        WHERE session_id = $1 AND verified_at IS NULL
+        RETURNING verification_code, expires_at < NOW() AS expired, verification_attempts",
+        form.session_id,
+    )
+    .fetch_optional(&state.db)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response())?;
+
+    let Some(pending) = pending else {
+        return Err((StatusCode::NOT_FOUND, "No pending login found").into_response());
+    };
+    if pending.expired.unwrap_or(true) || pending.verification_attempts > MAX_VERIFICATION_ATTEMPTS
+    {
+        return Err((
+            StatusCode::GONE,
+            "This code has expired, please log in again",
+        )
+            .into_response());
+    }
+    if !sms::verification_code_matches(pending.verification_code.as_deref(), &form.code) {
+        return Err((StatusCode::BAD_REQUEST, "That code didn't match").into_response());
+    }
+
+    sqlx::query!(
+        "UPDATE Sessions
+        SET verified_at = NOW(), verification_code = NULL, expires_at = NOW() + make_interval(secs => $2), updated_at = NOW()
+        WHERE session_id = $1",
+        form.session_id,
+        SESSION_TTL.as_secs_f64(),
+    )
+    .execute(&state.db)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response())?;
+
+    set_session_cookie(&state, &cookies, form.session_id);
+
+    Ok(Redirect::to("/subscriptions").into_response())
+}
+
+pub fn set_session_cookie(state: &AppState, cookies: &Cookies, session_id: uuid::Uuid) {
+    let mut cookie = Cookie::new(SESSION_COOKIE, session_id.to_string());
+    cookie.set_path("/");
+    cookie.set_http_only(true);
+    cookie.set_same_site(SameSite::Lax);
+    cookie.set_secure(state.base_url.starts_with("https://"));
+    cookie.set_max_age(tower_cookies::cookie::time::Duration::seconds(
+        SESSION_TTL.as_secs() as i64,
+    ));
+    cookies.private(&state.cookie_key.0).add(cookie);
+}
+
+pub async fn logout(State(state): State<AppState>, cookies: Cookies) -> Result<Response, Response> {
+    let session = current_session(&state, &cookies)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response())?;
+
+    if let Some(session) = session {
+        sqlx::query!(
+            "DELETE FROM Sessions WHERE session_id = $1",
+            session.session_id
+        )
+        .execute(&state.db)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response())?;
+    }
+
+    let mut cookie = Cookie::from(SESSION_COOKIE);
+    cookie.set_path("/");
+    cookies.private(&state.cookie_key.0).remove(cookie);
+
+    Ok(Redirect::to("/").into_response())
+}
@@ -1,31 +1,86 @@
 use cja::{color_eyre, Result};
 use serde::{Deserialize, Serialize};
 
+use crate::{
+    atproto::AppView,
+    notifiers::{Message, Notifier},
+};
+
+#[cfg(test)]
+pub mod fake;
+pub mod format;
+
+const API_BASE_URL: &str = "https://api.twilio.com";
+const LOOKUP_BASE_URL: &str = "https://lookups.twilio.com";
+
 #[derive(Clone)]
 pub struct TwilioConfig {
     pub account_sid: String,
     pub auth_token: String,
     pub phone_number: String,
+    /// Where the Messages API lives, only changed to point at a fake
+    pub api_base_url: String,
+    /// Where the Lookup API lives, only changed to point at a fake
+    pub lookup_base_url: String,
+    pub client: reqwest::Client,
+    /// Looks up the posts texts quote
+    pub appview: AppView,
 }
 
 impl TwilioConfig {
-    pub fn from_env() -> Result<Self> {
-        Ok(Self {
-            account_sid: std::env::var("TWILIO_ACCOUNT_SID")?,
-            auth_token: std::env::var("TWILIO_AUTH_TOKEN")?,
-            phone_number: std::env::var("TWILIO_PHONE_NUMBER")?,
+    /// `None` if any of the three Twilio variables is missing, which turns
+    /// off SMS and inbound texts. `TWILIO_API_URL` and `TWILIO_LOOKUP_URL`
+    /// override where requests go.
+    pub fn from_env(client: reqwest::Client, appview: &AppView) -> Option<Self> {
+        Some(Self {
+            account_sid: std::env::var("TWILIO_ACCOUNT_SID").ok()?,
+            auth_token: std::env::var("TWILIO_AUTH_TOKEN").ok()?,
+            phone_number: std::env::var("TWILIO_PHONE_NUMBER").ok()?,
+            api_base_url: std::env::var("TWILIO_API_URL")
+                .unwrap_or_else(|_| API_BASE_URL.to_string()),
+            lookup_base_url: std::env::var("TWILIO_LOOKUP_URL")
+                .unwrap_or_else(|_| LOOKUP_BASE_URL.to_string()),
+            client,
+            appview: appview.clone(),
         })
     }
 }
 
+#[async_trait::async_trait]
+impl Notifier for TwilioConfig {
+    /// Texts never show the subject of a notice
+    async fn send(&self, to: &str, message: &Message) -> Result<()> {
+        let body = match message {
+            Message::Post(post) => {
+                let quoted = match &post.quote_uri {
+                    Some(uri) => self
+                        .appview
+                        .quoted_post(uri)
+                        .await
+                        .inspect_err(
+                            |err| tracing::warn!(?err, %uri, "failed to fetch quoted post"),
+                        )
+                        .ok()
+                        .flatten(),
+                    None => None,
+                };
+                format::format_post(post, quoted.as_ref().map(|quoted| quoted.handle.as_str()))
+            }
+            Message::Notice { body, .. } => format::fit_body(body),
+        };
+        send_sms(self, to, &body).await
+    }
+}
+
 pub async fn send_sms(config: &TwilioConfig, to: &str, body: &str) -> Result<()> {
-    let client = reqwest::Client::new();
     let url = format!(
-        "https://api.twilio.com/2010-04-01/Accounts/{}/Messages.json",
+        "{}/2010-04-01/Accounts/{}/Messages.json",
+        config.api_base_url.trim_end_matches('/'),
         config.account_sid
     );
 
-    let resp = client
+    let resp = config
+        .client
         .post(url)
         .basic_auth(config.account_sid.clone(), Some(config.auth_token.clone()))
         .form(&[("To", to), ("From", &config.phone_number), ("Body", body)])
@@ -33,13 +88,62 @@ pub async fn send_sms(config: &TwilioConfig, to: &str, body: &str) -> Result<()>
         .await?;
 
     if !resp.status().is_success() {
-        return Err(color_eyre::eyre::eyre!("failed to send sms"));
+        return Err(color_eyre::eyre::eyre!(
+            "failed to send sms, Twilio returned {}",
+            resp.status()
+        ));
     }
 
     Ok(())
 }
 
-#[derive(Serialize, Deserialize)]
+/// A random 6 digit code, zero padded
+pub fn generate_verification_code() -> String {
+    use rand::Rng as _;
+
+    format!("{:06}", rand::thread_rng().gen_range(0..1_000_000))
+}
+
+/// Compared in constant time, so how long a wrong guess takes to reject says
+/// nothing about how close it was
+pub fn verification_code_matches(expected: Option<&str>, given: &str) -> bool {
+    use subtle::ConstantTimeEq as _;
+
+    expected.is_some_and(|expected| expected.as_bytes().ct_eq(given.trim().as_bytes()).into())
+}
+
+pub const SIGNATURE_HEADER: &str = "X-Twilio-Signature";
+
+/// Check an inbound request really came from Twilio. The signature is the
+/// base64 HMAC-SHA1 of the full request URL followed by every POST param,
+/// sorted by name, with each name and value concatenated.
+pub fn validate_signature(
+    auth_token: &str,
+    url: &str,
+    params: &[(String, String)],
+    signature: &str,
+) -> bool {
+    use base64::Engine as _;
+    use hmac::{Hmac, Mac};
+
+    let Ok(signature) = base64::engine::general_purpose::STANDARD.decode(signature) else {
+        return false;
+    };
+
+    let mut sorted: Vec<_> = params.iter().collect();
+    sorted.sort();
+
+    let mut mac = Hmac::<sha1::Sha1>::new_from_slice(auth_token.as_bytes())
+        .expect("HMAC accepts keys of any size");
+    mac.update(url.as_bytes());
+    for (name, value) in sorted {
+        mac.update(name.as_bytes());
+        mac.update(value.as_bytes());
+    }
+    mac.verify_slice(&signature).is_ok()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct VerifiedPhoneNumber {
     pub calling_country_code: String,
     pub country_code: String,
@@ -53,19 +157,159 @@ pub async fn find_verified_phone_numbers(
     config: &TwilioConfig,
     input_number: &str,
 ) -> Result<VerifiedPhoneNumber> {
-    let client = reqwest::Client::new();
     let url = format!(
-        "https://lookups.twilio.com/v2/PhoneNumbers/{}",
+        "{}/v2/PhoneNumbers/{}",
+        config.lookup_base_url.trim_end_matches('/'),
         input_number
     );
 
-    let resp = client
+    let resp = config
+        .client
         .get(url)
         .basic_auth(config.account_sid.clone(), Some(config.auth_token.clone()))
         .send()
         .await?
+        .error_for_status()?
         .json::<VerifiedPhoneNumber>()
         .await?;
 
     Ok(resp)
 }
+
+/// Twilio's lookup when it's configured. Without it, which only happens when
+/// SMS is being logged rather than sent, the number is taken as typed.
+pub async fn lookup_phone_number(
+    config: Option<&TwilioConfig>,
+    input_number: &str,
+) -> Result<VerifiedPhoneNumber> {
+    match config {
+        Some(config) => find_verified_phone_numbers(config, input_number).await,
+        None => Ok(VerifiedPhoneNumber {
+            calling_country_code: String::new(),
+            country_code: String::new(),
+            national_format: input_number.trim().to_string(),
+            phone_number: input_number.trim().to_string(),
+            url: String::new(),
+            valid: true,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn example_params() -> Vec<(String, String)> {
+        [
+            ("CallSid", "CA1234567890ABCDE"),
+            ("Caller", "+12349013030"),
+            ("Digits", "1234"),
+            ("From", "+12349013030"),
+            ("To", "+18005551212"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+    }
+
+    // The worked example from Twilio's webhook security docs
+    #[test]
+    fn validates_twilio_signature() {
+        let url = "https://mycompany.com/myapp.php?foo=1&bar=2";
+        assert!(validate_signature(
+            "12345",
+            url,
+            &example_params(),
+            "0/KCTR6DLpKmkAf8muzZqo1nDgQ="
+        ));
+
+        let mut params = example_params();
+        params.reverse();
+        assert!(validate_signature(
+            "12345",
+            url,
+            &params,
+            "0/KCTR6DLpKmkAf8muzZqo1nDgQ="
+        ));
+    }
+
+    #[test]
+    fn rejects_bad_twilio_signature() {
+        let url = "https://mycompany.com/myapp.php?foo=1&bar=2";
+        assert!(!validate_signature(
+            "wrong",
+            url,
+            &example_params(),
+            "0/KCTR6DLpKmkAf8muzZqo1nDgQ="
+        ));
+        assert!(!validate_signature(
+            "12345",
+            url,
+            &example_params(),
+            "not base64!"
+        ));
+        assert!(!validate_signature(
+            "12345",
+            url,
+            &[],
+            "0/KCTR6DLpKmkAf8muzZqo1nDgQ="
+        ));
+    }
+
+    #[tokio::test]
+    async fn talks_to_configured_base_urls() {
+        let twilio = fake::FakeTwilio::start().await.unwrap();
+        let client = reqwest::Client::new();
+        let config = twilio.config(client.clone(), AppView::new(client));
+        twilio.add_number("5555550100", "+15555550100", "(555) 555-0100");
+
+        let number = find_verified_phone_numbers(&config, "5555550100")
+            .await
+            .unwrap();
+        assert!(number.valid);
+        assert_eq!(number.phone_number, "+15555550100");
+        assert!(
+            !find_verified_phone_numbers(&config, "123")
+                .await
+                .unwrap()
+                .valid
+        );
+
+        send_sms(&config, &number.phone_number, "hello")
+            .await
+            .unwrap();
+        assert_eq!(
+            twilio.messages(),
+            vec![fake::SentMessage {
+                to: "+15555550100".to_string(),
+                from: config.phone_number.clone(),
+                body: "hello".to_string(),
+            }]
+        );
+
+        let wrong_token = TwilioConfig {
+            auth_token: "wrong".to_string(),
+            ..config
+        };
+        assert!(send_sms(&wrong_token, "+15555550100", "hello")
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn verification_codes_are_six_digits() {
+        for _ in 0..100 {
+            let code = generate_verification_code();
+            assert_eq!(code.len(), 6);
+            assert!(code.chars().all(|c| c.is_ascii_digit()));
+        }
+    }
+
+    #[test]
+    fn verification_codes_match_exactly() {
+        assert!(verification_code_matches(Some("012345"), " 012345\n"));
+        assert!(!verification_code_matches(Some("012345"), "012346"));
+        assert!(!verification_code_matches(Some("012345"), "01234"));
+        assert!(!verification_code_matches(None, ""));
+    }
+}
@@ -0,0 +1,185 @@
+//! A stand-in for the parts of Twilio we call, served locally so signups and
+//! notifications can be tested end to end without sending real texts.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    extract::{Path, State},
+    http::{header::AUTHORIZATION, HeaderMap, StatusCode},
+    routing::{get, post},
+    Form, Json, Router,
+};
+use cja::Result;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::task::JoinHandle;
+
+use crate::atproto::AppView;
+
+use super::{TwilioConfig, VerifiedPhoneNumber};
+
+const ACCOUNT_SID: &str = "ACfake";
+const AUTH_TOKEN: &str = "fake-auth-token";
+const PHONE_NUMBER: &str = "+15555550000";
+
+/// A text the app asked Twilio to send
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SentMessage {
+    pub to: String,
+    pub from: String,
+    pub body: String,
+}
+
+#[derive(Default)]
+struct Recorded {
+    messages: Vec<SentMessage>,
+    lookups: HashMap<String, VerifiedPhoneNumber>,
+}
+
+type Shared = Arc<Mutex<Recorded>>;
+
+/// Serves the Messages and Lookup APIs until dropped. Every message is
+/// recorded, and Lookup only knows the numbers added with
+/// [`FakeTwilio::add_number`], anything else comes back invalid.
+pub struct FakeTwilio {
+    pub local_addr: SocketAddr,
+    recorded: Shared,
+    handle: JoinHandle<()>,
+}
+
+impl Drop for FakeTwilio {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+impl FakeTwilio {
+    pub async fn start() -> Result<Self> {
+        let recorded = Shared::default();
+        let app = Router::new()
+            .route(
+                "/2010-04-01/Accounts/:account_sid/Messages.json",
+                post(create_message),
+            )
+            .route("/v2/PhoneNumbers/:number", get(lookup))
+            .with_state(recorded.clone());
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let local_addr = listener.local_addr()?;
+        let handle = tokio::spawn(async move {
+            let _ = axum::serve(listener, app).await;
+        });
+
+        Ok(Self {
+            local_addr,
+            recorded,
+            handle,
+        })
+    }
+
+    /// A config with both APIs pointed at this fake
+    pub fn config(&self, client: reqwest::Client, appview: AppView) -> TwilioConfig {
+        let base_url = format!("http://{}", self.local_addr);
+        TwilioConfig {
+            account_sid: ACCOUNT_SID.to_string(),
+            auth_token: AUTH_TOKEN.to_string(),
+            phone_number: PHONE_NUMBER.to_string(),
+            api_base_url: base_url.clone(),
+            lookup_base_url: base_url,
+            client,
+            appview,
+        }
+    }
+
+    /// Have Lookup resolve `input`, exactly as it'll appear in the request
+    /// path, to a valid US number
+    pub fn add_number(&self, input: &str, e164: &str, national_format: &str) {
+        self.recorded.lock().unwrap().lookups.insert(
+            input.to_string(),
+            VerifiedPhoneNumber {
+                calling_country_code: "1".to_string(),
+                country_code: "US".to_string(),
+                national_format: national_format.to_string(),
+                phone_number: e164.to_string(),
+                url: format!("https://lookups.twilio.com/v2/PhoneNumbers/{e164}"),
+                valid: true,
+            },
+        );
+    }
+
+    /// Every message sent so far, oldest first
+    pub fn messages(&self) -> Vec<SentMessage> {
+        self.recorded.lock().unwrap().messages.clone()
+    }
+}
+
+fn authorized(headers: &HeaderMap) -> bool {
+    use base64::Engine as _;
+
+    let expected =
+        base64::engine::general_purpose::STANDARD.encode(format!("{ACCOUNT_SID}:{AUTH_TOKEN}"));
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Basic "))
+        == Some(expected.as_str())
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct MessageForm {
+    to: String,
+    from: String,
+    body: String,
+}
+
+async fn create_message(
+    State(recorded): State<Shared>,
+    Path(account_sid): Path<String>,
+    headers: HeaderMap,
+    Form(form): Form<MessageForm>,
+) -> Result<(StatusCode, Json<Value>), StatusCode> {
+    if account_sid != ACCOUNT_SID || !authorized(&headers) {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    let response = json!({
+        "sid": format!("SM{}", uuid::Uuid::new_v4().simple()),
+        "account_sid": account_sid,
+        "to": form.to,
+        "from": form.from,
+        "body": form.body,
+        "status": "queued",
+    });
+    recorded.lock().unwrap().messages.push(SentMessage {
+        to: form.to,
+        from: form.from,
+        body: form.body,
+    });
+
+    Ok((StatusCode::CREATED, Json(response)))
+}
+
+async fn lookup(
+    State(recorded): State<Shared>,
+    Path(number): Path<String>,
+    headers: HeaderMap,
+) -> Result<Json<VerifiedPhoneNumber>, StatusCode> {
+    if !authorized(&headers) {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    let found = recorded.lock().unwrap().lookups.get(&number).cloned();
+    Ok(Json(found.unwrap_or_else(|| VerifiedPhoneNumber {
+        calling_country_code: String::new(),
+        country_code: String::new(),
+        national_format: number.clone(),
+        phone_number: number,
+        url: String::new(),
+        valid: false,
+    })))
+}
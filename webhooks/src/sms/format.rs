@@ -0,0 +1,338 @@
+//! Posts as texts. Every text says who posted and links back to the post, and
+//! long posts are cut down so the whole thing stays within a sensible number
+//! of SMS segments.
+
+use crate::notifiers::{LinkKind, PostMessage};
+
+/// Twilio rejects bodies longer than this
+const MAX_CHARS: usize = 1600;
+
+/// Twilio recommends staying at or under 10 segments. Longer messages are
+/// less reliably delivered, and every segment is billed.
+const MAX_SEGMENTS: usize = 10;
+
+/// When cutting a post short, back up to the end of the last whole word
+/// unless that would throw away more than this many bytes
+const MAX_PARTIAL_WORD: usize = 20;
+
+/// Link card URLs longer than this are shortened
+const MAX_URL_CHARS: usize = 200;
+
+/// Every character in the GSM 03.38 default alphabet, which costs one septet
+const GSM7_BASIC: &str = "@£$¥èéùìòÇ\nØø\rÅåΔ_ΦΓΛΩΠΨΣΘΞÆæßÉ !\"#¤%&'()*+,-./0123456789:;<=>?\
+    ¡ABCDEFGHIJKLMNOPQRSTUVWXYZÄÖÑÜ§¿abcdefghijklmnopqrstuvwxyzäöñüà";
+
+/// Characters from the extension table, which cost two septets since they're
+/// sent behind an escape
+const GSM7_EXTENDED: &str = "\u{c}^{}\\[~]|€";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    /// 160 septets in a single segment, 153 per segment once split
+    Gsm7,
+    /// Anything outside GSM-7 switches the whole message to UCS-2, which fits
+    /// 70 UTF-16 code units in a single segment and 67 once split
+    Ucs2,
+}
+
+/// How a body will be encoded, and how many segments it'll be sent as
+pub fn segments(body: &str) -> (Encoding, usize) {
+    let septets: Option<Vec<usize>> = body
+        .chars()
+        .map(|c| {
+            if GSM7_BASIC.contains(c) {
+                Some(1)
+            } else if GSM7_EXTENDED.contains(c) {
+                Some(2)
+            } else {
+                None
+            }
+        })
+        .collect();
+
+    match septets {
+        Some(septets) => (Encoding::Gsm7, count_segments(&septets, 160, 153)),
+        None => {
+            let units: Vec<usize> = body.chars().map(char::len_utf16).collect();
+            (Encoding::Ucs2, count_segments(&units, 70, 67))
+        }
+    }
+}
+
+/// Characters are never split across segments, so an escaped GSM-7 character
+/// or a surrogate pair that doesn't fit starts the next one
+fn count_segments(sizes: &[usize], single: usize, split: usize) -> usize {
+    if sizes.iter().sum::<usize>() <= single {
+        return 1;
+    }
+
+    let mut segments = 1;
+    let mut used = 0;
+    for &size in sizes {
+        if used + size > split {
+            segments += 1;
+            used = 0;
+        }
+        used += size;
+    }
+    segments
+}
+
+fn fits(body: &str) -> bool {
+    body.chars().count() <= MAX_CHARS && segments(body).1 <= MAX_SEGMENTS
+}
+
+/// `prefix`, `text` and `suffix` together, with as much of `text` dropped as
+/// it takes to fit
+fn fit(prefix: &str, text: &str, suffix: &str) -> String {
+    let full = format!("{prefix}{text}{suffix}");
+    if fits(&full) || text.is_empty() {
+        return full;
+    }
+
+    // `cuts[n]` is where the text ends if we keep `n` characters
+    let cuts: Vec<usize> = text.char_indices().map(|(i, _)| i).collect();
+    let truncated = |n: usize| {
+        let (head, rest) = text.split_at(cuts[n]);
+        let head = if rest.starts_with(char::is_whitespace) {
+            head.trim_end()
+        } else {
+            drop_partial_word(head)
+        };
+        // Not `…`, that alone would switch the whole text to UCS-2
+        format!("{prefix}{head}...{suffix}")
+    };
+
+    // Keeping fewer characters never makes a text longer, so search for the
+    // most that still fit
+    let (mut lo, mut hi) = (0, cuts.len() - 1);
+    while lo < hi {
+        let mid = (lo + hi).div_ceil(2);
+        if fits(&truncated(mid)) {
+            lo = mid;
+        } else {
+            hi = mid - 1;
+        }
+    }
+    truncated(lo)
+}
+
+fn drop_partial_word(text: &str) -> &str {
+    match text.rfind(char::is_whitespace) {
+        Some(i) if text.len() - i <= MAX_PARTIAL_WORD => text[..i].trim_end(),
+        _ => text,
+    }
+}
+
+/// Cut a plain body, like a notice or a digest, down to fit
+pub fn fit_body(body: &str) -> String {
+    fit("", body, "")
+}
+
+/// The post's text with link facets swapped for the URLs they point at,
+/// since the text Bluesky shows is often shortened
+fn expanded_text(post: &PostMessage) -> String {
+    post.segments()
+        .into_iter()
+        .map(|(text, link)| match link {
+            Some(link) if link.kind == LinkKind::Link => link.url.as_str(),
+            _ => text,
+        })
+        .collect()
+}
+
+/// Link cards can point anywhere, and only the text gets cut to fit, so a
+/// long URL is cut here instead
+fn shorten_url(url: &str) -> String {
+    if url.chars().count() <= MAX_URL_CHARS {
+        return url.to_string();
+    }
+    let head: String = url.chars().take(MAX_URL_CHARS - 3).collect();
+    format!("{head}...")
+}
+
+/// Like `[2 images] [quoting @bob.test]`, or nothing if the post is just text
+fn embed_summary(post: &PostMessage, text: &str, quoted_handle: Option<&str>) -> String {
+    let mut parts = vec![];
+    match post.images.len() {
+        0 => {}
+        1 => parts.push("1 image".to_string()),
+        n => parts.push(format!("{n} images")),
+    }
+    if post.video {
+        parts.push("video".to_string());
+    }
+    if let Some(uri) = post.external_uri.as_deref() {
+        if !text.contains(uri) {
+            parts.push(format!("link: {}", shorten_url(uri)));
+        }
+    }
+    match (quoted_handle, &post.quote_uri) {
+        (Some(handle), _) => parts.push(format!("quoting @{handle}")),
+        (None, Some(_)) => parts.push("quoting a post".to_string()),
+        (None, None) => {}
+    }
+
+    parts
+        .iter()
+        .map(|part| format!("[{part}]"))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// `@handle: text`, then what's attached, then the link to the post. The
+/// reason it was sent goes on its own line first, if there is one. Only the
+/// text is ever cut short.
+pub fn format_post(post: &PostMessage, quoted_handle: Option<&str>) -> String {
+    let text = expanded_text(post);
+    let summary = embed_summary(post, &text, quoted_handle);
+
+    let mut prefix = String::new();
+    if let Some(reason) = &post.reason {
+        prefix.push_str(reason);
+        prefix.push('\n');
+    }
+    prefix.push_str(&format!("@{}: ", post.handle));
+    let mut suffix = String::new();
+    if !summary.is_empty() {
+        suffix.push('\n');
+        suffix.push_str(&summary);
+    }
+    suffix.push_str(&format!(
+        "\nhttps://bsky.app/profile/{}/post/{}",
+        post.handle, post.rkey
+    ));
+
+    fit(&prefix, &text, &suffix)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn post(record: serde_json::Value) -> PostMessage {
+        PostMessage::new("alice.test", "did:plc:alice", "3kabc", &record)
+    }
+
+    #[test]
+    fn counts_gsm7_and_ucs2_segments() {
+        assert_eq!(segments(""), (Encoding::Gsm7, 1));
+        assert_eq!(segments(&"a".repeat(160)), (Encoding::Gsm7, 1));
+        assert_eq!(segments(&"a".repeat(161)), (Encoding::Gsm7, 2));
+        assert_eq!(segments(&"a".repeat(306)), (Encoding::Gsm7, 2));
+        // Extension characters take two septets
+        assert_eq!(segments(&"€".repeat(80)), (Encoding::Gsm7, 1));
+        assert_eq!(segments(&"€".repeat(81)), (Encoding::Gsm7, 2));
+        // and aren't split from their escape
+        assert_eq!(
+            segments(&format!("{}€{}", "a".repeat(152), "a".repeat(152))),
+            (Encoding::Gsm7, 3)
+        );
+
+        assert_eq!(segments(&"é".repeat(161)), (Encoding::Gsm7, 2));
+        assert_eq!(segments(&"ê".repeat(70)), (Encoding::Ucs2, 1));
+        assert_eq!(segments(&"ê".repeat(71)), (Encoding::Ucs2, 2));
+        // Emoji are a surrogate pair each
+        assert_eq!(segments(&"🦋".repeat(35)), (Encoding::Ucs2, 1));
+        assert_eq!(segments(&"🦋".repeat(36)), (Encoding::Ucs2, 2));
+    }
+
+    #[test]
+    fn formats_author_links_and_embeds() {
+        let post = post(json!({
+            "text": "New blog post: example.com/2024/11/a-very... via @bob.test",
+            "facets": [
+                { "index": { "byteStart": 15, "byteEnd": 44 }, "features": [{ "$type": "app.bsky.richtext.facet#link", "uri": "https://example.com/2024/11/a-very-long-slug" }] },
+                { "index": { "byteStart": 49, "byteEnd": 58 }, "features": [{ "$type": "app.bsky.richtext.facet#mention", "did": "did:plc:bob" }] }
+            ],
+            "embed": {
+                "$type": "app.bsky.embed.recordWithMedia",
+                "record": { "record": { "uri": "at://did:plc:carol/app.bsky.feed.post/3kxyz", "cid": "" } },
+                "media": {
+                    "$type": "app.bsky.embed.images",
+                    "images": [
+                        { "alt": "", "image": { "ref": { "$link": "bafkcat" } } },
+                        { "alt": "", "image": { "ref": { "$link": "bafkdog" } } }
+                    ]
+                }
+            }
+        }));
+
+        assert_eq!(
+            format_post(&post, Some("carol.test")),
+            "@alice.test: New blog post: https://example.com/2024/11/a-very-long-slug via @bob.test\n\
+            [2 images] [quoting @carol.test]\n\
+            https://bsky.app/profile/alice.test/post/3kabc"
+        );
+        assert!(format_post(&post, None).contains("[2 images] [quoting a post]\n"));
+
+        let plain = self::post(json!({ "text": "hello world" }));
+        assert_eq!(
+            format_post(&plain, None),
+            "@alice.test: hello world\nhttps://bsky.app/profile/alice.test/post/3kabc"
+        );
+        assert_eq!(
+            format_post(&plain.with_reason("New reply to @bob.test"), None),
+            "New reply to @bob.test\n@alice.test: hello world\nhttps://bsky.app/profile/alice.test/post/3kabc"
+        );
+    }
+
+    #[test]
+    fn link_cards_are_only_mentioned_once() {
+        let card = |text: &str| {
+            post(json!({
+                "text": text,
+                "embed": { "$type": "app.bsky.embed.external", "external": { "uri": "https://example.com/" } }
+            }))
+        };
+
+        assert!(format_post(&card("look"), None).contains("\n[link: https://example.com/]\n"));
+        assert!(!format_post(&card("look https://example.com/"), None).contains("[link"));
+
+        // The text is all that gets cut to fit, so a huge card URL is
+        // shortened on its own
+        let long = post(json!({
+            "text": "look",
+            "embed": { "$type": "app.bsky.embed.external", "external": { "uri": format!("https://example.com/{}", "a".repeat(3000)) } }
+        }));
+        let body = format_post(&long, None);
+        assert!(fits(&body));
+        assert!(body.contains(&format!(
+            "[link: https://example.com/{}...]",
+            "a".repeat(177)
+        )));
+    }
+
+    #[test]
+    fn long_posts_are_cut_at_a_word() {
+        let words = "lorem ipsum dolor ".repeat(200);
+        let text = post(json!({ "text": words }));
+        let body = format_post(&text, None);
+
+        assert!(segments(&body).1 <= MAX_SEGMENTS);
+        assert!(body.ends_with("...\nhttps://bsky.app/profile/alice.test/post/3kabc"));
+        let kept = body
+            .strip_prefix("@alice.test: ")
+            .and_then(|body| body.split("...").next())
+            .unwrap();
+        assert!(words.starts_with(kept));
+        assert!(words[kept.len()..].starts_with(' '));
+        // Nearly all of the room is used
+        assert!(body.len() > 153 * (MAX_SEGMENTS - 1));
+
+        // One emoji switches to UCS-2, so much less of the text fits
+        let emoji = post(json!({ "text": format!("🦋 {words}") }));
+        let body = format_post(&emoji, None);
+        assert_eq!(segments(&body), (Encoding::Ucs2, MAX_SEGMENTS));
+    }
+
+    #[test]
+    fn fits_bodies_to_the_character_limit() {
+        assert_eq!(fit_body("short"), "short");
+        let long = fit_body(&"a".repeat(2000));
+        assert!(fits(&long));
+        assert!(long.ends_with("a..."));
+    }
+}
@@ -0,0 +1,695 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse as _, Redirect, Response},
+    Form,
+};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use maud::{html, Markup};
+use serde::Deserialize;
+use sha2::Sha256;
+use sqlx::types::Json;
+use tower_cookies::{Cookies, Key};
+
+use crate::{
+    filters::PostFilters,
+    sessions::{current_session, Session},
+    terms::{TermKind, MAX_TERMS_PER_PHONE},
+    AppState,
+};
+
+struct SubscriptionRow {
+    id: uuid::Uuid,
+    handle: String,
+    channel: String,
+    notify_deletes: bool,
+    notify_mentions: bool,
+    notify_replies: bool,
+    digest: bool,
+    confirmed_at: Option<DateTime<Utc>>,
+    paused_at: Option<DateTime<Utc>>,
+    last_fired_at: Option<DateTime<Utc>>,
+    filters: Json<PostFilters>,
+}
+
+impl SubscriptionRow {
+    fn status(&self) -> &'static str {
+        match (self.confirmed_at, self.paused_at) {
+            (None, _) => "Waiting for confirmation",
+            (Some(_), Some(_)) => "Paused",
+            (Some(_), None) => "Active",
+        }
+    }
+}
+
+async fn require_session(state: &AppState, cookies: &Cookies) -> Result<Session, Response> {
+    current_session(state, cookies)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response())?
+        .ok_or_else(|| Redirect::to("/login").into_response())
+}
+
+pub async fn list(State(state): State<AppState>, cookies: Cookies) -> Result<Response, Response> {
+    let session = require_session(&state, &cookies).await?;
+
+    let subscriptions = sqlx::query_as!(
+        SubscriptionRow,
+        r#"SELECT id, handle, channel, notify_deletes, notify_mentions, notify_replies, digest, confirmed_at, paused_at, last_fired_at, filters as "filters: Json<PostFilters>"
+        FROM SmsHandleSubscriptions
+        WHERE phone_number = $1 OR owner_did = $2
+        ORDER BY handle"#,
+        session.phone_number,
+        session.did,
+    )
+    .fetch_all(&state.db)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response())?;
+
+    let webhooks = sqlx::query!(
+        "SELECT id, handle, url FROM WebhookSubscriptions WHERE owner_did = $1 ORDER BY handle",
+        session.did,
+    )
+    .fetch_all(&state.db)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response())?;
+
+    let terms = sqlx::query!(
+        "SELECT id, kind, term, paused_at IS NOT NULL AS paused FROM TermSubscriptions
+        WHERE phone_number = $1 ORDER BY kind, term",
+        session.phone_number,
+    )
+    .fetch_all(&state.db)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response())?;
+
+    let owner = session
+        .phone_number
+        .as_deref()
+        .or(session.did.as_deref())
+        .unwrap_or_default();
+
+    Ok(html! {
+        h1 { "Subscriptions for " (owner) }
+        @if subscriptions.is_empty() {
+            p { "You aren't subscribed to anyone yet." }
+        } @else {
+            table {
+                thead {
+                    tr {
+                        th { "Handle" }
+                        th { "Status" }
+                        th { "Options" }
+                        th { "Last Fired" }
+                        th {}
+                    }
+                }
+                tbody {
+                    @for sub in &subscriptions {
+                        tr {
+                            td { "@" (sub.handle) }
+                            td { (sub.status()) }
+                            td {
+                                @match sub.channel.as_str() {
+                                    "email" => { "Email, " }
+                                    "discord" => { "Discord, " }
+                                    "slack" => { "Slack, " }
+                                    _ => { "Text, " }
+                                }
+                                @if sub.digest { "daily digest" } @else { "every post" }
+                                @if sub.notify_deletes { ", deletes" }
+                                @if sub.notify_mentions { ", mentions" }
+                                @if sub.notify_replies { ", replies" }
+                                @if !sub.filters.is_default() { ", filtered" }
+                            }
+                            td {
+                                @match sub.last_fired_at {
+                                    Some(at) => { (at.format("%Y-%m-%d %H:%M UTC")) }
+                                    None => { "Never" }
+                                }
+                            }
+                            td {
+                                @if sub.confirmed_at.is_some() {
+                                    @if sub.paused_at.is_some() {
+                                        form action={ "/subscriptions/" (sub.id) "/resume" } method="post" {
+                                            input type="submit" value="Resume" {}
+                                        }
+                                    } @else {
+                                        form action={ "/subscriptions/" (sub.id) "/pause" } method="post" {
+                                            input type="submit" value="Pause" {}
+                                        }
+                                    }
+                                }
+                                a href={ "/subscriptions/" (sub.id) "/filters" } { "Filters" }
+                                form action={ "/subscriptions/" (sub.id) "/delete" } method="post" {
+                                    input type="submit" value="Delete" {}
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        @if !webhooks.is_empty() {
+            h2 { "Webhooks" }
+            table {
+                tbody {
+                    @for webhook in &webhooks {
+                        tr {
+                            td { "@" (webhook.handle) }
+                            td { (webhook.url) }
+                            td {
+                                a href={ "/webhook_subscriptions/" (webhook.id) "/filters" } { "Filters" }
+                                form action={ "/webhook_subscriptions/" (webhook.id) "/delete" } method="post" {
+                                    input type="submit" value="Delete" {}
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        // Term subscriptions text a phone number, so they need a session that
+        // has proven it owns one
+        @if session.phone_number.is_some() {
+            h2 { "Keywords, hashtags and domains" }
+            p { "Get a text whenever anyone on Bluesky posts about these." }
+            @if !terms.is_empty() {
+                table {
+                    tbody {
+                        @for term in &terms {
+                            tr {
+                                td { (term.kind) }
+                                td { (term.term) }
+                                td {
+                                    @if term.paused == Some(true) { "Paused" } @else { "Active" }
+                                }
+                                td {
+                                    form action={ "/term_subscriptions/" (term.id) "/delete" } method="post" {
+                                        input type="submit" value="Delete" {}
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            form action="/term_subscriptions" method="post" {
+                select name="kind" {
+                    option value=(TermKind::Keyword.as_str()) { "Keyword" }
+                    option value=(TermKind::Hashtag.as_str()) { "Hashtag" }
+                    option value=(TermKind::Domain.as_str()) { "Domain" }
+                }
+                input type="text" name="term" placeholder="rust, #atproto or example.com" {}
+                input type="submit" value="Subscribe" {}
+            }
+        }
+        a href="/" { "Add a subscription" }
+        form action="/logout" method="post" {
+            input type="submit" value="Log Out" {}
+        }
+    }
+    .into_response())
+}
+
+pub async fn pause(
+    State(state): State<AppState>,
+    cookies: Cookies,
+    Path(id): Path<uuid::Uuid>,
+) -> Result<Response, Response> {
+    let session = require_session(&state, &cookies).await?;
+
+    // Paused here it stays paused through a START, even if STOP got to it first
+    sqlx::query!(
+        "UPDATE SmsHandleSubscriptions
+        SET paused_at = COALESCE(paused_at, NOW()), paused_by_stop = FALSE, updated_at = NOW()
+        WHERE id = $1 AND (phone_number = $2 OR owner_did = $3)",
+        id,
+        session.phone_number,
+        session.did,
+    )
+    .execute(&state.db)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response())?;
+
+    Ok(Redirect::to("/subscriptions").into_response())
+}
+
+pub async fn resume(
+    State(state): State<AppState>,
+    cookies: Cookies,
+    Path(id): Path<uuid::Uuid>,
+) -> Result<Response, Response> {
+    let session = require_session(&state, &cookies).await?;
+
+    sqlx::query!(
+        "UPDATE SmsHandleSubscriptions SET paused_at = NULL, paused_by_stop = FALSE, updated_at = NOW()
+        WHERE id = $1 AND (phone_number = $2 OR owner_did = $3)",
+        id,
+        session.phone_number,
+        session.did,
+    )
+    .execute(&state.db)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response())?;
+
+    Ok(Redirect::to("/subscriptions").into_response())
+}
+
+pub async fn delete(
+    State(state): State<AppState>,
+    cookies: Cookies,
+    Path(id): Path<uuid::Uuid>,
+) -> Result<Response, Response> {
+    let session = require_session(&state, &cookies).await?;
+
+    sqlx::query!(
+        "DELETE FROM SmsHandleSubscriptions WHERE id = $1 AND (phone_number = $2 OR owner_did = $3)",
+        id,
+        session.phone_number,
+        session.did,
+    )
+    .execute(&state.db)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response())?;
+
+    Ok(Redirect::to("/subscriptions").into_response())
+}
+
+/// A link that deletes one subscription without logging in, for the footer
+/// and `List-Unsubscribe` header of every email. It's signed with the cookie
+/// key so nobody can unsubscribe someone else by guessing ids.
+pub fn unsubscribe_url(state: &AppState, id: uuid::Uuid) -> String {
+    format!(
+        "{}/unsubscribe/{id}?sig={}",
+        state.base_url,
+        hex::encode(
+            unsubscribe_mac(&state.cookie_key.0, id)
+                .finalize()
+                .into_bytes()
+        )
+    )
+}
+
+fn unsubscribe_mac(key: &Key, id: uuid::Uuid) -> Hmac<Sha256> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(key.signing()).expect("HMAC accepts keys of any size");
+    mac.update(b"unsubscribe:");
+    mac.update(id.as_bytes());
+    mac
+}
+
+fn verify_unsubscribe(key: &Key, id: uuid::Uuid, sig: &str) -> bool {
+    let Ok(sig) = hex::decode(sig) else {
+        return false;
+    };
+    unsubscribe_mac(key, id).verify_slice(&sig).is_ok()
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct UnsubscribeQuery {
+    sig: String,
+}
+
+/// Only asks, mail scanners follow links in emails and shouldn't be able to
+/// unsubscribe anyone by doing so
+pub async fn unsubscribe_page(
+    State(state): State<AppState>,
+    Path(id): Path<uuid::Uuid>,
+    Query(query): Query<UnsubscribeQuery>,
+) -> Result<Response, Response> {
+    if !verify_unsubscribe(&state.cookie_key.0, id, &query.sig) {
+        return Err((StatusCode::NOT_FOUND, "That unsubscribe link isn't valid").into_response());
+    }
+
+    Ok(html! {
+        form action={ "/unsubscribe/" (id) "?sig=" (query.sig) } method="post" {
+            input type="submit" value="Unsubscribe" {}
+        }
+    }
+    .into_response())
+}
+
+/// Also what mail clients call for one-click unsubscribes (RFC 8058)
+pub async fn unsubscribe(
+    State(state): State<AppState>,
+    Path(id): Path<uuid::Uuid>,
+    Query(query): Query<UnsubscribeQuery>,
+) -> Result<Response, Response> {
+    if !verify_unsubscribe(&state.cookie_key.0, id, &query.sig) {
+        return Err((StatusCode::NOT_FOUND, "That unsubscribe link isn't valid").into_response());
+    }
+
+    sqlx::query!("DELETE FROM SmsHandleSubscriptions WHERE id = $1", id)
+        .execute(&state.db)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response())?;
+
+    Ok("You've been unsubscribed".into_response())
+}
+
+pub async fn delete_webhook(
+    State(state): State<AppState>,
+    cookies: Cookies,
+    Path(id): Path<uuid::Uuid>,
+) -> Result<Response, Response> {
+    let session = require_session(&state, &cookies).await?;
+
+    sqlx::query!(
+        "DELETE FROM WebhookSubscriptions WHERE id = $1 AND owner_did = $2",
+        id,
+        session.did,
+    )
+    .execute(&state.db)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response())?;
+
+    Ok(Redirect::to("/subscriptions").into_response())
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct TermSubscriptionForm {
+    kind: String,
+    term: String,
+}
+
+pub async fn create_term(
+    State(state): State<AppState>,
+    cookies: Cookies,
+    Form(form): Form<TermSubscriptionForm>,
+) -> Result<Response, Response> {
+    let session = require_session(&state, &cookies).await?;
+    let Some(phone_number) = session.phone_number else {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "Log in with your phone number to subscribe to keywords",
+        )
+            .into_response());
+    };
+
+    let kind = TermKind::parse(&form.kind)
+        .ok_or_else(|| (StatusCode::BAD_REQUEST, "Unknown subscription kind").into_response())?;
+    let term = kind
+        .normalize(&form.term)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e).into_response())?;
+
+    let existing = sqlx::query_scalar!(
+        r#"SELECT COUNT(*) AS "count!" FROM TermSubscriptions WHERE phone_number = $1"#,
+        phone_number,
+    )
+    .fetch_one(&state.db)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response())?;
+    if existing >= MAX_TERMS_PER_PHONE {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("You can follow at most {MAX_TERMS_PER_PHONE} keywords, hashtags and domains"),
+        )
+            .into_response());
+    }
+
+    sqlx::query!(
+        "INSERT INTO TermSubscriptions (phone_number, kind, term) VALUES ($1, $2, $3)
+        ON CONFLICT (phone_number, kind, term) DO NOTHING",
+        phone_number,
+        kind.as_str(),
+        term,
+    )
+    .execute(&state.db)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response())?;
+
+    Ok(Redirect::to("/subscriptions").into_response())
+}
+
+pub async fn delete_term(
+    State(state): State<AppState>,
+    cookies: Cookies,
+    Path(id): Path<uuid::Uuid>,
+) -> Result<Response, Response> {
+    let session = require_session(&state, &cookies).await?;
+
+    sqlx::query!(
+        "DELETE FROM TermSubscriptions WHERE id = $1 AND phone_number = $2",
+        id,
+        session.phone_number,
+    )
+    .execute(&state.db)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response())?;
+
+    Ok(Redirect::to("/subscriptions").into_response())
+}
+
+/// Filters as they come off the HTML form. Lists are comma separated and
+/// blank fields mean "not set".
+#[derive(Debug, Deserialize, Clone)]
+pub struct FiltersForm {
+    #[serde(default)]
+    exclude_replies: bool,
+    #[serde(default)]
+    top_level_only: bool,
+    #[serde(default)]
+    media_only: bool,
+    #[serde(default)]
+    require_keywords: String,
+    #[serde(default)]
+    exclude_keywords: String,
+    #[serde(default)]
+    require_regex: String,
+    #[serde(default)]
+    exclude_regex: String,
+    #[serde(default)]
+    langs: String,
+}
+
+fn split_list(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(ToString::to_string)
+        .collect()
+}
+
+fn non_empty(value: &str) -> Option<String> {
+    let value = value.trim();
+    (!value.is_empty()).then(|| value.to_string())
+}
+
+impl FiltersForm {
+    /// Errors with a message for the user if the filters aren't valid
+    fn into_filters(self) -> Result<PostFilters, String> {
+        let filters = PostFilters {
+            exclude_replies: self.exclude_replies,
+            top_level_only: self.top_level_only,
+            media_only: self.media_only,
+            require_keywords: split_list(&self.require_keywords),
+            exclude_keywords: split_list(&self.exclude_keywords),
+            require_regex: non_empty(&self.require_regex),
+            exclude_regex: non_empty(&self.exclude_regex),
+            langs: split_list(&self.langs),
+        };
+        filters.validate()?;
+
+        Ok(filters)
+    }
+}
+
+fn filters_form(action: &str, handle: &str, filters: &PostFilters) -> Markup {
+    html! {
+        h1 { "Filters for @" (handle) }
+        form action=(action) method="post" {
+            label {
+                input type="checkbox" name="exclude_replies" value="true" checked[filters.exclude_replies] {}
+                "Skip replies"
+            }
+            label {
+                input type="checkbox" name="top_level_only" value="true" checked[filters.top_level_only] {}
+                "Only top level posts, no replies or quotes"
+            }
+            label {
+                input type="checkbox" name="media_only" value="true" checked[filters.media_only] {}
+                "Only posts with images or video"
+            }
+            label {
+                "Must mention one of "
+                input type="text" name="require_keywords" placeholder="rust, tokio" value=(filters.require_keywords.join(", ")) {}
+            }
+            label {
+                "Must not mention any of "
+                input type="text" name="exclude_keywords" value=(filters.exclude_keywords.join(", ")) {}
+            }
+            label {
+                "Must match regex "
+                input type="text" name="require_regex" value=(filters.require_regex.as_deref().unwrap_or_default()) {}
+            }
+            label {
+                "Must not match regex "
+                input type="text" name="exclude_regex" value=(filters.exclude_regex.as_deref().unwrap_or_default()) {}
+            }
+            label {
+                "Languages "
+                input type="text" name="langs" placeholder="en, pt-BR" value=(filters.langs.join(", ")) {}
+            }
+            input type="submit" value="Save Filters" {}
+        }
+        a href="/subscriptions" { "Back" }
+    }
+}
+
+pub async fn edit_filters(
+    State(state): State<AppState>,
+    cookies: Cookies,
+    Path(id): Path<uuid::Uuid>,
+) -> Result<Response, Response> {
+    let session = require_session(&state, &cookies).await?;
+
+    let sub = sqlx::query!(
+        r#"SELECT handle, filters as "filters: Json<PostFilters>" FROM SmsHandleSubscriptions
+        WHERE id = $1 AND (phone_number = $2 OR owner_did = $3)"#,
+        id,
+        session.phone_number,
+        session.did,
+    )
+    .fetch_optional(&state.db)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response())?
+    .ok_or_else(|| StatusCode::NOT_FOUND.into_response())?;
+
+    let action = format!("/subscriptions/{id}/filters");
+    Ok(filters_form(&action, &sub.handle, &sub.filters).into_response())
+}
+
+pub async fn save_filters(
+    State(state): State<AppState>,
+    cookies: Cookies,
+    Path(id): Path<uuid::Uuid>,
+    Form(form): Form<FiltersForm>,
+) -> Result<Response, Response> {
+    let session = require_session(&state, &cookies).await?;
+    let filters = form
+        .into_filters()
+        .map_err(|e| (StatusCode::BAD_REQUEST, e).into_response())?;
+
+    sqlx::query!(
+        "UPDATE SmsHandleSubscriptions SET filters = $4, updated_at = NOW()
+        WHERE id = $1 AND (phone_number = $2 OR owner_did = $3)",
+        id,
+        session.phone_number,
+        session.did,
+        Json(filters) as _,
+    )
+    .execute(&state.db)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response())?;
+
+    Ok(Redirect::to("/subscriptions").into_response())
+}
+
+pub async fn edit_webhook_filters(
+    State(state): State<AppState>,
+    cookies: Cookies,
+    Path(id): Path<uuid::Uuid>,
+) -> Result<Response, Response> {
+    let session = require_session(&state, &cookies).await?;
+
+    let webhook = sqlx::query!(
+        r#"SELECT handle, filters as "filters: Json<PostFilters>" FROM WebhookSubscriptions
+        WHERE id = $1 AND owner_did = $2"#,
+        id,
+        session.did,
+    )
+    .fetch_optional(&state.db)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response())?
+    .ok_or_else(|| StatusCode::NOT_FOUND.into_response())?;
+
+    let action = format!("/webhook_subscriptions/{id}/filters");
+    Ok(filters_form(&action, &webhook.handle, &webhook.filters).into_response())
+}
+
+pub async fn save_webhook_filters(
+    State(state): State<AppState>,
+    cookies: Cookies,
+    Path(id): Path<uuid::Uuid>,
+    Form(form): Form<FiltersForm>,
+) -> Result<Response, Response> {
+    let session = require_session(&state, &cookies).await?;
+    let filters = form
+        .into_filters()
+        .map_err(|e| (StatusCode::BAD_REQUEST, e).into_response())?;
+
+    sqlx::query!(
+        "UPDATE WebhookSubscriptions SET filters = $3, updated_at = NOW()
+        WHERE id = $1 AND owner_did = $2",
+        id,
+        session.did,
+        Json(filters) as _,
+    )
+    .execute(&state.db)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response())?;
+
+    Ok(Redirect::to("/subscriptions").into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unsubscribe_links_are_signed() {
+        let key = Key::generate();
+        let id = uuid::Uuid::new_v4();
+        let sig = hex::encode(unsubscribe_mac(&key, id).finalize().into_bytes());
+
+        assert!(verify_unsubscribe(&key, id, &sig));
+        assert!(!verify_unsubscribe(&key, uuid::Uuid::new_v4(), &sig));
+        assert!(!verify_unsubscribe(&Key::generate(), id, &sig));
+        assert!(!verify_unsubscribe(&key, id, "not hex"));
+    }
+
+    fn row(confirmed: bool, paused: bool) -> SubscriptionRow {
+        SubscriptionRow {
+            id: uuid::Uuid::new_v4(),
+            handle: "alice.test".to_string(),
+            channel: "sms".to_string(),
+            notify_deletes: false,
+            notify_mentions: false,
+            notify_replies: false,
+            digest: false,
+            confirmed_at: confirmed.then(Utc::now),
+            paused_at: paused.then(Utc::now),
+            last_fired_at: None,
+            filters: Json(PostFilters::default()),
+        }
+    }
+
+    #[test]
+    fn status_reflects_confirmation_and_pause() {
+        assert_eq!(row(false, false).status(), "Waiting for confirmation");
+        assert_eq!(row(true, false).status(), "Active");
+        assert_eq!(row(true, true).status(), "Paused");
+    }
+
+    #[test]
+    fn filters_form_splits_lists() {
+        let form = FiltersForm {
+            exclude_replies: true,
+            top_level_only: false,
+            media_only: false,
+            require_keywords: " rust,, tokio ".to_string(),
+            exclude_keywords: String::new(),
+            require_regex: "  ".to_string(),
+            exclude_regex: "nsfw".to_string(),
+            langs: "en".to_string(),
+        };
+        let filters = form.into_filters().unwrap();
+
+        assert_eq!(filters.require_keywords, vec!["rust", "tokio"]);
+        assert!(filters.exclude_keywords.is_empty());
+        assert_eq!(filters.require_regex, None);
+        assert_eq!(filters.exclude_regex.as_deref(), Some("nsfw"));
+        assert_eq!(filters.langs, vec!["en"]);
+    }
+}
@@ -0,0 +1,523 @@
+use std::collections::{BTreeMap, HashMap};
+
+use aho_corasick::{AhoCorasick, MatchKind};
+use bsky_firehose::NewPost;
+use cja::Result;
+use sqlx::{types::Json, PgPool};
+
+use crate::{
+    digest,
+    filters::{is_whole_word, PostFacts},
+    jobs::{self, Destination},
+    notifiers::{Channel, Message, PostMessage},
+};
+
+/// Shortest keyword or hashtag we'll watch for, anything shorter matches far
+/// too much of the network
+pub const MIN_TERM_CHARS: usize = 3;
+
+/// Most term subscriptions a single phone number can have
+pub const MAX_TERMS_PER_PHONE: i64 = 20;
+
+/// Most matches listed in one batched text, the rest are just counted
+const MAX_BATCH_ENTRIES: usize = 5;
+
+/// Longest a post gets in a batched text before it's cut off
+const MAX_ENTRY_CHARS: usize = 80;
+
+/// What a [`TermSubscription`] is looking for in posts from anyone on the
+/// network
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TermKind {
+    /// A word or phrase anywhere in the text
+    Keyword,
+    /// A `#tag` facet
+    Hashtag,
+    /// A link to this domain or any of its subdomains
+    Domain,
+}
+
+impl TermKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TermKind::Keyword => "keyword",
+            TermKind::Hashtag => "hashtag",
+            TermKind::Domain => "domain",
+        }
+    }
+
+    pub fn parse(kind: &str) -> Option<Self> {
+        match kind {
+            "keyword" => Some(TermKind::Keyword),
+            "hashtag" => Some(TermKind::Hashtag),
+            "domain" => Some(TermKind::Domain),
+            _ => None,
+        }
+    }
+
+    /// Tidy up what someone typed into the form so it compares equal to what
+    /// we pull out of posts. Errors with a message for the user if what's left
+    /// is too broad to watch for.
+    pub fn normalize(self, term: &str) -> std::result::Result<String, String> {
+        let term = term.trim().to_lowercase();
+        let term = match self {
+            TermKind::Keyword => term,
+            TermKind::Hashtag => term.trim_start_matches('#').to_string(),
+            TermKind::Domain => {
+                let host = term
+                    .split_once("://")
+                    .map_or(term.as_str(), |(_, rest)| rest);
+                let host = host.split(['/', '?', '#']).next().unwrap_or_default();
+                host.trim_start_matches("www.").to_string()
+            }
+        };
+
+        if term.is_empty() {
+            return Err("Enter something to match on".to_string());
+        }
+        match self {
+            TermKind::Keyword | TermKind::Hashtag if term.chars().count() < MIN_TERM_CHARS => Err(
+                format!("Keywords and hashtags need at least {MIN_TERM_CHARS} characters"),
+            ),
+            TermKind::Domain if !term.trim_matches('.').contains('.') => Err(
+                "Enter a full domain like example.com, not just the part after the last dot"
+                    .to_string(),
+            ),
+            _ => Ok(term),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TermSubscription {
+    pub id: uuid::Uuid,
+    pub phone_number: String,
+    pub kind: TermKind,
+    pub term: String,
+}
+
+impl TermSubscription {
+    fn label(&self) -> String {
+        match self.kind {
+            TermKind::Keyword => format!("\"{}\"", self.term),
+            TermKind::Hashtag => format!("#{}", self.term),
+            TermKind::Domain => format!("a link to {}", self.term),
+        }
+    }
+
+    /// The author's handle is left for the job sending it to look up
+    fn message(&self, post: &NewPost) -> Result<Message> {
+        let record = serde_json::to_value(&post.record)?;
+        let message = PostMessage::new("", post.author.as_str(), post.rkey(), &record)
+            .with_reason(format!("New post with {}", self.label()));
+        Ok(Message::Post(Box::new(message)))
+    }
+
+    /// Hold a match for [`send_matches`], which texts each number at most once
+    /// per run however busy its terms are
+    pub async fn hold(&self, db: &PgPool, post: &NewPost) -> Result<()> {
+        let summary = format!(
+            "{}: {} {}",
+            self.label(),
+            digest::truncate(&post.record.text, MAX_ENTRY_CHARS),
+            permalink(post)
+        );
+        sqlx::query!(
+            "INSERT INTO TermMatches (subscription_id, phone_number, post_uri, message, summary)
+            VALUES ($1, $2, $3, $4, $5)",
+            self.id,
+            self.phone_number,
+            post.uri(),
+            Json(self.message(post)?) as _,
+            summary,
+        )
+        .execute(db)
+        .await?;
+
+        Ok(())
+    }
+}
+
+fn permalink(post: &NewPost) -> String {
+    format!(
+        "https://bsky.app/profile/{}/post/{}",
+        post.author.as_str(),
+        post.rkey()
+    )
+}
+
+struct PendingMatch {
+    id: uuid::Uuid,
+    phone_number: String,
+    post_uri: String,
+    message: Json<Message>,
+    summary: String,
+}
+
+/// Send everything [`TermSubscription::hold`] has put aside, one text per
+/// number. A lone match goes out as is, several are rolled up into a list.
+/// Matches for subscriptions paused since are dropped.
+pub async fn send_matches(db: &PgPool) -> Result<()> {
+    let mut tx = db.begin().await?;
+
+    sqlx::query!(
+        "DELETE FROM TermMatches
+        USING TermSubscriptions
+        WHERE TermSubscriptions.id = TermMatches.subscription_id
+        AND TermSubscriptions.paused_at IS NOT NULL"
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    let pending = sqlx::query_as!(
+        PendingMatch,
+        r#"SELECT id, phone_number, post_uri, message as "message: Json<Message>", summary
+        FROM TermMatches
+        ORDER BY created_at ASC
+        FOR UPDATE SKIP LOCKED"#
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    let mut by_number: BTreeMap<&str, Vec<&PendingMatch>> = BTreeMap::new();
+    for pending in &pending {
+        let matches = by_number.entry(&pending.phone_number).or_default();
+        // A post that hits several of someone's terms is only sent once
+        if !matches.iter().any(|m| m.post_uri == pending.post_uri) {
+            matches.push(pending);
+        }
+    }
+
+    for (phone_number, matches) in &by_number {
+        let message = match matches.as_slice() {
+            [only] => only.message.0.clone(),
+            matches => {
+                let summaries: Vec<_> = matches.iter().map(|m| m.summary.as_str()).collect();
+                Message::notice("New posts matching your terms", batch_body(&summaries))
+            }
+        };
+        let destination = Destination {
+            channel: Channel::Sms,
+            to: phone_number.to_string(),
+        };
+        jobs::enqueue(&mut *tx, &destination.term_matches(message), "term matches").await?;
+    }
+
+    let ids: Vec<_> = pending.iter().map(|m| m.id).collect();
+    sqlx::query!("DELETE FROM TermMatches WHERE id = ANY($1)", &ids)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+
+    tracing::info!(
+        matches = pending.len(),
+        texts = by_number.len(),
+        "sent term matches"
+    );
+    Ok(())
+}
+
+fn batch_body(summaries: &[&str]) -> String {
+    let mut body = format!("{} new posts matching your terms:", summaries.len());
+    for summary in summaries.iter().take(MAX_BATCH_ENTRIES) {
+        body.push_str("\n- ");
+        body.push_str(summary);
+    }
+    if summaries.len() > MAX_BATCH_ENTRIES {
+        body.push_str(&format!(
+            "\n…and {} more",
+            summaries.len() - MAX_BATCH_ENTRIES
+        ));
+    }
+    body
+}
+
+/// Every active term subscription, indexed so a post can be checked against
+/// all of them at once. Keywords go through a single Aho-Corasick automaton,
+/// hashtags and domains are exact lookups. Built from scratch whenever the
+/// subscriptions are reloaded.
+#[derive(Debug, Default)]
+pub struct TermMatcher {
+    subscriptions: Vec<TermSubscription>,
+    keywords: Option<AhoCorasick>,
+    /// Indexes into `subscriptions` for each keyword, by automaton pattern id
+    keyword_subscriptions: Vec<Vec<usize>>,
+    hashtags: HashMap<String, Vec<usize>>,
+    domains: HashMap<String, Vec<usize>>,
+}
+
+impl TermMatcher {
+    pub fn new(subscriptions: Vec<TermSubscription>) -> Self {
+        let mut keyword_ids: HashMap<&str, usize> = HashMap::new();
+        let mut keywords: Vec<&str> = vec![];
+        let mut keyword_subscriptions: Vec<Vec<usize>> = vec![];
+        let mut hashtags: HashMap<String, Vec<usize>> = HashMap::new();
+        let mut domains: HashMap<String, Vec<usize>> = HashMap::new();
+
+        for (i, sub) in subscriptions.iter().enumerate() {
+            match sub.kind {
+                TermKind::Keyword => {
+                    let id = *keyword_ids.entry(&sub.term).or_insert_with(|| {
+                        keywords.push(&sub.term);
+                        keyword_subscriptions.push(vec![]);
+                        keywords.len() - 1
+                    });
+                    keyword_subscriptions[id].push(i);
+                }
+                TermKind::Hashtag => hashtags.entry(sub.term.clone()).or_default().push(i),
+                TermKind::Domain => domains.entry(sub.term.clone()).or_default().push(i),
+            }
+        }
+
+        let keywords = if keywords.is_empty() {
+            None
+        } else {
+            AhoCorasick::builder()
+                .match_kind(MatchKind::Standard)
+                .build(&keywords)
+                .inspect_err(|err| tracing::error!(%err, "failed to build keyword matcher"))
+                .ok()
+        };
+
+        Self {
+            subscriptions,
+            keywords,
+            keyword_subscriptions,
+            hashtags,
+            domains,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.subscriptions.is_empty()
+    }
+
+    /// The subscriptions this post matches, each at most once however many of
+    /// its terms show up
+    pub fn matches(&self, post: &PostFacts) -> Vec<&TermSubscription> {
+        let mut matched: Vec<usize> = vec![];
+
+        if let Some(keywords) = &self.keywords {
+            for m in keywords.find_overlapping_iter(&post.text) {
+                if is_whole_word(&post.text, m.start(), m.end()) {
+                    matched.extend(&self.keyword_subscriptions[m.pattern().as_usize()]);
+                }
+            }
+        }
+        for tag in &post.tags {
+            matched.extend(self.hashtags.get(tag).into_iter().flatten());
+        }
+        for domain in &post.domains {
+            for suffix in domain_suffixes(domain) {
+                matched.extend(self.domains.get(suffix).into_iter().flatten());
+            }
+        }
+
+        matched.sort_unstable();
+        matched.dedup();
+        matched
+            .into_iter()
+            .map(|i| &self.subscriptions[i])
+            .collect()
+    }
+}
+
+/// `blog.example.com` and `example.com` but never the bare `com`, so a
+/// subscription to a domain also catches its subdomains
+fn domain_suffixes(domain: &str) -> impl Iterator<Item = &str> {
+    std::iter::successors(Some(domain), |&domain| {
+        domain.split_once('.').map(|(_, rest)| rest)
+    })
+    .filter(|suffix| suffix.trim_matches('.').contains('.'))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn sub(kind: TermKind, term: &str) -> TermSubscription {
+        TermSubscription {
+            id: uuid::Uuid::new_v4(),
+            phone_number: "+15555550100".to_string(),
+            kind,
+            term: term.to_string(),
+        }
+    }
+
+    #[test]
+    fn normalizes_terms() {
+        assert_eq!(
+            TermKind::Hashtag.normalize(" #RustLang ").as_deref(),
+            Ok("rustlang")
+        );
+        assert_eq!(
+            TermKind::Domain
+                .normalize("https://www.Example.com/path?q=1")
+                .as_deref(),
+            Ok("example.com")
+        );
+        assert!(TermKind::Keyword.normalize("   ").is_err());
+        assert_eq!(TermKind::parse("hashtag"), Some(TermKind::Hashtag));
+        assert_eq!(TermKind::parse("handle"), None);
+    }
+
+    #[test]
+    fn rejects_terms_that_match_too_much() {
+        assert!(TermKind::Keyword.normalize("a").is_err());
+        assert!(TermKind::Hashtag.normalize("#ai").is_err());
+        assert!(TermKind::Keyword.normalize("rust").is_ok());
+        assert!(TermKind::Domain.normalize("com").is_err());
+        assert!(TermKind::Domain.normalize(".com").is_err());
+        assert!(TermKind::Domain.normalize("https://com/").is_err());
+        assert_eq!(
+            TermKind::Domain.normalize("bsky.app").as_deref(),
+            Ok("bsky.app")
+        );
+    }
+
+    #[test]
+    fn bare_tlds_never_match() {
+        assert_eq!(
+            domain_suffixes("blog.example.com").collect::<Vec<_>>(),
+            vec!["blog.example.com", "example.com"]
+        );
+
+        // Left over from before bare TLDs were rejected
+        let matcher = TermMatcher::new(vec![sub(TermKind::Domain, "com")]);
+        let post = PostFacts::from_json(&json!({
+            "text": "hi",
+            "embed": { "$type": "app.bsky.embed.external", "external": { "uri": "https://example.com" } }
+        }));
+        assert!(matcher.matches(&post).is_empty());
+    }
+
+    #[sqlx::test]
+    async fn one_text_per_number(db: PgPool) {
+        let subscribe = |term: &'static str| {
+            sqlx::query_scalar!(
+                "INSERT INTO TermSubscriptions (phone_number, kind, term) VALUES ('+15555550100', 'keyword', $1) RETURNING id",
+                term,
+            )
+            .fetch_one(&db)
+        };
+        let rust = TermSubscription {
+            id: subscribe("rust").await.unwrap(),
+            ..sub(TermKind::Keyword, "rust")
+        };
+        let tokio = TermSubscription {
+            id: subscribe("tokio").await.unwrap(),
+            ..sub(TermKind::Keyword, "tokio")
+        };
+        let post = |rkey: &str, text: &str| NewPost {
+            record: serde_json::from_value(json!({
+                "text": text,
+                "createdAt": "2024-11-20T12:00:00.000Z"
+            }))
+            .unwrap(),
+            author: "did:plc:alice".parse().unwrap(),
+            path: format!("app.bsky.feed.post/{rkey}"),
+            cid: None,
+            prev: None,
+        };
+
+        // The first post hits both terms but is only listed once
+        let both = post("3kabc", "rust and tokio");
+        rust.hold(&db, &both).await.unwrap();
+        tokio.hold(&db, &both).await.unwrap();
+        tokio
+            .hold(&db, &post("3kdef", "tokio again"))
+            .await
+            .unwrap();
+
+        send_matches(&db).await.unwrap();
+
+        let jobs = sqlx::query_scalar!("SELECT payload FROM Jobs")
+            .fetch_all(&db)
+            .await
+            .unwrap();
+        assert_eq!(jobs.len(), 1);
+        assert_eq!(jobs[0]["to"], "+15555550100");
+        let body = jobs[0]["message"]["body"].as_str().unwrap();
+        assert!(
+            body.starts_with("2 new posts matching your terms:"),
+            "{body}"
+        );
+        assert!(body.contains("https://bsky.app/profile/did:plc:alice/post/3kdef"));
+
+        let remaining = sqlx::query_scalar!("SELECT COUNT(*) FROM TermMatches")
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        assert_eq!(remaining, Some(0));
+
+        // A lone match goes out as the post itself
+        sqlx::query!("DELETE FROM Jobs").execute(&db).await.unwrap();
+        rust.hold(&db, &post("3kghi", "rust again")).await.unwrap();
+        send_matches(&db).await.unwrap();
+        let message = sqlx::query_scalar!("SELECT payload FROM Jobs")
+            .fetch_one(&db)
+            .await
+            .unwrap()["message"]
+            .clone();
+        assert_eq!(message["type"], "post");
+        // The handle is looked up when it's sent
+        assert_eq!(message["handle"], "");
+        assert_eq!(message["reason"], "New post with \"rust\"");
+    }
+
+    #[test]
+    fn batches_list_a_few_matches() {
+        assert_eq!(
+            batch_body(&["\"rust\": one", "#rustlang: two"]),
+            "2 new posts matching your terms:\n- \"rust\": one\n- #rustlang: two"
+        );
+        let summaries = vec!["post"; 7];
+        let body = batch_body(&summaries);
+        assert!(body.starts_with("7 new posts"));
+        assert!(body.ends_with("\n- post\n…and 2 more"));
+    }
+
+    #[test]
+    fn keywords_match_whole_words() {
+        let matcher = TermMatcher::new(vec![
+            sub(TermKind::Keyword, "rust"),
+            sub(TermKind::Keyword, "rust"),
+            sub(TermKind::Keyword, "new release"),
+        ]);
+
+        let post = PostFacts::from_json(&json!({ "text": "The new release of Rust is out" }));
+        assert_eq!(matcher.matches(&post).len(), 3);
+
+        let post = PostFacts::from_json(&json!({ "text": "In code we trust" }));
+        assert!(matcher.matches(&post).is_empty());
+    }
+
+    #[test]
+    fn hashtags_and_domains() {
+        let matcher = TermMatcher::new(vec![
+            sub(TermKind::Hashtag, "rustlang"),
+            sub(TermKind::Domain, "example.com"),
+        ]);
+        let post = PostFacts::from_json(&json!({
+            "text": "check this out",
+            "facets": [
+                { "features": [{ "$type": "app.bsky.richtext.facet#tag", "tag": "RustLang" }] },
+                { "features": [{ "$type": "app.bsky.richtext.facet#link", "uri": "https://blog.example.com/post" }] }
+            ]
+        }));
+
+        let matched = matcher.matches(&post);
+        assert_eq!(matched.len(), 2);
+        assert_eq!(matched[0].kind, TermKind::Hashtag);
+        assert_eq!(matched[1].kind, TermKind::Domain);
+
+        let post = PostFacts::from_json(&json!({
+            "text": "#rustlang notexample.com",
+            "embed": { "$type": "app.bsky.embed.external", "external": { "uri": "https://notexample.com" } }
+        }));
+        assert!(matcher.matches(&post).is_empty());
+    }
+}
@@ -0,0 +1,152 @@
+use bsky_firehose::NewPost;
+use cja::{color_eyre, Result};
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+
+use crate::http::PublicClient;
+
+pub const SIGNATURE_HEADER: &str = "X-Bsky-Webhooks-Signature";
+/// Unique per event and unchanged across retries
+pub const DELIVERY_HEADER: &str = "X-Bsky-Webhooks-Delivery";
+
+/// The JSON body POSTed to a subscriber's callback URL
+#[derive(Serialize)]
+pub struct Envelope<'a> {
+    #[serde(rename = "type")]
+    pub event_type: &'static str,
+    pub uri: String,
+    pub cid: Option<String>,
+    /// For `post.updated`, the CID of the version that was replaced
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prev_cid: Option<String>,
+    pub author: Author<'a>,
+    pub record: &'a atrium_api::app::bsky::feed::post::Record,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Serialize)]
+pub struct Author<'a> {
+    pub did: &'a str,
+    pub handle: &'a str,
+}
+
+impl<'a> Envelope<'a> {
+    pub fn post_created(post: &'a NewPost, handle: &'a str) -> Self {
+        Self::new("post.created", post, handle)
+    }
+
+    /// An edited post, with the new record
+    pub fn post_updated(post: &'a NewPost, handle: &'a str) -> Self {
+        Self {
+            prev_cid: post.prev.as_ref().map(|cid| cid.0.to_string()),
+            ..Self::new("post.updated", post, handle)
+        }
+    }
+
+    fn new(event_type: &'static str, post: &'a NewPost, handle: &'a str) -> Self {
+        Self {
+            event_type,
+            uri: post.uri(),
+            cid: post.cid.as_ref().map(|cid| cid.0.to_string()),
+            prev_cid: None,
+            author: Author {
+                did: post.author.as_str(),
+                handle,
+            },
+            record: &post.record,
+            timestamp: chrono::Utc::now(),
+        }
+    }
+}
+
+/// `sha256=<hex HMAC-SHA256 of body>`, keyed with the subscription's secret
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+pub fn generate_secret() -> String {
+    hex::encode(rand::random::<[u8; 32]>())
+}
+
+pub async fn send_webhook(
+    client: &PublicClient,
+    delivery_id: uuid::Uuid,
+    url: &str,
+    secret: &str,
+    body: &serde_json::Value,
+) -> Result<()> {
+    let body = serde_json::to_vec(body)?;
+
+    let resp = client
+        .post(url)?
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header(SIGNATURE_HEADER, sign(secret, &body))
+        .header(DELIVERY_HEADER, delivery_id.to_string())
+        .body(body)
+        .send()
+        .await?;
+
+    if !resp.status().is_success() {
+        return Err(color_eyre::eyre::eyre!(
+            "webhook to {url} failed with {}",
+            resp.status()
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signs_with_hmac_sha256() {
+        assert_eq!(
+            sign("key", b"The quick brown fox jumps over the lazy dog"),
+            "sha256=f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+    }
+
+    #[test]
+    fn updates_carry_the_previous_cid() {
+        let cid: atrium_api::types::CidLink = serde_json::from_value(serde_json::json!({
+            "$link": "bafyreie5737gdxlw5i64vzichcalba3z2v5n6icifvx5xytvske7mr3hpm"
+        }))
+        .unwrap();
+        let post = NewPost {
+            record: serde_json::from_value(serde_json::json!({
+                "text": "edited",
+                "createdAt": "2024-11-20T12:00:00.000Z"
+            }))
+            .unwrap(),
+            author: "did:plc:alice".parse().unwrap(),
+            path: "app.bsky.feed.post/3kabc".to_string(),
+            cid: None,
+            prev: Some(cid),
+        };
+
+        let created = serde_json::to_value(Envelope::post_created(&post, "alice.test")).unwrap();
+        assert_eq!(created["type"], "post.created");
+        assert!(created.get("prev_cid").is_none());
+
+        let updated = serde_json::to_value(Envelope::post_updated(&post, "alice.test")).unwrap();
+        assert_eq!(updated["type"], "post.updated");
+        assert_eq!(
+            updated["prev_cid"],
+            "bafyreie5737gdxlw5i64vzichcalba3z2v5n6icifvx5xytvske7mr3hpm"
+        );
+    }
+
+    #[test]
+    fn secrets_are_random_hex() {
+        let secret = generate_secret();
+        assert_eq!(secret.len(), 64);
+        assert!(secret.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_ne!(secret, generate_secret());
+    }
+}